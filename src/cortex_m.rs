@@ -0,0 +1,466 @@
+use serde::Serialize;
+use std::fmt;
+
+// Core debug registers (ARMv6-M Debug Control Block)
+pub const DHCSR_ADDR: u32 = 0xe000edf0;
+pub const DCRSR_ADDR: u32 = 0xe000edf4;
+pub const DCRDR_ADDR: u32 = 0xe000edf8;
+pub const DEMCR_ADDR: u32 = 0xe000edfc;
+
+// DHCSR writes must carry the debug key in the upper half-word
+pub const DBGKEY: u32 = 0xa05f0000;
+pub const C_DEBUGEN: u32 = 1 << 0;
+pub const C_HALT: u32 = 1 << 1;
+pub const C_STEP: u32 = 1 << 2;
+pub const C_MASKINTS: u32 = 1 << 3;
+pub const S_REGRDY: u32 = 1 << 16;
+pub const S_HALT: u32 = 1 << 17;
+pub const S_SLEEP: u32 = 1 << 18;
+pub const S_LOCKUP: u32 = 1 << 19;
+pub const S_RETIRE_ST: u32 = 1 << 24;
+pub const S_RESET_ST: u32 = 1 << 25;
+
+pub const DCRSR_REGWNR: u32 = 1 << 16;
+
+/// DEMCR vector catch: halt on the first instruction after a core reset
+pub const DEMCR_VC_CORERESET: u32 = 1 << 0;
+/// DEMCR vector catch: halt on entry to the HardFault handler
+pub const DEMCR_VC_HARDERR: u32 = 1 << 10;
+
+/// Vector catches enabled in DEMCR (the only two ARMv6-M implements)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct VectorCatch {
+    pub hard_fault: bool,
+    pub core_reset: bool,
+}
+
+impl VectorCatch {
+    pub fn from_demcr(value: u32) -> Self {
+        VectorCatch {
+            hard_fault: (value & DEMCR_VC_HARDERR) != 0,
+            core_reset: (value & DEMCR_VC_CORERESET) != 0,
+        }
+    }
+
+    /// `demcr` with the vector catch bits replaced by these settings
+    pub fn apply(self, demcr: u32) -> u32 {
+        let mut value = demcr & !(DEMCR_VC_HARDERR | DEMCR_VC_CORERESET);
+        if self.hard_fault {
+            value |= DEMCR_VC_HARDERR;
+        }
+        if self.core_reset {
+            value |= DEMCR_VC_CORERESET;
+        }
+        value
+    }
+}
+
+impl fmt::Display for VectorCatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+        write!(f, "HardFault {}, reset {}", on_off(self.hard_fault), on_off(self.core_reset))
+    }
+}
+
+// Application Interrupt and Reset Control Register
+pub const AIRCR_ADDR: u32 = 0xe000ed0c;
+/// AIRCR writes must carry the vector key in the upper half-word
+pub const AIRCR_VECTKEY: u32 = 0x05fa0000;
+pub const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+/// Core state decoded from DHCSR
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CoreStatus {
+    pub debug_enabled: bool,
+    pub halted: bool,
+    pub register_ready: bool,
+    pub sleeping: bool,
+    pub lockup: bool,
+    /// An instruction completed since DHCSR was last read
+    pub retired: bool,
+    /// The core was reset since DHCSR was last read
+    pub reset: bool,
+}
+
+impl CoreStatus {
+    pub fn from_dhcsr(value: u32) -> Self {
+        CoreStatus {
+            debug_enabled: (value & C_DEBUGEN) != 0,
+            halted: (value & S_HALT) != 0,
+            register_ready: (value & S_REGRDY) != 0,
+            sleeping: (value & S_SLEEP) != 0,
+            lockup: (value & S_LOCKUP) != 0,
+            retired: (value & S_RETIRE_ST) != 0,
+            reset: (value & S_RESET_ST) != 0,
+        }
+    }
+}
+
+impl fmt::Display for CoreStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.lockup {
+            "locked up"
+        } else if self.halted {
+            "halted"
+        } else if self.sleeping {
+            "sleeping"
+        } else {
+            "running"
+        };
+        write!(f, "{}", state)?;
+        if self.reset {
+            write!(f, ", reset since last check")?;
+        }
+        if !self.debug_enabled {
+            write!(f, ", debug disabled")?;
+        }
+        Ok(())
+    }
+}
+
+// Breakpoint Unit (BPU, the ARMv6-M flavour of the FPB)
+pub const BP_CTRL_ADDR: u32 = 0xe0002000;
+pub const BP_COMP0_ADDR: u32 = 0xe0002008;
+pub const BP_CTRL_ENABLE: u32 = 1 << 0;
+/// Must be written as one for a BP_CTRL write to take effect
+pub const BP_CTRL_KEY: u32 = 1 << 1;
+const BP_CTRL_NUM_CODE_SHIFT: u32 = 4;
+const BP_CTRL_NUM_CODE_MASK: u32 = 0xf;
+pub const BP_COMP_ENABLE: u32 = 1 << 0;
+const BP_COMP_ADDRESS_MASK: u32 = 0x1ffffffc;
+const BP_MATCH_SHIFT: u32 = 30;
+const BP_MATCH_LOWER: u32 = 0b01;
+const BP_MATCH_UPPER: u32 = 0b10;
+/// The BPU can only match instruction fetches from the Code region
+pub const BP_MAX_ADDRESS: u32 = 0x1fffffff;
+
+/// Address of BPU comparator `index`
+pub fn bp_comp_addr(index: usize) -> u32 {
+    BP_COMP0_ADDR + (index as u32) * 4
+}
+
+/// Number of instruction comparators reported by BP_CTRL.NUM_CODE
+pub fn bp_comparator_count(bp_ctrl: u32) -> usize {
+    ((bp_ctrl >> BP_CTRL_NUM_CODE_SHIFT) & BP_CTRL_NUM_CODE_MASK) as usize
+}
+
+/// BP_COMP value matching the halfword at `address`, merged into `existing` when
+/// the comparator already watches the other halfword of the same word
+pub fn bp_comp_encode(address: u32, existing: u32) -> u32 {
+    let half = if (address & 2) != 0 { BP_MATCH_UPPER } else { BP_MATCH_LOWER };
+    let matched = if (existing & BP_COMP_ENABLE) != 0 { existing >> BP_MATCH_SHIFT } else { 0 };
+    ((matched | half) << BP_MATCH_SHIFT) | (address & BP_COMP_ADDRESS_MASK) | BP_COMP_ENABLE
+}
+
+/// BP_COMP value with the match for `address` removed; 0 when nothing is left
+pub fn bp_comp_remove(address: u32, existing: u32) -> u32 {
+    let half = if (address & 2) != 0 { BP_MATCH_UPPER } else { BP_MATCH_LOWER };
+    let matched = (existing >> BP_MATCH_SHIFT) & !half;
+    if matched == 0 {
+        0
+    } else {
+        (matched << BP_MATCH_SHIFT) | (existing & BP_COMP_ADDRESS_MASK) | BP_COMP_ENABLE
+    }
+}
+
+/// Halfword addresses a BP_COMP value breaks on
+pub fn bp_comp_addresses(value: u32) -> Vec<u32> {
+    if (value & BP_COMP_ENABLE) == 0 {
+        return Vec::new();
+    }
+    let word = value & BP_COMP_ADDRESS_MASK;
+    let matched = value >> BP_MATCH_SHIFT;
+    let mut addresses = Vec::new();
+    if (matched & BP_MATCH_LOWER) != 0 {
+        addresses.push(word);
+    }
+    if (matched & BP_MATCH_UPPER) != 0 {
+        addresses.push(word | 2);
+    }
+    addresses
+}
+
+/// Whether a BP_COMP value covers the word containing `address`
+pub fn bp_comp_same_word(value: u32, address: u32) -> bool {
+    (value & BP_COMP_ENABLE) != 0 && (value & BP_COMP_ADDRESS_MASK) == (address & BP_COMP_ADDRESS_MASK)
+}
+
+/// An active hardware breakpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Breakpoint {
+    /// BPU comparator holding it
+    pub comparator: usize,
+    pub address: u32,
+}
+
+// Data Watchpoint and Trace unit
+pub const DWT_CTRL_ADDR: u32 = 0xe0001000;
+const DWT_COMP0_ADDR: u32 = 0xe0001020;
+const DWT_CTRL_NUMCOMP_SHIFT: u32 = 28;
+/// DEMCR bit that powers the DWT on ARMv6-M (TRCENA on ARMv7-M)
+pub const DEMCR_DWTENA: u32 = 1 << 24;
+pub const DWT_FUNCTION_MASK: u32 = 0xf;
+/// Set when the comparator matched; cleared by reading FUNCTION
+pub const DWT_FUNCTION_MATCHED: u32 = 1 << 24;
+
+/// Address of DWT_COMPn; MASKn and FUNCTIONn follow at +4 and +8
+pub fn dwt_comp_addr(index: usize) -> u32 {
+    DWT_COMP0_ADDR + (index as u32) * 16
+}
+
+pub fn dwt_mask_addr(index: usize) -> u32 {
+    dwt_comp_addr(index) + 4
+}
+
+pub fn dwt_function_addr(index: usize) -> u32 {
+    dwt_comp_addr(index) + 8
+}
+
+/// Number of comparators reported by DWT_CTRL.NUMCOMP
+pub fn dwt_comparator_count(dwt_ctrl: u32) -> usize {
+    (dwt_ctrl >> DWT_CTRL_NUMCOMP_SHIFT) as usize
+}
+
+/// Accesses a watchpoint triggers on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    /// DWT_FUNCTION encoding for a data address comparison
+    pub fn function(self) -> u32 {
+        match self {
+            WatchKind::Read => 0b0101,
+            WatchKind::Write => 0b0110,
+            WatchKind::Access => 0b0111,
+        }
+    }
+
+    pub fn from_function(function: u32) -> Option<Self> {
+        match function & DWT_FUNCTION_MASK {
+            0b0101 => Some(WatchKind::Read),
+            0b0110 => Some(WatchKind::Write),
+            0b0111 => Some(WatchKind::Access),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for WatchKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "read" | "r" => Ok(WatchKind::Read),
+            "write" | "w" => Ok(WatchKind::Write),
+            "access" | "rw" => Ok(WatchKind::Access),
+            _ => Err(format!("Unknown watchpoint kind: {} (read, write or access)", s)),
+        }
+    }
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read"),
+            WatchKind::Write => write!(f, "write"),
+            WatchKind::Access => write!(f, "access"),
+        }
+    }
+}
+
+/// An active data watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Watchpoint {
+    /// DWT comparator holding it
+    pub comparator: usize,
+    pub address: u32,
+    /// Bytes covered; a power of two the address is aligned to
+    pub size: u32,
+    pub kind: WatchKind,
+}
+
+// Debug Fault Status Register: which debug event halted the core, write one to clear
+pub const DFSR_ADDR: u32 = 0xe000ed30;
+pub const DFSR_HALTED: u32 = 1 << 0;
+pub const DFSR_BKPT: u32 = 1 << 1;
+pub const DFSR_DWTTRAP: u32 = 1 << 2;
+pub const DFSR_VCATCH: u32 = 1 << 3;
+pub const DFSR_EXTERNAL: u32 = 1 << 4;
+pub const DFSR_ALL: u32 = DFSR_HALTED | DFSR_BKPT | DFSR_DWTTRAP | DFSR_VCATCH | DFSR_EXTERNAL;
+
+/// Why the core entered debug state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HaltReason {
+    /// The debugger set C_HALT
+    Request,
+    /// A C_STEP single step completed
+    Step,
+    /// A BPU comparator matched
+    Breakpoint {
+        breakpoint: Breakpoint,
+    },
+    /// The core executed a BKPT instruction
+    Bkpt,
+    /// A DWT comparator matched; `None` when MATCHED was already cleared
+    Watchpoint {
+        watchpoint: Option<Watchpoint>,
+    },
+    /// An exception enabled in DEMCR (VC_CORERESET, VC_HARDERR) was caught;
+    /// for a HardFault `frame` holds the registers stacked on entry
+    VectorCatch {
+        vector: CaughtVector,
+        frame: Option<ExceptionFrame>,
+    },
+    /// EDBGRQ was asserted
+    External,
+    /// DFSR held no event, e.g. it was already read and cleared
+    Unknown,
+}
+
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HaltReason::Request => write!(f, "halt request"),
+            HaltReason::Step => write!(f, "single step"),
+            HaltReason::Breakpoint { breakpoint } =>
+                write!(f, "breakpoint at 0x{:08X}", breakpoint.address),
+            HaltReason::Bkpt => write!(f, "BKPT instruction"),
+            HaltReason::Watchpoint { watchpoint: Some(watchpoint) } =>
+                write!(
+                    f,
+                    "{} watchpoint at 0x{:08X}+{}",
+                    watchpoint.kind,
+                    watchpoint.address,
+                    watchpoint.size
+                ),
+            HaltReason::Watchpoint { watchpoint: None } => write!(f, "watchpoint"),
+            HaltReason::VectorCatch { vector, frame: Some(frame) } =>
+                write!(f, "{} vector catch, faulting PC 0x{:08X}", vector, frame.pc),
+            HaltReason::VectorCatch { vector, frame: None } => write!(f, "{} vector catch", vector),
+            HaltReason::External => write!(f, "external debug request"),
+            HaltReason::Unknown => write!(f, "no event recorded in DFSR"),
+        }
+    }
+}
+
+/// IPSR exception number of HardFault
+pub const HARDFAULT_EXCEPTION: u32 = 3;
+/// xPSR.T: must be set for the core to execute Thumb code, the only state ARMv6-M has
+pub const XPSR_THUMB: u32 = 1 << 24;
+
+/// IPSR exception number held in the low bits of xPSR
+pub fn exception_number(xpsr: u32) -> u32 {
+    xpsr & 0x3f
+}
+
+/// Name of an ARMv6-M exception number
+pub fn exception_name(number: u32) -> String {
+    match number {
+        0 => "Thread mode".to_string(),
+        1 => "Reset".to_string(),
+        2 => "NMI".to_string(),
+        3 => "HardFault".to_string(),
+        11 => "SVCall".to_string(),
+        14 => "PendSV".to_string(),
+        15 => "SysTick".to_string(),
+        n if n >= 16 => format!("IRQ{}", n - 16),
+        n => format!("reserved exception {}", n),
+    }
+}
+
+/// Whether LR holds one of the three ARMv6-M EXC_RETURN codes rather than a return address
+pub fn is_exc_return(lr: u32) -> bool {
+    matches!(lr, 0xfffffff1 | 0xfffffff9 | 0xfffffffd)
+}
+
+/// EXC_RETURN bit selecting the process stack (PSP) for the stacked frame
+pub const EXC_RETURN_SPSEL: u32 = 1 << 2;
+/// Stacked xPSR bit 9: the core added a padding word to align the frame
+const XPSR_STACK_ALIGN: u32 = 1 << 9;
+
+/// Registers the core pushes on exception entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ExceptionFrame {
+    /// Address of the frame (SP after stacking)
+    pub address: u32,
+    /// The frame is on the process stack (PSP) rather than the main stack (MSP)
+    pub process_stack: bool,
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    /// Instruction that was executing (or next to execute) when the exception hit
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+impl ExceptionFrame {
+    /// Words of a frame in stacking order
+    pub const WORDS: usize = 8;
+
+    pub fn from_words(address: u32, process_stack: bool, words: &[u32]) -> Option<Self> {
+        match words {
+            [r0, r1, r2, r3, r12, lr, pc, xpsr, ..] =>
+                Some(ExceptionFrame {
+                    address,
+                    process_stack,
+                    r0: *r0,
+                    r1: *r1,
+                    r2: *r2,
+                    r3: *r3,
+                    r12: *r12,
+                    lr: *lr,
+                    pc: *pc,
+                    xpsr: *xpsr,
+                }),
+            _ => None,
+        }
+    }
+
+    /// SP of the interrupted code, before the frame (and any alignment word) was pushed
+    pub fn original_sp(&self) -> u32 {
+        let padding = if (self.xpsr & XPSR_STACK_ALIGN) != 0 { 4 } else { 0 };
+        self.address + (Self::WORDS as u32) * 4 + padding
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Stacked frame at 0x{:08X} ({}):",
+            self.address,
+            if self.process_stack { "PSP" } else { "MSP" }
+        )?;
+        writeln!(f, "  R0 : 0x{:08X}  R1 : 0x{:08X}  R2 : 0x{:08X}  R3  : 0x{:08X}", self.r0, self.r1, self.r2, self.r3)?;
+        writeln!(f, "  R12: 0x{:08X}  LR : 0x{:08X}  PC : 0x{:08X}  xPSR: 0x{:08X}", self.r12, self.lr, self.pc, self.xpsr)?;
+        write!(f, "  interrupted {}, SP before exception 0x{:08X}", exception_name(exception_number(self.xpsr)), self.original_sp())
+    }
+}
+
+/// Exception vector a DEMCR vector catch stopped on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaughtVector {
+    /// VC_CORERESET: first instruction of the reset handler
+    Reset,
+    /// VC_HARDERR: entry to the HardFault handler
+    HardFault,
+}
+
+impl fmt::Display for CaughtVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaughtVector::Reset => write!(f, "reset"),
+            CaughtVector::HardFault => write!(f, "HardFault"),
+        }
+    }
+}
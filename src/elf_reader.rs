@@ -1,23 +1,29 @@
 use std::fs::File;
-use std::io::{ Read, Seek, SeekFrom };
-use tracing::info;
+use std::io::Read;
+use std::fmt;
 use goblin::elf::Elf;
-use crc::{ Crc, Algorithm::CRC_32_IEEE };
-use std::collections::HashMap;
-use (#[derive(Debug)] pub);
-struct ByteMismatch {
+use crc::{ Crc, CRC_32_ISO_HDLC };
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::info;
+use crate::image::FirmwareImage;
+use crate::mspm0;
+#[derive(Debug, Serialize)]
+pub struct ByteMismatch {
     pub address: u32,
     pub expected: u8,
     pub actual: u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct VerificationResult {
     pub success: bool,
     pub total_sections: usize,
     pub verified_sections: Vec<u32>,
-    pub mismatched_sections: HashMap<u32, Vec<ByteMismatch>>,
+    pub mismatched_sections: BTreeMap<u32, Vec<ByteMismatch>>,
     pub errors: Vec<String>,
+    /// Bytes fetched over the bridge; sections whose target CRC matched are not read back
+    pub bytes_read_back: usize,
 }
 
 impl VerificationResult {
@@ -26,46 +32,56 @@ impl VerificationResult {
             success: true,
             total_sections: 0,
             verified_sections: Vec::new(),
-            mismatched_sections: HashMap::new(),
+            mismatched_sections: BTreeMap::new(),
             errors: Vec::new(),
+            bytes_read_back: 0,
         }
     }
 
-    pub fn print_report(&self) {
-        println!("\n=== Flash Verification Report ===");
-        println!("Total sections: {}", self.total_sections);
-        println!("Verified sections: {}", self.verified_sections.len());
-        println!("Failed sections: {}", self.mismatched_sections.len());
+    /// Number of bytes that differ across all sections
+    pub fn mismatch_count(&self) -> usize {
+        self.mismatched_sections.values().map(Vec::len).sum()
+    }
+}
+
+impl fmt::Display for VerificationResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "\n=== Flash Verification Report ===")?;
+        writeln!(f, "Total sections: {}", self.total_sections)?;
+        writeln!(f, "Verified sections: {}", self.verified_sections.len())?;
+        writeln!(f, "Failed sections: {}", self.mismatched_sections.len())?;
+        writeln!(f, "Bytes read back: {}", self.bytes_read_back)?;
 
         if !self.errors.is_empty() {
-            println!("\nErrors:");
+            writeln!(f, "\nErrors:")?;
             for error in &self.errors {
-                println!("  • {}", error);
+                writeln!(f, "  • {}", error)?;
             }
         }
 
         if !self.mismatched_sections.is_empty() {
-            println!("\nMismatched Sections:");
+            writeln!(f, "\nMismatched Sections:")?;
             for (addr, mismatches) in &self.mismatched_sections {
-                println!("  Section 0x{:08X}: {} mismatches", addr, mismatches.len());
+                writeln!(f, "  Section 0x{:08X}: {} mismatches", addr, mismatches.len())?;
 
                 // Show first few mismatches
-                for (i, mismatch) in mismatches.iter().take(5).enumerate() {
-                    println!(
+                for mismatch in mismatches.iter().take(5) {
+                    writeln!(
+                        f,
                         "    0x{:08X}: expected 0x{:02X}, got 0x{:02X}",
                         mismatch.address,
                         mismatch.expected,
                         mismatch.actual
-                    );
+                    )?;
                 }
 
                 if mismatches.len() > 5 {
-                    println!("    ... and {} more", mismatches.len() - 5);
+                    writeln!(f, "    ... and {} more", mismatches.len() - 5)?;
                 }
             }
         }
 
-        println!("\nOverall result: {}", if self.success { "PASS" } else { "FAIL" });
+        write!(f, "\nOverall result: {}", if self.success { "PASS" } else { "FAIL" })
     }
 }
 
@@ -76,11 +92,33 @@ pub struct FlashSection {
     pub data: Vec<u8>,
 }
 
+impl FlashSection {
+    /// CRC-32 (ISO-HDLC) of the section data, as the on-target CRC routine computes it
+    pub fn checksum(&self) -> u32 {
+        Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&self.data)
+    }
+}
+
 pub struct ElfFlashVerifier {
     pub sections: Vec<FlashSection>,
     pub entry_point: u32,
 }
 impl ElfFlashVerifier {
+    /// Verifier for an image loaded from any format (ELF, Intel HEX, binary);
+    /// each segment is checked as one section
+    pub fn from_image(image: &FirmwareImage) -> Self {
+        ElfFlashVerifier {
+            sections: image.segments
+                .iter()
+                .map(|segment| FlashSection {
+                    address: segment.address,
+                    size: segment.data.len() as u32,
+                    data: segment.data.clone(),
+                })
+                .collect(),
+            entry_point: image.entry_point.unwrap_or(0),
+        }
+    }
     pub fn from_elf_file(elf_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let mut file = File::open(elf_path)?;
         let mut buffer = Vec::new();
@@ -92,11 +130,11 @@ impl ElfFlashVerifier {
         // Extract programmable sections (those that should be in flash)
         for section_header in &elf.section_headers {
             // Check if section should be programmed to flash
-            if Self::is_programmable_section(&elf, section_header, &buffer) {
+            if Self::is_programmable_section(&elf, section_header) {
                 let section_data = Self::extract_section_data(&buffer, section_header)?;
 
                 sections.push(FlashSection {
-                    address: section_header.sh_addr as u32,
+                    address: Self::load_address(&elf, section_header),
                     size: section_header.sh_size as u32,
                     data: section_data,
                 });
@@ -113,8 +151,7 @@ impl ElfFlashVerifier {
     }
     fn is_programmable_section(
         elf: &Elf,
-        section_header: &goblin::elf::SectionHeader,
-        buffer: &[u8]
+        section_header: &goblin::elf::SectionHeader
     ) -> bool {
         use goblin::elf::section_header::*;
 
@@ -128,18 +165,25 @@ impl ElfFlashVerifier {
             return false;
         }
 
-        // Must be in flash address range (0x00000000 - 0x0001FFFF for MSPM0G3507)
-        let addr = section_header.sh_addr as u32;
-        if addr >= 0x00000000 && addr < 0x00020000 {
-            return true;
-        }
-
-        // Also check for info flash (0x41C00000 range)
-        if addr >= 0x41c00000 && addr < 0x41c00400 {
-            return true;
-        }
-
-        false
+        // Must be stored in flash (main flash or NONMAIN) on the MSPM0G3507; sections
+        // that run from SRAM (e.g. .data) count when their load address is in flash
+        let addr = Self::load_address(elf, section_header);
+        mspm0::in_flash(addr) || mspm0::in_nonmain(addr)
+    }
+    /// Where a section is stored (LMA): its run address translated through the
+    /// PT_LOAD segment containing it, which differs for sections copied to SRAM at startup
+    fn load_address(elf: &Elf, section_header: &goblin::elf::SectionHeader) -> u32 {
+        let address = section_header.sh_addr;
+        elf.program_headers
+            .iter()
+            .filter(|header| header.p_type == goblin::elf::program_header::PT_LOAD)
+            .find(|header| {
+                section_header.sh_offset >= header.p_offset &&
+                    section_header.sh_offset + section_header.sh_size <= header.p_offset + header.p_filesz &&
+                    address >= header.p_vaddr
+            })
+            .map(|header| header.p_paddr + (address - header.p_vaddr))
+            .unwrap_or(address) as u32
     }
     fn extract_section_data(
         buffer: &[u8],
@@ -154,44 +198,55 @@ impl ElfFlashVerifier {
 
         Ok(buffer[start..start + size].to_vec())
     }
+    /// Compare every section with the target. `target_crcs` holds the CRC-32 the
+    /// target computed for each section, if any: a section whose CRC matches
+    /// `calculate_checksums` is accepted without reading it back. Otherwise
+    /// `read_flash(address, length)` is asked for the whole section at once;
+    /// `SerialLoader::read_bytes` splits that into READ_BYTES frames as large as
+    /// the bridge allows.
     pub fn verify_flash<F>(
         &self,
+        target_crcs: Option<&[u32]>,
         mut read_flash: F
     ) -> Result<VerificationResult, Box<dyn std::error::Error>>
         where F: FnMut(u32, u32) -> Result<Vec<u8>, String>
     {
         let mut result = VerificationResult::new();
-
-        for section in &self.sections {
-            println!(
-                "Verifying section at 0x{:08X}, size: {} bytes",
-                section.address,
-                section.size
-            );
-
-            // Read flash data in chunks (your protocol has 4-byte max)
-            let mut flash_data = Vec::new();
-            let mut current_addr = section.address;
-            let mut remaining = section.size;
-
-            while remaining > 0 {
-                let chunk_size = std::cmp::min(remaining, 4);
-                match read_flash(current_addr, chunk_size) {
-                    Ok(mut chunk) => {
-                        // Pad chunk if necessary
-                        chunk.resize(chunk_size as usize, 0xff);
-                        flash_data.extend_from_slice(&chunk);
-                    }
-                    Err(e) => {
-                        result.errors.push(
-                            format!("Failed to read flash at 0x{:08X}: {}", current_addr, e)
-                        );
-                        return Ok(result);
-                    }
+        let expected_crcs = match target_crcs {
+            Some(_) => self.calculate_checksums(),
+            None => Vec::new(),
+        };
+
+        for (index, section) in self.sections.iter().enumerate() {
+            if let Some(&crc) = target_crcs.and_then(|crcs| crcs.get(index)) {
+                let expected = expected_crcs[index];
+                if crc == expected {
+                    info!("✓ Section at 0x{:08X} verified by CRC-32 0x{:08X}", section.address, crc);
+                    result.verified_sections.push(section.address);
+                    continue;
                 }
-                current_addr += chunk_size;
-                remaining -= chunk_size;
+                info!(
+                    "Section at 0x{:08X}: target CRC-32 0x{:08X}, image 0x{:08X}; reading it back",
+                    section.address,
+                    crc,
+                    expected
+                );
             }
+            info!("Verifying section at 0x{:08X}, size: {} bytes", section.address, section.size);
+
+            let flash_data = match read_flash(section.address, section.size) {
+                Ok(data) => data,
+                Err(e) => {
+                    result.errors.push(
+                        format!("Failed to read flash at 0x{:08X}: {}", section.address, e)
+                    );
+                    result.total_sections = self.sections.len();
+                    result.success = false;
+                    return Ok(result);
+                }
+            };
+
+            result.bytes_read_back += flash_data.len();
 
             // Compare data
             if flash_data.len() != section.data.len() {
@@ -220,14 +275,10 @@ impl ElfFlashVerifier {
 
             if mismatches.is_empty() {
                 result.verified_sections.push(section.address);
-                println!("✓ Section at 0x{:08X} verified successfully", section.address);
+                info!("✓ Section at 0x{:08X} verified successfully", section.address);
             } else {
+                info!("✗ Section at 0x{:08X} has {} mismatches", section.address, mismatches.len());
                 result.mismatched_sections.insert(section.address, mismatches);
-                // println!(
-                //     "✗ Section at 0x{:08X} has {} mismatches",
-                //     section.address,
-                //     mismatches.len()
-                // );
             }
         }
 
@@ -237,22 +288,9 @@ impl ElfFlashVerifier {
 
         Ok(result)
     }
-    pub fn get_memory_map(&self) -> Vec<(u32, u32)> {
-        self.sections
-            .iter()
-            .map(|s| (s.address, s.address + s.size - 1))
-            .collect()
-    }
-
-    pub fn calculate_checksum(&self) -> u32 {
-        let crc = Crc::<u32>::new(&CRC_32_IEEE);
-        let mut digest = crc.digest();
-
-        for section in &self.sections {
-            digest.update(&section.address.to_le_bytes());
-            digest.update(&section.data);
-        }
-
-        digest.finalize()
+    /// Host-side CRC-32 of every section, in the order of `sections`, to compare
+    /// with the CRCs the target computes over the same ranges
+    pub fn calculate_checksums(&self) -> Vec<u32> {
+        self.sections.iter().map(FlashSection::checksum).collect()
     }
 }
@@ -0,0 +1,199 @@
+use std::fmt;
+use crate::protocol::FrameError;
+
+/// Everything that can go wrong between the host and the target core
+#[derive(Debug)]
+pub enum DebugError {
+    /// No link to the bridge is open
+    NotConnected,
+    /// The link to the bridge failed (I/O error, port vanished, peer closed)
+    Transport(String),
+    /// The bridge did not answer in time
+    Timeout,
+    /// A checksum did not match (response frame CRC-8 or image CRC-32)
+    CrcMismatch {
+        expected: u32,
+        received: u32,
+    },
+    /// A response frame was malformed or did not match the request
+    Frame(FrameError),
+    /// The bridge answered a command with an error code
+    Nack {
+        opcode: u8,
+        code: u8,
+    },
+    /// A bus fault while accessing target memory
+    TargetFault {
+        address: u32,
+    },
+    /// A register never reached the expected value while polling
+    PollTimeout {
+        address: u32,
+        mask: u32,
+    },
+    /// The bridge firmware does not implement a command
+    Unsupported {
+        opcode: u8,
+    },
+    /// The bridge firmware cannot be used by this host
+    IncompatibleBridge(String),
+    /// A request the host refuses to send (bad address, oversized payload...)
+    InvalidArgument(String),
+    /// The operation requires the core to be halted
+    NotHalted,
+    /// Every hardware comparator of a debug unit (BPU, DWT) is in use
+    NoFreeComparator {
+        unit: &'static str,
+        available: usize,
+    },
+    /// The flash controller reported a failed erase or program command
+    FlashFailed {
+        address: u32,
+        status: u32,
+    },
+    /// Target memory does not match the image it was checked against. Only the
+    /// CLI fails on this; Socket.IO clients get the report with `success: false`.
+    #[cfg(feature = "cli")]
+    VerifyFailed {
+        mismatches: usize,
+    },
+    /// Code the host ran from SRAM did not stop on its closing BKPT
+    RoutineFailed {
+        pc: u32,
+        reason: String,
+    },
+}
+
+impl DebugError {
+    /// Stable identifier for clients (Socket.IO responses, scripts)
+    pub fn code(&self) -> &'static str {
+        match self {
+            DebugError::NotConnected => "not_connected",
+            DebugError::Transport(_) => "transport",
+            DebugError::Timeout => "timeout",
+            DebugError::CrcMismatch { .. } => "crc_mismatch",
+            DebugError::Frame(_) => "bad_frame",
+            DebugError::Nack { .. } => "nack",
+            DebugError::TargetFault { .. } => "target_fault",
+            DebugError::PollTimeout { .. } => "poll_timeout",
+            DebugError::Unsupported { .. } => "unsupported",
+            DebugError::IncompatibleBridge(_) => "incompatible_bridge",
+            DebugError::InvalidArgument(_) => "invalid_argument",
+            DebugError::NotHalted => "not_halted",
+            DebugError::NoFreeComparator { .. } => "no_free_comparator",
+            DebugError::FlashFailed { .. } => "flash_failed",
+            #[cfg(feature = "cli")]
+            DebugError::VerifyFailed { .. } => "verify_failed",
+            DebugError::RoutineFailed { .. } => "routine_failed",
+        }
+    }
+
+    /// Process exit status used by the CLI
+    #[cfg(feature = "cli")]
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DebugError::NotConnected | DebugError::Transport(_) => 2,
+            DebugError::Timeout => 3,
+            DebugError::CrcMismatch { .. } | DebugError::Frame(_) => 4,
+            DebugError::Nack { .. } => 5,
+            DebugError::TargetFault { .. } | DebugError::PollTimeout { .. } => 6,
+            DebugError::Unsupported { .. } | DebugError::IncompatibleBridge(_) => 7,
+            DebugError::InvalidArgument(_) => 8,
+            DebugError::NotHalted => 9,
+            DebugError::NoFreeComparator { .. } => 10,
+            DebugError::FlashFailed { .. } => 11,
+            DebugError::VerifyFailed { .. } => 12,
+            DebugError::RoutineFailed { .. } => 13,
+        }
+    }
+
+    /// Whether sending the same command again may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            DebugError::Timeout |
+                DebugError::CrcMismatch { .. } |
+                DebugError::Frame(_) |
+                DebugError::Nack { .. }
+        )
+    }
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DebugError::NotConnected => write!(f, "Bridge is not connected"),
+            DebugError::Transport(message) => write!(f, "Transport error: {}", message),
+            DebugError::Timeout => write!(f, "Timed out waiting for response frame"),
+            DebugError::CrcMismatch { expected, received } =>
+                write!(f, "CRC mismatch: expected {:#x}, received {:#x}", expected, received),
+            DebugError::Frame(e) => write!(f, "Invalid response frame: {}", e),
+            DebugError::Nack { opcode, code } =>
+                write!(f, "Bridge rejected command {:#04x} with {:#04x}", opcode, code),
+            DebugError::TargetFault { address } =>
+                write!(f, "Target fault accessing 0x{:08X}", address),
+            DebugError::PollTimeout { address, mask } =>
+                write!(f, "Timed out waiting for 0x{:08X} to set bits 0x{:08X}", address, mask),
+            DebugError::Unsupported { opcode } =>
+                write!(f, "Bridge firmware lacks command {:#04x}", opcode),
+            DebugError::IncompatibleBridge(message) => write!(f, "{}", message),
+            DebugError::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            DebugError::NotHalted => write!(f, "Target core is not halted"),
+            DebugError::NoFreeComparator { unit, available } =>
+                write!(f, "All {} {} comparators are in use", available, unit),
+            DebugError::FlashFailed { address, status } =>
+                write!(
+                    f,
+                    "Flash command at 0x{:08X} failed: {} (STATCMD 0x{:08X})",
+                    address,
+                    crate::mspm0::flash_status_text(*status),
+                    status
+                ),
+            #[cfg(feature = "cli")]
+            DebugError::VerifyFailed { mismatches } =>
+                write!(f, "Verification failed: {} byte(s) differ from the image", mismatches),
+            DebugError::RoutineFailed { pc, reason } =>
+                write!(f, "Target routine failed at 0x{:08X}: {}", pc, reason),
+        }
+    }
+}
+
+impl std::error::Error for DebugError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DebugError::Frame(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<FrameError> for DebugError {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::CrcMismatch { expected, received } =>
+                DebugError::CrcMismatch { expected: expected as u32, received: received as u32 },
+            e => DebugError::Frame(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for DebugError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => DebugError::Timeout,
+            _ => DebugError::Transport(e.to_string()),
+        }
+    }
+}
+
+impl From<serialport::Error> for DebugError {
+    fn from(e: serialport::Error) -> Self {
+        DebugError::Transport(e.to_string())
+    }
+}
+
+impl From<rusb::Error> for DebugError {
+    fn from(e: rusb::Error) -> Self {
+        DebugError::Transport(e.to_string())
+    }
+}
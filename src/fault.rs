@@ -0,0 +1,291 @@
+use serde::Serialize;
+use std::fmt;
+use crate::cortex_m::{ self, ExceptionFrame, EXC_RETURN_SPSEL, HARDFAULT_EXCEPTION, XPSR_THUMB };
+use crate::mspm0;
+use crate::symbols::{ ElfSymbols, Location };
+
+/// What the core was doing when it faulted, with a diagnosis in plain words
+#[derive(Debug, Clone, Serialize)]
+pub struct FaultReport {
+    /// PC of the halted core (inside the exception handler)
+    pub pc: u32,
+    pub xpsr: u32,
+    /// IPSR: the exception being handled, 0 in Thread mode
+    pub exception: u32,
+    pub exception_name: String,
+    /// LR when it still holds the EXC_RETURN code written on exception entry
+    pub exc_return: Option<u32>,
+    /// The core was locked up (fault while in HardFault or NMI) when the report was taken
+    pub lockup: bool,
+    pub frame: Option<ExceptionFrame>,
+    /// Address of a stacked frame that could not be read
+    pub unreadable_frame: Option<u32>,
+    /// Halfword at the stacked PC
+    pub instruction: Option<u16>,
+    /// Filled from the ELF by `symbolize`
+    pub location: Option<Location>,
+    pub fault_location: Option<Location>,
+    pub caller_location: Option<Location>,
+    pub diagnosis: Vec<String>,
+}
+
+impl FaultReport {
+    pub fn new(
+        pc: u32,
+        lr: u32,
+        xpsr: u32,
+        lockup: bool,
+        frame: Option<ExceptionFrame>,
+        unreadable_frame: Option<u32>,
+        instruction: Option<u16>
+    ) -> Self {
+        let exception = cortex_m::exception_number(xpsr);
+        let mut report = FaultReport {
+            pc,
+            xpsr,
+            exception,
+            exception_name: cortex_m::exception_name(exception),
+            exc_return: cortex_m::is_exc_return(lr).then_some(lr),
+            lockup,
+            frame,
+            unreadable_frame,
+            instruction,
+            location: None,
+            fault_location: None,
+            caller_location: None,
+            diagnosis: Vec::new(),
+        };
+        report.diagnosis = report.diagnose();
+        report
+    }
+
+    /// Resolve the handler PC, the faulting PC and the caller to functions and source lines
+    pub fn symbolize(&mut self, symbols: &ElfSymbols) {
+        let handler = format!("{}_Handler", self.exception_name);
+        self.location = Some(symbols.locate_preferring(self.pc, Some(&handler)));
+        if let Some(frame) = self.frame {
+            self.fault_location = Some(symbols.locate(frame.pc));
+            if !cortex_m::is_exc_return(frame.lr) {
+                // LR holds the return address of the function that was running
+                self.caller_location = Some(symbols.locate(frame.lr));
+            }
+        }
+    }
+
+    /// One-line summary for logs and Socket.IO messages
+    pub fn summary(&self) -> String {
+        match (self.frame, &self.fault_location) {
+            (Some(_), Some(location)) => format!("{} at {}", self.exception_name, location),
+            (Some(frame), None) => format!("{} at 0x{:08X}", self.exception_name, frame.pc),
+            (None, _) => format!("{}, no stacked frame", self.exception_name),
+        }
+    }
+
+    fn diagnose(&self) -> Vec<String> {
+        let mut notes = Vec::new();
+        if self.lockup {
+            notes.push(
+                "The core was locked up: it faulted while already in HardFault or NMI, so no handler could run. Only a reset recovers it.".to_string()
+            );
+        }
+        if self.exception == 0 {
+            notes.push("The core is in Thread mode, not handling an exception; nothing was stacked.".to_string());
+            return notes;
+        }
+        if self.exception != HARDFAULT_EXCEPTION {
+            notes.push(
+                format!("The core is handling {}, not a HardFault; the frame shows the code it interrupted.", self.exception_name)
+            );
+        }
+        if let Some(address) = self.unreadable_frame {
+            notes.push(
+                format!(
+                    "The stacked frame at 0x{:08X} cannot be read: the stack pointer is corrupt (stack overflow or a wild write).",
+                    address
+                )
+            );
+            return notes;
+        }
+        let Some(frame) = self.frame else {
+            notes.push(
+                "LR no longer holds EXC_RETURN because the handler has called other functions, so the stacked frame cannot be located. Enable `vector-catch --hard-fault on` to stop on handler entry.".to_string()
+            );
+            return notes;
+        };
+
+        let original_sp = frame.original_sp();
+        if !(original_sp > mspm0::SRAM_BASE && original_sp <= mspm0::SRAM_BASE + mspm0::SRAM_SIZE) {
+            notes.push(
+                format!(
+                    "The {} was 0x{:08X} before the exception, outside SRAM: the stack overflowed or the stack pointer was overwritten.",
+                    if (self.exc_return.unwrap_or(0) & EXC_RETURN_SPSEL) != 0 { "PSP" } else { "MSP" },
+                    original_sp
+                )
+            );
+        }
+        if (frame.xpsr & XPSR_THUMB) == 0 {
+            notes.push(
+                "The Thumb bit in the stacked xPSR is clear: the code branched to an address with bit 0 clear, typically a function pointer or return address built without the Thumb bit.".to_string()
+            );
+        }
+        if frame.pc >= 0xfffffff0 {
+            notes.push(
+                format!(
+                    "The stacked PC 0x{:08X} is an erased-flash or EXC_RETURN value: execution ran into erased flash or returned through a corrupted LR.",
+                    frame.pc
+                )
+            );
+        } else if !mspm0::in_flash(frame.pc) && !mspm0::in_sram(frame.pc) {
+            notes.push(
+                format!(
+                    "The stacked PC 0x{:08X} is outside flash and SRAM: a jump through a corrupted function pointer or return address.",
+                    frame.pc
+                )
+            );
+        }
+        match self.instruction {
+            Some(instruction) if (instruction & 0xff00) == 0xbe00 => {
+                notes.push(
+                    format!(
+                        "The faulting instruction is BKPT #{}: a breakpoint instruction ran while no debugger was halting on it.",
+                        instruction & 0xff
+                    )
+                );
+            }
+            Some(instruction) if is_load_store(instruction) => {
+                notes.push(
+                    "The faulting instruction is a load or store: check for an unaligned address (the Cortex-M0+ faults on every unaligned access) or an access to a peripheral that is unpowered or does not exist. R0-R3 and R12 above usually hold the address.".to_string()
+                );
+            }
+            _ => {}
+        }
+        let interrupted = cortex_m::exception_number(frame.xpsr);
+        if interrupted != 0 {
+            notes.push(
+                format!("The fault happened inside the {} handler.", cortex_m::exception_name(interrupted))
+            );
+        }
+        if notes.is_empty() {
+            notes.push(
+                "No specific cause recognised; inspect the instruction at the faulting PC and the registers it uses.".to_string()
+            );
+        }
+        notes
+    }
+}
+
+/// 16-bit Thumb loads and stores (LDR/STR with register, immediate, SP or
+/// literal addressing; LDM/STM)
+fn is_load_store(instruction: u16) -> bool {
+    let top = instruction >> 11;
+    matches!(top, 0b01001 | 0b01010 | 0b01011 | 0b01100..=0b10011 | 0b11000 | 0b11001)
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (IPSR {}), xPSR 0x{:08X}, PC 0x{:08X}", self.exception_name, self.exception, self.xpsr, self.pc)?;
+        if let Some(Location { symbol: Some(symbol), .. }) = &self.location {
+            write!(f, " in {}", symbol)?;
+        }
+        writeln!(f)?;
+        if let Some(exc_return) = self.exc_return {
+            writeln!(
+                f,
+                "EXC_RETURN 0x{:08X}: frame on {}, returning to {} mode",
+                exc_return,
+                if (exc_return & EXC_RETURN_SPSEL) != 0 { "PSP" } else { "MSP" },
+                if (exc_return & 0x8) != 0 { "Thread" } else { "Handler" }
+            )?;
+        }
+        if let Some(frame) = &self.frame {
+            writeln!(f, "{}", frame)?;
+            match &self.fault_location {
+                Some(location) => writeln!(f, "Faulting PC: {}", location)?,
+                None => writeln!(f, "Faulting PC: 0x{:08X}", frame.pc)?,
+            }
+            match &self.caller_location {
+                Some(location) => writeln!(f, "Called from: {}", location)?,
+                None if !cortex_m::is_exc_return(frame.lr) =>
+                    writeln!(f, "Called from: 0x{:08X}", frame.lr & !1)?,
+                None => {}
+            }
+            if let Some(instruction) = self.instruction {
+                writeln!(f, "Instruction: 0x{:04X}", instruction)?;
+            }
+        }
+        write!(f, "Diagnosis:")?;
+        for note in &self.diagnosis {
+            write!(f, "\n  - {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// HardFault report for a frame stacked on the MSP by a fault in Thread mode
+    fn report(frame: ExceptionFrame, instruction: Option<u16>) -> FaultReport {
+        FaultReport::new(0x0000_0100, 0xfffffff9, HARDFAULT_EXCEPTION, false, Some(frame), None, instruction)
+    }
+
+    /// Frame at the top of SRAM for a fault at `pc`
+    fn frame(pc: u32) -> ExceptionFrame {
+        let words = [0, 0, 0, 0, 0, 0x0000_0201, pc, XPSR_THUMB];
+        ExceptionFrame::from_words(mspm0::SRAM_BASE + mspm0::SRAM_SIZE - 0x20, false, &words).unwrap()
+    }
+
+    fn mentions(report: &FaultReport, text: &str) -> bool {
+        report.diagnosis.iter().any(|note| note.contains(text))
+    }
+
+    #[test]
+    fn decodes_loads_and_stores() {
+        // LDR r0, [r1, #0]; STR r0, [sp, #4]; LDR r0, [pc, #8]; STM r0!, {r1}
+        for instruction in [0x6808, 0x9001, 0x4802, 0xc002] {
+            assert!(mentions(&report(frame(0x1000), Some(instruction)), "load or store"), "0x{:04X}", instruction);
+        }
+        // ADDS r0, r0, #1 and BX lr are not memory accesses
+        for instruction in [0x1c40, 0x4770] {
+            let report = report(frame(0x1000), Some(instruction));
+            assert_eq!(report.diagnosis.len(), 1);
+            assert!(mentions(&report, "No specific cause"));
+        }
+        assert!(mentions(&report(frame(0x1000), Some(0xbe05)), "BKPT #5"));
+    }
+
+    #[test]
+    fn recognises_erased_flash_and_wild_pcs() {
+        for pc in [0xfffffffe, 0xfffffff9] {
+            assert!(mentions(&report(frame(pc), None), "erased-flash"));
+        }
+        let wild = report(frame(0x4000_0000), None);
+        assert!(!mentions(&wild, "erased-flash"));
+        assert!(mentions(&wild, "outside flash and SRAM"));
+
+        let mut arm = frame(0x1000);
+        arm.xpsr &= !XPSR_THUMB;
+        assert!(mentions(&report(arm, None), "Thumb bit"));
+    }
+
+    #[test]
+    fn detects_stack_overflow() {
+        // The whole frame fits in SRAM: the stack pointer before it was fine
+        assert!(!mentions(&report(frame(0x1000), None), "outside SRAM"));
+
+        // Stacked just below SRAM, so the SP before the exception is the SRAM base
+        let mut overflowed = frame(0x1000);
+        overflowed.address = mspm0::SRAM_BASE - 0x20;
+        let report_msp = report(overflowed, None);
+        assert!(mentions(&report_msp, "The MSP was 0x20200000"));
+
+        overflowed.process_stack = true;
+        let report_psp = FaultReport::new(0x100, 0xfffffffd, HARDFAULT_EXCEPTION, false, Some(overflowed), None, None);
+        assert!(mentions(&report_psp, "The PSP was"));
+
+        // A frame that cannot be read at all is reported as a corrupt stack pointer
+        let unreadable = FaultReport::new(0x100, 0xfffffff9, HARDFAULT_EXCEPTION, false, None, Some(0x1000_0000), None);
+        assert!(mentions(&unreadable, "0x10000000 cannot be read"));
+    }
+}
@@ -0,0 +1,477 @@
+use std::fmt;
+use std::time::Duration;
+use goblin::elf::{ section_header::{ SectionHeader, SHT_NOBITS }, Elf };
+use crate::error::DebugError;
+use crate::loader::RoutineFrame;
+use crate::mspm0;
+use crate::routines;
+
+/// Placed in front of the algorithm: its functions return here through LR and stop
+const RETURN_BKPT: u32 = 0xbe00be00;
+/// Stack between the algorithm and its page buffers
+const STACK_SIZE: u32 = 0x400;
+/// Offsets into the FlashDevice structure (CMSIS FlashOS.h) of an .FLM file
+const DEVICE_NAME: std::ops::Range<usize> = 2..130;
+const DEVICE_ADDRESS: usize = 132;
+const DEVICE_SIZE: usize = 136;
+const DEVICE_PAGE_SIZE: usize = 140;
+const DEVICE_ERASED_VALUE: usize = 148;
+const DEVICE_PROGRAM_TIMEOUT: usize = 152;
+const DEVICE_ERASE_TIMEOUT: usize = 156;
+const DEVICE_SECTORS: usize = 160;
+/// Ends the (size, offset) sector list of a FlashDevice
+const SECTORS_END: u32 = 0xffffffff;
+
+/// A flash algorithm in the CMSIS-Pack format: position-independent functions
+/// run from SRAM that erase and program flash. They take their arguments in
+/// R0-R3 and return 0 on success.
+#[derive(Debug, Clone)]
+pub struct FlashAlgorithm {
+    pub name: String,
+    /// Code followed by data (zero-initialised data included)
+    pub code: Vec<u8>,
+    /// Function offsets into `code`
+    pub init: Option<u32>,
+    pub uninit: Option<u32>,
+    pub erase_sector: u32,
+    pub program_page: u32,
+    /// Offset of the data RWPI code addresses through R9
+    pub data_offset: Option<u32>,
+    pub flash_base: u32,
+    pub flash_size: u32,
+    /// Most bytes one `ProgramPage` call takes
+    pub page_size: u32,
+    pub sector_size: u32,
+    pub erased_value: u8,
+    pub program_timeout: Duration,
+    pub erase_timeout: Duration,
+}
+
+/// Where an algorithm and its double-buffered page mailbox sit in SRAM
+#[derive(Debug, Clone, Copy)]
+pub struct AlgorithmLayout {
+    pub load_address: u32,
+    /// Address of `code`; function offsets are relative to it
+    pub code_address: u32,
+    pub frame: RoutineFrame,
+    /// Two page buffers: the host fills one while the core programs the other
+    pub buffers: [u32; 2],
+}
+
+impl FlashAlgorithm {
+    /// `builtin` for the algorithm shipped with the debugger, otherwise the path of an .FLM file
+    pub fn load(spec: &str) -> Result<Self, DebugError> {
+        if spec == "builtin" { Ok(Self::builtin()) } else { Self::from_flm(spec) }
+    }
+
+    /// The MSPM0 FLASHCTL algorithm from `routines`
+    pub fn builtin() -> Self {
+        FlashAlgorithm {
+            name: "MSPM0 FLASHCTL (built-in)".to_string(),
+            code: routines
+                ::words(&routines::MSPM0_FLASH_LOADER)
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect(),
+            init: Some(routines::MSPM0_FLASH_INIT),
+            uninit: Some(routines::MSPM0_FLASH_UNINIT),
+            erase_sector: routines::MSPM0_FLASH_ERASE_SECTOR,
+            program_page: routines::MSPM0_FLASH_PROGRAM_PAGE,
+            data_offset: None,
+            flash_base: mspm0::FLASH_BASE,
+            flash_size: mspm0::FLASH_SIZE,
+            page_size: mspm0::FLASH_SECTOR_SIZE,
+            sector_size: mspm0::FLASH_SECTOR_SIZE,
+            erased_value: 0xff,
+            program_timeout: Duration::from_millis(500),
+            erase_timeout: Duration::from_millis(2000),
+        }
+    }
+
+    /// Parse a CMSIS-Pack flash algorithm: an ELF file whose `PrgCode` and
+    /// `PrgData` sections hold the code and data, with the device described by
+    /// the FlashDevice structure in `DevDscr`
+    pub fn from_flm(path: &str) -> Result<Self, DebugError> {
+        let buffer = std::fs
+            ::read(path)
+            .map_err(|e| DebugError::InvalidArgument(format!("Cannot read {}: {}", path, e)))?;
+        let invalid = |message: &str| DebugError::InvalidArgument(format!("{}: {}", path, message));
+        let elf = Elf::parse(&buffer).map_err(|e| invalid(&e.to_string()))?;
+        let named = |name: &str| -> Vec<&SectionHeader> {
+            elf.section_headers
+                .iter()
+                .filter(|header| elf.shdr_strtab.get_at(header.sh_name) == Some(name))
+                .collect()
+        };
+        let contents = |header: &SectionHeader| -> Result<Vec<u8>, DebugError> {
+            if header.sh_type == SHT_NOBITS {
+                return Ok(vec![0; header.sh_size as usize]);
+            }
+            let start = header.sh_offset as usize;
+            buffer
+                .get(start..start + (header.sh_size as usize))
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| invalid("section data extends beyond the file"))
+        };
+
+        let code_section = *named("PrgCode").first().ok_or_else(|| invalid("no PrgCode section"))?;
+        let base = code_section.sh_addr;
+        let mut code = contents(code_section)?;
+        // PrgData (initialised, then zero-initialised) follows the code at the
+        // offsets the linker gave it
+        let mut data_sections = named("PrgData");
+        data_sections.sort_by_key(|header| header.sh_addr);
+        let mut data_offset = None;
+        for header in data_sections {
+            let offset = header.sh_addr
+                .checked_sub(base)
+                .ok_or_else(|| invalid("PrgData lies below PrgCode"))? as usize;
+            if offset < code.len() {
+                return Err(invalid("PrgData overlaps PrgCode"));
+            }
+            code.resize(offset, 0);
+            code.extend(contents(header)?);
+            data_offset.get_or_insert(offset as u32);
+        }
+
+        let function = |name: &str| -> Option<u32> {
+            elf.syms
+                .iter()
+                .find(|symbol| symbol.is_function() && elf.strtab.get_at(symbol.st_name) == Some(name))
+                .and_then(|symbol| symbol.st_value.checked_sub(base))
+                .map(|offset| (offset as u32) & !1)
+        };
+        let required = |name: &str| function(name).ok_or_else(|| invalid(&format!("no {} function", name)));
+
+        let device = contents(*named("DevDscr").first().ok_or_else(|| invalid("no DevDscr section"))?)?;
+        if device.len() < DEVICE_SECTORS {
+            return Err(invalid("FlashDevice structure is truncated"));
+        }
+        let word = |offset: usize| -> Result<u32, DebugError> {
+            device
+                .get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .ok_or_else(|| invalid("FlashDevice structure is truncated"))
+        };
+        let name_bytes = &device[DEVICE_NAME];
+        let name = String::from_utf8_lossy(name_bytes.split(|byte| *byte == 0).next().unwrap_or_default()).to_string();
+        // The sector list must end with SECTORS_END before the structure does
+        let mut sector_sizes = Vec::new();
+        let mut entry = DEVICE_SECTORS;
+        loop {
+            let (size, offset) = (word(entry)?, word(entry + 4)?);
+            if size == SECTORS_END || offset == SECTORS_END {
+                break;
+            }
+            sector_sizes.push(size);
+            entry += 8;
+        }
+        let sector_size = *sector_sizes.first().ok_or_else(|| invalid("FlashDevice lists no sectors"))?;
+        if sector_sizes.iter().any(|size| *size != sector_size) {
+            return Err(invalid("sectors of different sizes are not supported"));
+        }
+
+        Ok(FlashAlgorithm {
+            name,
+            code,
+            init: function("Init"),
+            uninit: function("UnInit"),
+            erase_sector: required("EraseSector")?,
+            program_page: required("ProgramPage")?,
+            data_offset,
+            flash_base: word(DEVICE_ADDRESS)?,
+            flash_size: word(DEVICE_SIZE)?,
+            page_size: word(DEVICE_PAGE_SIZE)?,
+            sector_size,
+            erased_value: device[DEVICE_ERASED_VALUE],
+            program_timeout: Duration::from_millis(word(DEVICE_PROGRAM_TIMEOUT)? as u64),
+            erase_timeout: Duration::from_millis(word(DEVICE_ERASE_TIMEOUT)? as u64),
+        })
+    }
+
+    /// Place the algorithm at the start of SRAM: the return BKPT, the code and
+    /// data, the stack, then two page buffers
+    pub fn layout(&self) -> Result<AlgorithmLayout, DebugError> {
+        let load_address = mspm0::SRAM_BASE;
+        let code_address = load_address + 4;
+        let stack_pointer = (code_address + (self.code.len() as u32)).next_multiple_of(8) + STACK_SIZE;
+        let buffer_size = self.page_size.next_multiple_of(8);
+        let buffers = [stack_pointer, stack_pointer + buffer_size];
+        if buffers[1] + buffer_size > mspm0::SRAM_BASE + mspm0::SRAM_SIZE {
+            return Err(
+                DebugError::InvalidArgument(
+                    format!(
+                        "{}: {} bytes of code and two {} byte pages do not fit in SRAM",
+                        self.name,
+                        self.code.len(),
+                        self.page_size
+                    )
+                )
+            );
+        }
+        Ok(AlgorithmLayout {
+            load_address,
+            code_address,
+            frame: RoutineFrame {
+                breakpoint: load_address,
+                stack_pointer,
+                static_base: self.data_offset.map(|offset| code_address + offset),
+            },
+            buffers,
+        })
+    }
+
+    /// The words written at `AlgorithmLayout::load_address`
+    pub fn image(&self) -> Vec<u32> {
+        let mut words = vec![RETURN_BKPT];
+        words.extend(
+            self.code
+                .chunks(4)
+                .map(|bytes| {
+                    let mut word = [0; 4];
+                    word[..bytes.len()].copy_from_slice(bytes);
+                    u32::from_le_bytes(word)
+                })
+        );
+        words
+    }
+
+    /// Whether the device the algorithm was written for covers `address..address + length`
+    pub fn covers(&self, address: u32, length: u32) -> bool {
+        let end = (self.flash_base as u64) + (self.flash_size as u64);
+        address >= self.flash_base && (address as u64) + (length as u64) <= end
+    }
+}
+
+impl fmt::Display for FlashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: 0x{:08X}-0x{:08X}, {} byte pages, {} byte sectors, {} bytes of code",
+            self.name,
+            self.flash_base,
+            ((self.flash_base as u64) + (self.flash_size as u64)).saturating_sub(1),
+            self.page_size,
+            self.sector_size,
+            self.code.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::elf::section_header::{ SHT_PROGBITS, SHT_STRTAB, SHT_SYMTAB };
+
+    /// Function symbols of the fixture: offsets into PrgCode with the Thumb bit set
+    const FUNCTIONS: [(&str, u32); 4] = [
+        ("Init", 0x01),
+        ("UnInit", 0x09),
+        ("EraseSector", 0x11),
+        ("ProgramPage", 0x21),
+    ];
+
+    /// A FlashDevice structure listing `sectors` as (size, offset) pairs
+    fn device(sectors: &[(u32, u32)]) -> Vec<u8> {
+        let mut device = vec![0; DEVICE_SECTORS];
+        device[0..2].copy_from_slice(&0x0101u16.to_le_bytes());
+        device[DEVICE_NAME.start..][..10].copy_from_slice(b"Test flash");
+        for (offset, value) in [
+            (DEVICE_ADDRESS, 0x0000_0000),
+            (DEVICE_SIZE, 0x0002_0000),
+            (DEVICE_PAGE_SIZE, 0x100),
+            (DEVICE_PROGRAM_TIMEOUT, 100),
+            (DEVICE_ERASE_TIMEOUT, 3000),
+        ] {
+            device[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        }
+        device[DEVICE_ERASED_VALUE] = 0xff;
+        for (size, offset) in sectors {
+            device.extend(size.to_le_bytes());
+            device.extend(offset.to_le_bytes());
+        }
+        device
+    }
+
+    /// A minimal 32-bit ARM ELF holding `sections` (name, type, address,
+    /// contents) and function symbols
+    fn flm(sections: &[(&str, u32, u32, Vec<u8>)], functions: &[(&str, u32)]) -> Vec<u8> {
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 16];
+        for (name, value) in functions {
+            symtab.extend((strtab.len() as u32).to_le_bytes());
+            symtab.extend(value.to_le_bytes());
+            symtab.extend(0u32.to_le_bytes());
+            // STB_GLOBAL, STT_FUNC in section 1 (PrgCode)
+            symtab.extend([0x12, 0, 1, 0]);
+            strtab.extend(name.as_bytes());
+            strtab.push(0);
+        }
+        let mut all: Vec<(&str, u32, u32, Vec<u8>)> = sections.to_vec();
+        all.push((".symtab", SHT_SYMTAB, 0, symtab));
+        all.push((".strtab", SHT_STRTAB, 0, strtab));
+        let mut shstrtab = vec![0];
+        let mut names = Vec::new();
+        for name in all.iter().map(|section| section.0).chain([".shstrtab"]) {
+            names.push(shstrtab.len() as u32);
+            shstrtab.extend(name.as_bytes());
+            shstrtab.push(0);
+        }
+        all.push((".shstrtab", SHT_STRTAB, 0, shstrtab));
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+        elf.resize(52, 0);
+        let mut headers = vec![0; 40];
+        for (index, (name, kind, address, data)) in all.iter().enumerate() {
+            let offset = elf.len() as u32;
+            if *kind != SHT_NOBITS {
+                elf.extend(data);
+                elf.resize(elf.len().next_multiple_of(4), 0);
+            }
+            // .strtab directly follows .symtab; section 0 is the null section
+            let (link, info, entry_size) = if *name == ".symtab" { ((index as u32) + 2, 1, 16) } else { (0, 0, 0) };
+            for value in [names[index], *kind, 0, *address, offset, data.len() as u32, link, info, 4, entry_size] {
+                headers.extend(value.to_le_bytes());
+            }
+        }
+        let header_offset = elf.len() as u32;
+        elf.extend(headers);
+        // ET_EXEC for EM_ARM, no program headers, .shstrtab last
+        elf[16..18].copy_from_slice(&2u16.to_le_bytes());
+        elf[18..20].copy_from_slice(&40u16.to_le_bytes());
+        elf[20..24].copy_from_slice(&1u32.to_le_bytes());
+        elf[32..36].copy_from_slice(&header_offset.to_le_bytes());
+        elf[40..42].copy_from_slice(&52u16.to_le_bytes());
+        elf[46..48].copy_from_slice(&40u16.to_le_bytes());
+        elf[48..50].copy_from_slice(&((all.len() + 1) as u16).to_le_bytes());
+        elf[50..52].copy_from_slice(&(all.len() as u16).to_le_bytes());
+        elf
+    }
+
+    /// Code, initialised and zero-initialised data, and a device with `sectors`
+    fn sections(sectors: &[(u32, u32)]) -> Vec<(&'static str, u32, u32, Vec<u8>)> {
+        vec![
+            ("PrgCode", SHT_PROGBITS, 0, (0..0x30).collect()),
+            ("PrgData", SHT_PROGBITS, 0x30, vec![0xaa; 8]),
+            ("PrgData", SHT_NOBITS, 0x38, vec![0; 8]),
+            ("DevDscr", SHT_PROGBITS, 0x40, device(sectors))
+        ]
+    }
+
+    /// Write `contents` where `from_flm` can read it and parse it
+    fn parse(name: &str, contents: &[u8]) -> Result<FlashAlgorithm, DebugError> {
+        let path = std::env::temp_dir().join(format!("flash_algorithm-{}-{}.FLM", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        let result = FlashAlgorithm::from_flm(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn error(result: Result<FlashAlgorithm, DebugError>) -> String {
+        match result {
+            Err(DebugError::InvalidArgument(message)) => message,
+            other => panic!("expected an invalid argument, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_an_flm() {
+        let algorithm = parse("valid", &flm(&sections(&[(0x400, 0), (SECTORS_END, SECTORS_END)]), &FUNCTIONS)).unwrap();
+        assert_eq!(algorithm.name, "Test flash");
+        // PrgData follows PrgCode, its zero-initialised part included
+        assert_eq!(algorithm.code.len(), 0x40);
+        assert_eq!(algorithm.code[..0x30], (0..0x30).collect::<Vec<u8>>()[..]);
+        assert_eq!(algorithm.code[0x30..0x38], [0xaa; 8]);
+        assert_eq!(algorithm.code[0x38..], [0; 8]);
+        assert_eq!(algorithm.data_offset, Some(0x30));
+        assert_eq!((algorithm.init, algorithm.uninit), (Some(0x00), Some(0x08)));
+        assert_eq!((algorithm.erase_sector, algorithm.program_page), (0x10, 0x20));
+        assert_eq!((algorithm.flash_base, algorithm.flash_size), (0, 0x20000));
+        assert_eq!((algorithm.page_size, algorithm.sector_size, algorithm.erased_value), (0x100, 0x400, 0xff));
+        assert_eq!(algorithm.program_timeout, Duration::from_millis(100));
+        assert_eq!(algorithm.erase_timeout, Duration::from_millis(3000));
+        assert!(algorithm.covers(0x1fc00, 0x400));
+        assert!(!algorithm.covers(0x1fc00, 0x401));
+
+        // Init and UnInit are optional
+        let algorithm = parse(
+            "required-only",
+            &flm(&sections(&[(0x400, 0), (SECTORS_END, SECTORS_END)]), &FUNCTIONS[2..])
+        ).unwrap();
+        assert_eq!((algorithm.init, algorithm.uninit), (None, None));
+    }
+
+    #[test]
+    fn the_sector_list_ends_at_its_terminator() {
+        // Entries after the terminator are not sectors
+        let algorithm = parse(
+            "terminated",
+            &flm(&sections(&[(0x400, 0), (SECTORS_END, SECTORS_END), (0x800, 0x10000)]), &FUNCTIONS)
+        ).unwrap();
+        assert_eq!(algorithm.sector_size, 0x400);
+
+        let unterminated = flm(&sections(&[(0x400, 0), (0x400, 0x400)]), &FUNCTIONS);
+        assert!(error(parse("unterminated", &unterminated)).ends_with("FlashDevice structure is truncated"));
+        let empty = flm(&sections(&[(SECTORS_END, SECTORS_END)]), &FUNCTIONS);
+        assert!(error(parse("empty", &empty)).ends_with("FlashDevice lists no sectors"));
+        let mixed = flm(&sections(&[(0x400, 0), (0x800, 0x8000), (SECTORS_END, SECTORS_END)]), &FUNCTIONS);
+        assert!(error(parse("mixed", &mixed)).ends_with("sectors of different sizes are not supported"));
+    }
+
+    #[test]
+    fn rejects_malformed_flms() {
+        let mut truncated = sections(&[]);
+        truncated[3].3.truncate(DEVICE_ERASED_VALUE);
+        assert!(error(parse("truncated", &flm(&truncated, &FUNCTIONS))).ends_with("FlashDevice structure is truncated"));
+
+        let terminated = [(0x400, 0), (SECTORS_END, SECTORS_END)];
+        let no_code: Vec<_> = sections(&terminated).into_iter().filter(|section| section.0 != "PrgCode").collect();
+        assert!(error(parse("no-code", &flm(&no_code, &FUNCTIONS))).ends_with("no PrgCode section"));
+        let no_device: Vec<_> = sections(&terminated).into_iter().filter(|section| section.0 != "DevDscr").collect();
+        assert!(error(parse("no-device", &flm(&no_device, &FUNCTIONS))).ends_with("no DevDscr section"));
+
+        let mut overlapping = sections(&terminated);
+        overlapping[1].2 = 0x20;
+        assert!(error(parse("overlapping", &flm(&overlapping, &FUNCTIONS))).ends_with("PrgData overlaps PrgCode"));
+
+        let no_erase = flm(&sections(&terminated), &[FUNCTIONS[0], FUNCTIONS[3]]);
+        assert!(error(parse("no-erase", &no_erase)).ends_with("no EraseSector function"));
+
+        assert!(error(parse("not-elf", b"not an ELF file")).starts_with(std::env::temp_dir().to_str().unwrap()));
+    }
+
+    #[test]
+    fn lays_out_the_builtin_algorithm() {
+        let algorithm = FlashAlgorithm::builtin();
+        let layout = algorithm.layout().unwrap();
+        let code_end = layout.code_address + (algorithm.code.len() as u32);
+        assert_eq!((layout.load_address, layout.code_address), (mspm0::SRAM_BASE, mspm0::SRAM_BASE + 4));
+        assert_eq!(layout.frame.breakpoint, layout.load_address);
+        assert_eq!(layout.frame.stack_pointer % 8, 0);
+        assert!(layout.frame.stack_pointer - STACK_SIZE >= code_end);
+        assert_eq!(layout.frame.static_base, None);
+        assert_eq!(layout.buffers, [layout.frame.stack_pointer, layout.frame.stack_pointer + algorithm.page_size]);
+
+        let image = algorithm.image();
+        assert_eq!(image[0], RETURN_BKPT);
+        assert_eq!(image[1..], routines::words(&routines::MSPM0_FLASH_LOADER)[..]);
+    }
+
+    #[test]
+    fn lays_out_position_independent_data() {
+        let mut algorithm = parse("layout", &flm(&sections(&[(0x400, 0), (SECTORS_END, SECTORS_END)]), &FUNCTIONS)).unwrap();
+        let layout = algorithm.layout().unwrap();
+        assert_eq!(layout.frame.static_base, Some(layout.code_address + 0x30));
+        // The stack starts 8-byte aligned after the 0x40 bytes of code and data
+        assert_eq!(layout.frame.stack_pointer, layout.load_address + 0x48 + STACK_SIZE);
+        // Odd page sizes keep the buffers 8-byte aligned
+        algorithm.page_size = 0x101;
+        assert_eq!(algorithm.layout().unwrap().buffers[1], layout.frame.stack_pointer + 0x108);
+        // The trailing partial word is zero-padded
+        algorithm.code.truncate(0x32);
+        assert_eq!(algorithm.image().last(), Some(&0x0000aaaa));
+
+        algorithm.page_size = mspm0::SRAM_SIZE / 2;
+        assert!(algorithm.layout().unwrap_err().to_string().contains("do not fit in SRAM"));
+    }
+}
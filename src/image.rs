@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::elf_reader::ElfFlashVerifier;
+use crate::error::DebugError;
+
+/// File formats `FirmwareImage::load` understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Elf,
+    /// Intel HEX, including extended segment and extended linear address records
+    IntelHex,
+    /// Raw bytes placed at a base address
+    Binary,
+}
+
+impl ImageFormat {
+    /// Guess the format from the ELF magic, then the file extension; anything
+    /// else is treated as raw binary
+    pub fn detect(path: &str, contents: &[u8]) -> Self {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase);
+        if contents.starts_with(b"\x7fELF") {
+            return ImageFormat::Elf;
+        }
+        match extension.as_deref() {
+            Some("hex" | "ihex" | "ihx") => ImageFormat::IntelHex,
+            Some("elf" | "out" | "axf") => ImageFormat::Elf,
+            _ => ImageFormat::Binary,
+        }
+    }
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "elf" => Ok(ImageFormat::Elf),
+            "hex" | "ihex" => Ok(ImageFormat::IntelHex),
+            "bin" | "binary" => Ok(ImageFormat::Binary),
+            _ => Err(format!("Unknown image format: {} (elf, hex or bin)", s)),
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageFormat::Elf => write!(f, "ELF"),
+            ImageFormat::IntelHex => write!(f, "Intel HEX"),
+            ImageFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// A contiguous run of image bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+/// Firmware to be written to the target, as non-overlapping segments sorted by address
+#[derive(Debug, Clone, Default)]
+pub struct FirmwareImage {
+    pub segments: Vec<Segment>,
+    /// Entry point from the ELF header or a HEX start address record
+    pub entry_point: Option<u32>,
+}
+
+impl FirmwareImage {
+    /// Read `path` as `format` (detected when `None`). `base` is where a raw
+    /// binary starts; ELF and HEX files carry their own addresses.
+    pub fn load(path: &str, format: Option<ImageFormat>, base: u32) -> Result<Self, DebugError> {
+        let contents = std::fs
+            ::read(path)
+            .map_err(|e| DebugError::InvalidArgument(format!("Cannot read {}: {}", path, e)))?;
+        let invalid = |e: String| DebugError::InvalidArgument(format!("{}: {}", path, e));
+        match format.unwrap_or_else(|| ImageFormat::detect(path, &contents)) {
+            ImageFormat::Elf => {
+                let elf = ElfFlashVerifier::from_elf_file(path).map_err(|e| invalid(e.to_string()))?;
+                let segments = elf.sections
+                    .into_iter()
+                    .filter(|section| !section.data.is_empty())
+                    .map(|section| Segment { address: section.address, data: section.data })
+                    .collect();
+                Self::from_segments(segments, Some(elf.entry_point)).map_err(invalid)
+            }
+            ImageFormat::IntelHex => {
+                let text = String::from_utf8(contents).map_err(|_| invalid("not a text file".to_string()))?;
+                Self::from_intel_hex(&text).map_err(invalid)
+            }
+            ImageFormat::Binary => Self::from_segments(vec![Segment { address: base, data: contents }], None).map_err(invalid),
+        }
+    }
+
+    /// Parse Intel HEX text: data (00), end of file (01), extended segment
+    /// address (02), start segment address (03), extended linear address (04)
+    /// and start linear address (05) records
+    pub fn from_intel_hex(text: &str) -> Result<Self, String> {
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut entry_point = None;
+        // Upper address bits set by type 02 or 04 records
+        let mut base: u32 = 0;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let number = index + 1;
+            let record = line
+                .strip_prefix(':')
+                .ok_or_else(|| format!("line {}: record does not start with ':'", number))?;
+            if record.len() % 2 != 0 {
+                return Err(format!("line {}: odd number of hex digits", number));
+            }
+            let bytes = (0..record.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&record[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| format!("line {}: invalid hex digit", number))?;
+            if bytes.len() < 5 || bytes.len() != (bytes[0] as usize) + 5 {
+                return Err(format!("line {}: record length does not match its byte count", number));
+            }
+            if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+                return Err(format!("line {}: checksum mismatch", number));
+            }
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            let field = |length: usize| -> Result<u32, String> {
+                if data.len() != length {
+                    return Err(format!("line {}: expected {} data bytes", number, length));
+                }
+                Ok(data.iter().fold(0u32, |value, byte| (value << 8) | (*byte as u32)))
+            };
+            match bytes[3] {
+                0x00 => {
+                    let address = base.wrapping_add(offset);
+                    match segments.last_mut() {
+                        Some(last) if (last.address as u64) + (last.data.len() as u64) == (address as u64) => {
+                            last.data.extend_from_slice(data);
+                        }
+                        _ => segments.push(Segment { address, data: data.to_vec() }),
+                    }
+                }
+                0x01 => {
+                    break;
+                }
+                0x02 => {
+                    base = field(2)? << 4;
+                }
+                0x03 => {
+                    let value = field(4)?;
+                    entry_point = Some(((value >> 16) << 4) + (value & 0xffff));
+                }
+                0x04 => {
+                    base = field(2)? << 16;
+                }
+                0x05 => {
+                    entry_point = Some(field(4)?);
+                }
+                kind => {
+                    return Err(format!("line {}: unknown record type {:02X}", number, kind));
+                }
+            }
+        }
+        Self::from_segments(segments, entry_point)
+    }
+
+    /// Sort the segments, drop empty ones, join touching ones and reject overlaps
+    /// and data past the end of the address space
+    fn from_segments(mut segments: Vec<Segment>, entry_point: Option<u32>) -> Result<Self, String> {
+        segments.retain(|segment| !segment.data.is_empty());
+        segments.sort_by_key(|segment| segment.address);
+        let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+        for segment in segments {
+            if (segment.address as u64) + (segment.data.len() as u64) > 1 << 32 {
+                return Err(format!("data at 0x{:08X} runs past the end of the address space", segment.address));
+            }
+            if let Some(last) = merged.last_mut() {
+                let end = (last.address as u64) + (last.data.len() as u64);
+                if (segment.address as u64) < end {
+                    return Err(format!("data overlaps at 0x{:08X}", segment.address));
+                }
+                if (segment.address as u64) == end {
+                    last.data.extend_from_slice(&segment.data);
+                    continue;
+                }
+            }
+            merged.push(segment);
+        }
+        Ok(FirmwareImage { segments: merged, entry_point })
+    }
+
+    /// Total number of image bytes
+    pub fn size(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.data.len())
+            .sum()
+    }
+
+    /// Image bytes grouped by the `sector_size`-aligned sector they fall in;
+    /// bytes the image does not cover are `None`
+    pub fn sectors(&self, sector_size: u32) -> BTreeMap<u32, Vec<Option<u8>>> {
+        let mut sectors: BTreeMap<u32, Vec<Option<u8>>> = BTreeMap::new();
+        for segment in &self.segments {
+            for (index, byte) in segment.data.iter().enumerate() {
+                let address = segment.address.wrapping_add(index as u32);
+                let sector = address & !(sector_size - 1);
+                let bytes = sectors.entry(sector).or_insert_with(|| vec![None; sector_size as usize]);
+                bytes[(address - sector) as usize] = Some(*byte);
+            }
+        }
+        sectors
+    }
+}
+
+impl fmt::Display for FirmwareImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes in {} segment(s)", self.size(), self.segments.len())?;
+        for segment in self.segments.iter().filter(|segment| !segment.data.is_empty()) {
+            let last = segment.address.wrapping_add((segment.data.len() as u32).wrapping_sub(1));
+            write!(f, ", 0x{:08X}-0x{:08X}", segment.address, last)?;
+        }
+        if let Some(entry_point) = self.entry_point {
+            write!(f, ", entry 0x{:08X}", entry_point)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One Intel HEX record with its checksum
+    fn record(kind: u8, offset: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (offset >> 8) as u8, offset as u8, kind];
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_sub(*byte));
+        bytes.push(checksum);
+        let digits: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        format!(":{}\n", digits)
+    }
+
+    #[test]
+    fn parses_every_record_type() {
+        let text = [
+            record(0x00, 0x0000, &[1, 2, 3, 4]),
+            // Touching data records are joined
+            record(0x00, 0x0004, &[5, 6]),
+            // Extended segment address: base 0x1230 << 4
+            record(0x02, 0x0000, &[0x12, 0x30]),
+            record(0x00, 0x0010, &[7]),
+            record(0x03, 0x0000, &[0x12, 0x34, 0x00, 0x08]),
+            // Extended linear address: base 0x2000 << 16
+            record(0x04, 0x0000, &[0x20, 0x00]),
+            record(0x00, 0x0100, &[8, 9]),
+            record(0x05, 0x0000, &[0x00, 0x00, 0x01, 0xdd]),
+            record(0x01, 0x0000, &[]),
+            // Nothing after the end of file record is read
+            record(0x00, 0x0200, &[10]),
+        ].concat();
+        let image = FirmwareImage::from_intel_hex(&text).unwrap();
+        assert_eq!(
+            image.segments,
+            [
+                Segment { address: 0x0, data: vec![1, 2, 3, 4, 5, 6] },
+                Segment { address: 0x12310, data: vec![7] },
+                Segment { address: 0x2000_0100, data: vec![8, 9] },
+            ]
+        );
+        assert_eq!(image.entry_point, Some(0x1dd));
+        assert_eq!(image.size(), 9);
+
+        // A start segment address is CS:IP
+        let text = record(0x03, 0x0000, &[0x12, 0x34, 0x00, 0x08]);
+        assert_eq!(FirmwareImage::from_intel_hex(&text).unwrap().entry_point, Some(0x12348));
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        let error = |text: &str| FirmwareImage::from_intel_hex(text).unwrap_err();
+        let good = record(0x00, 0x0000, &[1, 2]);
+
+        assert!(error("0200000001020B\n").contains("does not start with ':'"));
+        assert!(error(&format!("{}:0200000001020\n", good)).starts_with("line 2: odd number"));
+        assert!(error(":02000000010G0B\n").contains("invalid hex digit"));
+        assert!(error(":0300000001020A\n").contains("byte count"));
+        assert!(error(":02000000010201\n").contains("checksum"));
+        assert!(error(&record(0x04, 0x0000, &[0x20])).contains("expected 2 data bytes"));
+        assert!(error(&record(0x06, 0x0000, &[])).contains("unknown record type 06"));
+
+        let overlap = [record(0x00, 0x0000, &[1, 2, 3, 4]), record(0x00, 0x0002, &[5])].concat();
+        assert!(error(&overlap).contains("overlaps at 0x00000002"));
+        let wrap = [record(0x04, 0x0000, &[0xff, 0xff]), record(0x00, 0xfffe, &[1, 2, 3])].concat();
+        assert!(error(&wrap).contains("past the end of the address space"));
+    }
+
+    #[test]
+    fn segments_at_the_top_of_memory() {
+        let text = [
+            record(0x04, 0x0000, &[0xff, 0xff]),
+            record(0x00, 0xfffc, &[1, 2]),
+            record(0x00, 0xfffe, &[3, 4]),
+        ].concat();
+        let image = FirmwareImage::from_intel_hex(&text).unwrap();
+        assert_eq!(image.segments, [Segment { address: 0xffff_fffc, data: vec![1, 2, 3, 4] }]);
+        assert_eq!(image.to_string(), "4 bytes in 1 segment(s), 0xFFFFFFFC-0xFFFFFFFF");
+
+        let image = FirmwareImage::from_segments(
+            vec![Segment { address: 0, data: vec![] }, Segment { address: 0x10, data: vec![1] }],
+            None
+        ).unwrap();
+        assert_eq!(image.to_string(), "1 bytes in 1 segment(s), 0x00000010-0x00000010");
+    }
+
+    #[test]
+    fn hex_and_elf_builds_agree() {
+        let hex = FirmwareImage::load("main.hex", None, 0).unwrap();
+        let elf = FirmwareImage::load("main.elf", None, 0).unwrap();
+        assert_eq!(hex.segments, elf.segments);
+        assert_eq!(hex.sectors(1024), elf.sectors(1024));
+
+        let image = FirmwareImage::load("test.hex", Some(ImageFormat::IntelHex), 0).unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].address, 0);
+        assert_eq!(&image.segments[0].data[..8], &[0x00, 0x80, 0x20, 0x20, 0xcb, 0x01, 0x00, 0x00]);
+    }
+}
@@ -1,7 +1,104 @@
-use serialport::{ DataBits, FlowControl, Parity, SerialPort, StopBits };
-use std::io::Write;
-use std::time::Duration;
-use crate::protocol::{ ProtocolHandler, SWDCommand };
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::{ Duration, Instant };
+use crate::protocol::{
+    BatchOp,
+    BatchResult,
+    BridgeInfo,
+    FrameError,
+    ProtocolHandler,
+    Response,
+    SWDCommand,
+};
+use crate::cortex_m::{
+    self,
+    Breakpoint,
+    CoreStatus,
+    BP_COMP_ENABLE,
+    BP_CTRL_ADDR,
+    BP_CTRL_ENABLE,
+    BP_CTRL_KEY,
+    BP_MAX_ADDRESS,
+    DEMCR_ADDR,
+    DEMCR_DWTENA,
+    DEMCR_VC_CORERESET,
+    DFSR_ADDR,
+    DFSR_ALL,
+    DFSR_BKPT,
+    DFSR_DWTTRAP,
+    DFSR_EXTERNAL,
+    DFSR_HALTED,
+    DFSR_VCATCH,
+    DWT_CTRL_ADDR,
+    DWT_FUNCTION_MASK,
+    DWT_FUNCTION_MATCHED,
+    HaltReason,
+    CaughtVector,
+    ExceptionFrame,
+    VectorCatch,
+    EXC_RETURN_SPSEL,
+    HARDFAULT_EXCEPTION,
+    XPSR_THUMB,
+    WatchKind,
+    Watchpoint,
+    C_DEBUGEN,
+    C_HALT,
+    C_MASKINTS,
+    C_STEP,
+    DBGKEY,
+    DCRDR_ADDR,
+    DCRSR_ADDR,
+    DCRSR_REGWNR,
+    DHCSR_ADDR,
+    S_HALT,
+    S_REGRDY,
+    S_RESET_ST,
+    AIRCR_ADDR,
+    AIRCR_SYSRESETREQ,
+    AIRCR_VECTKEY,
+};
+use crate::mspm0::{
+    self,
+    ResetKind,
+    FLASHCTL_CMDADDR_ADDR,
+    FLASHCTL_CMDBYTEN_ADDR,
+    FLASHCTL_CMDBYTEN_WORD_ECC,
+    FLASHCTL_CMDDATA0_ADDR,
+    FLASHCTL_CMDDATA1_ADDR,
+    FLASHCTL_CMDEXEC_ADDR,
+    FLASHCTL_CMDEXEC_EXECUTE,
+    FLASHCTL_CMDTYPE_ADDR,
+    FLASHCTL_CMDTYPE_ERASE,
+    FLASHCTL_CMDTYPE_PROGRAM,
+    FLASHCTL_CMDTYPE_SIZE_ONEWORD,
+    FLASHCTL_CMDTYPE_SIZE_SECTOR,
+    FLASHCTL_STATCMD_ADDR,
+    FLASHCTL_STATCMD_DONE,
+    FLASHCTL_STATCMD_IN_PROGRESS,
+    FLASHCTL_STATCMD_PASS,
+    FLASH_BASE,
+    FLASH_SECTOR_SIZE,
+    FLASH_SIZE,
+    FLASH_WORD_SIZE,
+    SYSCTL_RESETCMD_ADDR,
+    SYSCTL_RESETCMD_GO,
+    SYSCTL_RESETCMD_KEY,
+    SYSCTL_RESETLEVEL_ADDR,
+    SYSCTL_RESETLEVEL_BOOT,
+    SYSCTL_RESETLEVEL_CPU,
+};
+#[cfg(feature = "cli")]
+use crate::mspm0::{ FLASHCTL_CMDTYPE_SIZE_BANK, FLASHCTL_CMDWEPROTA_ADDR, FLASHCTL_CMDWEPROTB_ADDR };
+use crate::elf_reader::{ ElfFlashVerifier, VerificationResult };
+use crate::error::DebugError;
+use crate::fault::FaultReport;
+use crate::flash_algorithm::{ AlgorithmLayout, FlashAlgorithm };
+use crate::image::FirmwareImage;
+use crate::routines;
+use crate::transport::{ SerialTransport, Transport };
+#[cfg(feature = "cli")]
+use crate::transport::TcpTransport;
 use tracing::info;
 
 pub const TARGET_PID: u16 = 0x8055; // Change this to your specific device PID
@@ -19,74 +116,246 @@ pub fn is_device_connected(pid: u16) -> bool {
     }
     false
 }
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(300);
+
+const REG_R9: u32 = 0x09;
+const REG_SP: u32 = 0x0d;
+const REG_LR: u32 = 0x0e;
+const REG_PC: u32 = 0x0f;
+const REG_XPSR: u32 = 0x10;
+const REG_MSP: u32 = 0x11;
+const REG_PSP: u32 = 0x12;
+/// DHCSR reads allowed for a register transfer to complete
+const REGRDY_POLL_ATTEMPTS: u16 = 100;
+/// DHCSR reads allowed for the core to enter or leave halt
+const HALT_POLL_ATTEMPTS: u16 = 100;
+/// Largest region one DWT comparator can cover (MASK of 15 bits)
+const MAX_WATCH_SIZE: u32 = 1 << 15;
+/// Delay between DHCSR reads while waiting for the core to halt
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long a reset may take to show up in DHCSR.S_RESET_ST
+const RESET_TIMEOUT: Duration = Duration::from_millis(500);
+/// STATCMD reads allowed for a flash word to be programmed (tens of microseconds)
+const FLASH_PROGRAM_POLL_ATTEMPTS: u16 = 100;
+/// How long a sector or bank erase may take
+const FLASH_ERASE_TIMEOUT: Duration = Duration::from_millis(2000);
+/// Delay between STATCMD reads while an erase runs
+const FLASH_POLL_INTERVAL: Duration = Duration::from_millis(2);
+/// Flash words programmed per BATCH round-trip, between progress callbacks
+const FLASH_WORDS_PER_BATCH: usize = 32;
+/// SRAM address routines are loaded to; the memory they cover is restored afterwards
+const ROUTINE_ADDRESS: u32 = mspm0::SRAM_BASE;
+/// How long a routine may run before it is halted
+const ROUTINE_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Extra run time allowed for each KB the CRC-32 routine reads (about 60 cycles per byte)
+const CRC_TIME_PER_KB: Duration = Duration::from_millis(10);
+/// Core registers a routine changes, saved before and restored after it runs
+const ROUTINE_REGISTERS: [u32; 10] = [0, 1, 2, 3, 4, 5, REG_SP, REG_LR, REG_PC, REG_XPSR];
+/// `function` argument of a flash algorithm's Init and UnInit (CMSIS FlashOS.h)
+const FLM_ERASE: u32 = 1;
+const FLM_PROGRAM: u32 = 2;
+
+/// How code loaded into SRAM is called: its functions return through LR to the
+/// BKPT at `breakpoint`, run on the stack at `stack_pointer` and, when the code
+/// reaches its data relative to R9 (RWPI), find it at `static_base`
+#[derive(Debug, Clone, Copy)]
+pub struct RoutineFrame {
+    pub breakpoint: u32,
+    pub stack_pointer: u32,
+    pub static_base: Option<u32>,
+}
+
+/// Where and why the core stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HaltEvent {
+    pub pc: u32,
+    pub reason: HaltReason,
+}
+
+/// Where a step command stopped
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StepOutcome {
+    pub pc: u32,
+    /// Instructions executed
+    pub steps: u32,
+    /// For range stepping: the PC left the range (rather than hitting the step limit)
+    pub left_range: bool,
+    /// Why the last step halted; anything but `Step` (a breakpoint, a watchpoint) ends stepping early
+    pub reason: HaltReason,
+}
+
+/// How far a flash operation has got, passed to progress callbacks
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FlashProgress {
+    /// Last flash word programmed, or the sector just handled when flashing an image
+    pub address: u32,
+    /// Bytes handled so far
+    pub done: usize,
+    pub total: usize,
+}
+
+/// What `flash_image` did to each sector the image touches
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct FlashSummary {
+    pub sectors: usize,
+    /// Sectors that were erased and reprogrammed
+    pub erased: usize,
+    /// Sectors programmed without an erase because the changed words were still erased
+    pub programmed: usize,
+    /// Sectors that already held the image
+    pub unchanged: usize,
+    /// Flash words written
+    pub words: usize,
+}
+
+impl std::fmt::Display for FlashSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} sector(s): {} erased and programmed, {} programmed, {} unchanged; {} flash words written",
+            self.sectors,
+            self.erased,
+            self.programmed,
+            self.unchanged,
+            self.words
+        )
+    }
+}
+
+/// What flashing one sector takes, from comparing the image with its contents
+enum SectorPlan {
+    Unchanged,
+    /// Runs of changed words that are all still erased, programmed without an erase
+    Program(Vec<(u32, Vec<u8>)>),
+    /// The whole sector, erased and programmed again
+    Erase(Vec<u8>),
+}
+
+/// How bridge commands are timed out and retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How long to wait for each response
+    pub timeout: Duration,
+    /// Extra attempts for idempotent commands (reads, halt); others are sent once
+    pub max_retries: u32,
+    /// Delay before the first retry
+    pub backoff: Duration,
+    /// Factor applied to the delay after every retry
+    pub backoff_multiplier: u32,
+    /// Longest delay between two attempts, however many retries are allowed
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            timeout: Duration::from_millis(1000),
+            max_retries: 3,
+            backoff: Duration::from_millis(20),
+            backoff_multiplier: 2,
+            max_backoff: Duration::from_millis(1000),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay after `delay`: grown by `backoff_multiplier`, at most `max_backoff`
+    fn next_backoff(&self, delay: Duration) -> Duration {
+        delay.saturating_mul(self.backoff_multiplier).min(self.max_backoff)
+    }
+}
+
 pub struct SerialLoader {
-    port: Option<Box<dyn SerialPort>>,
+    transport: Option<Box<dyn Transport>>,
+    bridge: BridgeInfo,
+    next_tag: u8,
+    retry_policy: RetryPolicy,
+    last_retries: u32,
+    total_retries: u64,
+    /// Watchpoint decoded for the current halt while its DFSR event is kept.
+    /// Reading DWT FUNCTION clears MATCHED, so a later peek could not find it again.
+    halt_watchpoint: Option<Watchpoint>,
 }
 impl SerialLoader {
     /// Create a new ARM debug serial connection
     pub fn new(
         port_name: Option<&str>,
         baud_rate: u32
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let final_port_name = if port_name.is_none() {
-            let ports = serialport::available_ports()?;
-            info!("Available serial ports:");
-            if ports.is_empty() {
-                info!("No serial ports found");
-                return Ok(SerialLoader { port: None });
-            }
-            info!("number of ports: {}", ports.len());
-            let mut found_port_name = None;
-            for port in &ports {
-                match port.port_type {
-                    serialport::SerialPortType::UsbPort(ref usb_info) => {
-                        if usb_info.pid == TARGET_PID {
-                            found_port_name = Some(port.port_name.clone());
-                            info!("Found matching USB serial port: {}", port.port_name);
-                            break;
+    ) -> Result<Self, DebugError> {
+        let final_port_name = match port_name {
+            Some(name) => name.to_string(),
+            None => {
+                let ports = serialport::available_ports()?;
+                info!("Available serial ports:");
+                if ports.is_empty() {
+                    info!("No serial ports found");
+                    return Ok(Self::disconnected());
+                }
+                info!("number of ports: {}", ports.len());
+                let mut found_port_name = None;
+                for port in &ports {
+                    match port.port_type {
+                        serialport::SerialPortType::UsbPort(ref usb_info) => {
+                            if usb_info.pid == TARGET_PID {
+                                found_port_name = Some(port.port_name.clone());
+                                info!("Found matching USB serial port: {}", port.port_name);
+                                break;
+                            }
                         }
+                        _ => {}
                     }
-                    _ => {}
                 }
-            }
-            match found_port_name {
-                Some(name) => name,
-                None => {
-                    return Ok(SerialLoader { port: None });
+                match found_port_name {
+                    Some(name) => name,
+                    None => {
+                        return Ok(Self::disconnected());
+                    }
                 }
             }
-        } else {
-            port_name.unwrap().to_string()
         };
-        let port = serialport
-            ::new(final_port_name, baud_rate)
-            .timeout(Duration::from_millis(1000))
-            .data_bits(DataBits::Eight)
-            .flow_control(FlowControl::None)
-            .parity(Parity::None)
-            .stop_bits(StopBits::One)
-            .open()?;
-
-        Ok(SerialLoader { port: Some(port) })
+        let transport = SerialTransport::open(&final_port_name, baud_rate)?;
+        Self::with_transport(Box::new(transport))
     }
-    //close the port
-    pub fn close(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        if let Some(ref mut port) = self.port {
-            // port.flush()?;
-            // port.clear(serialport::ClearBuffer::All)?;
+    /// Loader without a link; commands fail until a transport is attached
+    pub fn disconnected() -> Self {
+        SerialLoader {
+            transport: None,
+            bridge: BridgeInfo::legacy(),
+            next_tag: 0,
+            retry_policy: RetryPolicy::default(),
+            last_retries: 0,
+            total_retries: 0,
+            halt_watchpoint: None,
         }
-        self.port = None;
-        // Port will be dropped when the struct is dropped
+    }
+    /// Connect to a bridge over TCP (`host:port`)
+    #[cfg(feature = "cli")]
+    pub fn connect_tcp(address: &str) -> Result<Self, DebugError> {
+        let transport = TcpTransport::connect(address)?;
+        Self::with_transport(Box::new(transport))
+    }
+    /// Create a loader on top of an already opened transport
+    pub fn with_transport(transport: Box<dyn Transport>) -> Result<Self, DebugError> {
+        let mut loader = Self::disconnected();
+        loader.set_transport(transport)?;
+        Ok(loader)
+    }
+    //close the port
+    pub fn close(&mut self) -> Result<(), DebugError> {
+        // Transport will be dropped here, closing the underlying link
+        self.transport = None;
+        self.bridge = BridgeInfo::legacy();
+        self.halt_watchpoint = None;
         info!("Serial port closed successfully");
         Ok(())
     }
     //reconnect the port
-    pub fn reconnect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn reconnect(&mut self) -> Result<(), DebugError> {
         self.close()?;
         let ports = serialport::available_ports()?;
         info!("Available serial ports:");
         if ports.is_empty() {
-            return Err("No serial ports found".into());
+            return Err(DebugError::NotConnected);
         }
         info!("number of ports: {}", ports.len());
         let mut found_port_name = None;
@@ -102,64 +371,306 @@ impl SerialLoader {
                 _ => {}
             }
         }
-        let final_port_name = found_port_name.ok_or("No matching USB serial port found")?;
-        let port = serialport
-            ::new(final_port_name.clone(), 115200)
-            .timeout(Duration::from_millis(1000))
-            .data_bits(DataBits::Eight)
-            .flow_control(FlowControl::None)
-            .parity(Parity::None)
-            .stop_bits(StopBits::One)
-            .open()?;
-        self.port = Some(port);
+        let final_port_name = found_port_name.ok_or(DebugError::NotConnected)?;
+        let transport = SerialTransport::open(&final_port_name, 115200)?;
+        self.set_transport(Box::new(transport))?;
         info!("Reconnected to serial port: {}", final_port_name);
         Ok(())
     }
-    /// Halt the Program
-    pub fn halt(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let command = ProtocolHandler::new(SWDCommand::Halt);
-        let port = self.get_port()?;
-        port.write_all(&command.write_frame())?;
-        port.flush()?;
-
-        // Wait for response
-        std::thread::sleep(Duration::from_millis(10));
-
-        // Read response to clear buffer
-        let mut buffer = [0; 256];
-        match port.read(&mut buffer) {
-            Ok(_) => {}
-            Err(_) => {} // Ignore timeout errors
+    /// Replace the current link, e.g. to switch from USB to TCP, and
+    /// negotiate capabilities with the bridge behind it
+    pub fn set_transport(
+        &mut self,
+        transport: Box<dyn Transport>
+    ) -> Result<(), DebugError> {
+        info!("Using transport: {}", transport.describe());
+        self.transport = Some(transport);
+        if let Err(e) = self.handshake() {
+            self.close()?;
+            return Err(e);
         }
+        Ok(())
+    }
+    /// Query the bridge firmware with INFO. Firmware that does not answer is
+    /// treated as legacy; firmware with another major protocol version or
+    /// without the basic memory commands is refused.
+    fn handshake(&mut self) -> Result<(), DebugError> {
+        // Single attempt with a short timeout so legacy firmware does not stall the connect
+        self.get_transport()?.set_timeout(HANDSHAKE_TIMEOUT)?;
+        let result = self.transact_once(SWDCommand::Info);
+        let timeout = self.retry_policy.timeout;
+        self.get_transport()?.set_timeout(timeout)?;
 
+        let info = match result {
+            Ok(payload) => BridgeInfo::parse(&payload)?,
+            Err(e) => {
+                info!("Bridge did not answer INFO ({}), assuming legacy firmware", e);
+                BridgeInfo::legacy()
+            }
+        };
+        if !info.is_legacy() && info.version.0 != BridgeInfo::PROTOCOL_MAJOR {
+            return Err(
+                DebugError::IncompatibleBridge(
+                    format!(
+                        "Incompatible bridge firmware {}.{}.{} (host speaks protocol {}.x)",
+                        info.version.0,
+                        info.version.1,
+                        info.version.2,
+                        BridgeInfo::PROTOCOL_MAJOR
+                    )
+                )
+            );
+        }
+        for opcode in [ProtocolHandler::READ_WORD, ProtocolHandler::WRITE_COMMAND] {
+            if !info.supports(opcode) {
+                return Err(DebugError::Unsupported { opcode });
+            }
+        }
+        if info.max_payload < ProtocolHandler::WORD_SIZE {
+            return Err(
+                DebugError::IncompatibleBridge(
+                    format!("Bridge reports unusable max payload of {} bytes", info.max_payload)
+                )
+            );
+        }
+        info!("Bridge: {}", info);
+        self.bridge = info;
         Ok(())
     }
-    /// Resume the Program
-    pub fn resume(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let command = ProtocolHandler::new(SWDCommand::Resume);
-        let port = self.get_port()?;
-        port.write_all(&command.write_frame())?;
-        port.flush()?;
-        // Wait for response
-        std::thread::sleep(Duration::from_millis(10));
-        // Read response to clear buffer
-        let mut buffer = [0; 256];
-        match port.read(&mut buffer) {
-            Ok(_) => {
-                todo!();
-            }
-            Err(_) => {} // Ignore timeout errors
+    /// Capabilities negotiated with the bridge
+    pub fn bridge_info(&self) -> &BridgeInfo {
+        &self.bridge
+    }
+    /// Change how commands are timed out and retried
+    #[cfg(feature = "cli")]
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> Result<(), DebugError> {
+        if let Some(transport) = self.transport.as_mut() {
+            transport.set_timeout(policy.timeout)?;
         }
+        self.retry_policy = policy;
         Ok(())
     }
+    /// Number of retries the most recent bridge command needed
+    pub fn last_retries(&self) -> u32 {
+        self.last_retries
+    }
+    /// Retries needed by all commands since the loader was created
+    #[cfg(any(test, feature = "cli"))]
+    pub fn total_retries(&self) -> u64 {
+        self.total_retries
+    }
+    /// Halt the Program: request C_HALT through DHCSR, wait for S_HALT and report
+    /// where the core stopped. A core that was already halted by a breakpoint or
+    /// watchpoint reports that event rather than the request.
+    pub fn halt(&mut self) -> Result<HaltEvent, DebugError> {
+        let mut ops = vec![
+            BatchOp::WriteWord { address: DHCSR_ADDR, value: DBGKEY | C_DEBUGEN | C_HALT },
+            BatchOp::PollBitSet { address: DHCSR_ADDR, mask: S_HALT, attempts: HALT_POLL_ATTEMPTS }
+        ];
+        // Only this part is retried: a rerun after a lost reply must still find
+        // DFSR set, so it is cleared on its own once the reason is decoded
+        ops.extend(Self::halt_event_ops(false));
+        let results = self.execute_repeatable_batch(&ops)?;
+        let event = self.decode_halt_event(&results, false, false)?;
+        self.write_word(DFSR_ADDR, DFSR_ALL)?;
+        self.halt_watchpoint = None;
+        info!("Core halted at 0x{:08X} ({})", event.pc, event.reason);
+        Ok(event)
+    }
+    /// Resume the Program: clear C_HALT (keeping C_DEBUGEN so breakpoints still
+    /// halt the core) and wait until DHCSR no longer reports S_HALT
+    pub fn resume(&mut self) -> Result<CoreStatus, DebugError> {
+        // Drop the events of this halt so DFSR only reports why the core stops next
+        self.write_word(DFSR_ADDR, DFSR_ALL)?;
+        self.halt_watchpoint = None;
+        self.write_word(DHCSR_ADDR, DBGKEY | C_DEBUGEN)?;
+        for _ in 0..HALT_POLL_ATTEMPTS {
+            let status = self.core_status()?;
+            if !status.halted {
+                info!("Core {}", status);
+                return Ok(status);
+            }
+        }
+        Err(DebugError::PollTimeout { address: DHCSR_ADDR, mask: S_HALT })
+    }
+    /// Read DHCSR and decode the core state. S_RESET_ST and S_RETIRE_ST clear on read.
+    pub fn core_status(&mut self) -> Result<CoreStatus, DebugError> {
+        let dhcsr = self.read_word(DHCSR_ADDR)?;
+        Ok(CoreStatus::from_dhcsr(dhcsr))
+    }
+    /// The PC and halt reason if the core is halted, `None` while it runs. With
+    /// `clear` the DFSR event bits are cleared so the next halt starts fresh.
+    pub fn poll_halt(&mut self, clear: bool) -> Result<Option<HaltEvent>, DebugError> {
+        if !self.core_status()?.halted {
+            self.halt_watchpoint = None;
+            return Ok(None);
+        }
+        let results = self.execute_batch_checked(&Self::halt_event_ops(clear))?;
+        self.decode_halt_event(&results, false, clear).map(Some)
+    }
+    /// Block until the core halts on its own (breakpoint, watchpoint, BKPT...)
+    /// or `timeout` expires
+    pub fn wait_for_halt(&mut self, timeout: Duration) -> Result<HaltEvent, DebugError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.poll_halt(true)? {
+                info!("Core halted at 0x{:08X} ({})", event.pc, event.reason);
+                return Ok(event);
+            }
+            if Instant::now() >= deadline {
+                return Err(DebugError::PollTimeout { address: DHCSR_ADDR, mask: S_HALT });
+            }
+            std::thread::sleep(WAIT_POLL_INTERVAL);
+        }
+    }
+    /// Reset the target. With `halt` DEMCR.VC_CORERESET is set for the duration of
+    /// the reset so the core stops on the first instruction of the reset handler,
+    /// and the resulting halt event is returned.
+    pub fn reset(&mut self, kind: ResetKind, halt: bool) -> Result<Option<HaltEvent>, DebugError> {
+        let demcr = self.read_word(DEMCR_ADDR)?;
+        if halt {
+            // Vector catch only halts the core while C_DEBUGEN is set
+            self.enable_debug()?;
+            self.write_word(DEMCR_ADDR, demcr | DEMCR_VC_CORERESET)?;
+        }
+        // Reading DHCSR clears S_RESET_ST, so the next time it is set comes from this reset
+        self.core_status()?;
+        let request = match kind {
+            ResetKind::System => self.write_word(AIRCR_ADDR, AIRCR_VECTKEY | AIRCR_SYSRESETREQ),
+            ResetKind::Core | ResetKind::Boot => {
+                let level = if kind == ResetKind::Core {
+                    SYSCTL_RESETLEVEL_CPU
+                } else {
+                    SYSCTL_RESETLEVEL_BOOT
+                };
+                self.write_word(SYSCTL_RESETLEVEL_ADDR, level)?;
+                self.write_word(SYSCTL_RESETCMD_ADDR, SYSCTL_RESETCMD_KEY | SYSCTL_RESETCMD_GO)
+            }
+        };
+        match request {
+            // The reset can tear down the bus transfer that requested it, so the
+            // bridge may NACK the write or not answer at all; the S_RESET_ST poll
+            // below tells whether the reset happened
+            Ok(()) | Err(DebugError::Nack { .. } | DebugError::Timeout) => {}
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        let deadline = Instant::now() + RESET_TIMEOUT;
+        let mut reset_seen = false;
+        loop {
+            let status = self.core_status()?;
+            reset_seen |= status.reset;
+            if reset_seen && (!halt || status.halted) {
+                break;
+            }
+            if Instant::now() >= deadline {
+                let mask = if reset_seen { S_HALT } else { S_RESET_ST };
+                return Err(DebugError::PollTimeout { address: DHCSR_ADDR, mask });
+            }
+            std::thread::sleep(WAIT_POLL_INTERVAL);
+        }
+        if halt {
+            self.write_word(DEMCR_ADDR, demcr)?;
+            let event = self.poll_halt(true)?;
+            if let Some(event) = event {
+                info!("{}, halted at 0x{:08X} ({})", kind, event.pc, event.reason);
+            }
+            return Ok(event);
+        }
+        info!("{}, core running", kind);
+        Ok(None)
+    }
+    /// Vector catches currently enabled in DEMCR
+    pub fn vector_catch(&mut self) -> Result<VectorCatch, DebugError> {
+        Ok(VectorCatch::from_demcr(self.read_word(DEMCR_ADDR)?))
+    }
+    /// Enable or disable the HardFault and reset vector catches, keeping the
+    /// other DEMCR bits. Catches only halt the core while C_DEBUGEN is set.
+    pub fn set_vector_catch(&mut self, catch: VectorCatch) -> Result<VectorCatch, DebugError> {
+        if catch.hard_fault || catch.core_reset {
+            self.enable_debug()?;
+        }
+        let demcr = self.read_word(DEMCR_ADDR)?;
+        self.write_word(DEMCR_ADDR, catch.apply(demcr))?;
+        info!("Vector catch: {}", catch);
+        Ok(catch)
+    }
+    /// Registers the core stacked on entry to the exception it is handling, on
+    /// the stack EXC_RETURN in LR selects. `None` in Thread mode.
+    pub fn exception_frame(&mut self) -> Result<Option<ExceptionFrame>, DebugError> {
+        let values = self.read_registers(&[REG_XPSR, REG_LR, REG_MSP, REG_PSP])?;
+        let &[xpsr, lr, msp, psp] = values.as_slice() else {
+            return Err(DebugError::Frame(FrameError::PayloadLength { expected: 16, received: values.len() * 4 }));
+        };
+        // LR only holds EXC_RETURN until the handler calls another function
+        if cortex_m::exception_number(xpsr) == 0 || !cortex_m::is_exc_return(lr) {
+            return Ok(None);
+        }
+        let process_stack = (lr & EXC_RETURN_SPSEL) != 0;
+        let address = if process_stack { psp } else { msp };
+        let ops: Vec<BatchOp> = (0..ExceptionFrame::WORDS as u32)
+            .map(|i| BatchOp::ReadWord { address: address + i * 4 })
+            .collect();
+        let words: Vec<u32> = self
+            .execute_batch_checked(&ops)?
+            .iter()
+            .filter_map(|result| {
+                match result {
+                    BatchResult::Value(value) => Some(*value),
+                    _ => None,
+                }
+            })
+            .collect();
+        Ok(ExceptionFrame::from_words(address, process_stack, &words))
+    }
+    /// Collect what is needed to explain a fault: the exception being handled,
+    /// its stacked frame and the instruction at the stacked PC. A running core
+    /// (e.g. spinning in its HardFault handler) is halted first.
+    pub fn fault_report(&mut self) -> Result<FaultReport, DebugError> {
+        let status = self.core_status()?;
+        if !status.halted {
+            self.halt()?;
+        }
+        let values = self.read_registers(&[REG_PC, REG_LR, REG_XPSR])?;
+        let &[pc, lr, xpsr] = values.as_slice() else {
+            return Err(DebugError::Frame(FrameError::PayloadLength { expected: 12, received: values.len() * 4 }));
+        };
+        let (frame, unreadable_frame) = match self.exception_frame() {
+            Ok(frame) => (frame, None),
+            Err(DebugError::TargetFault { address }) => (None, Some(address)),
+            Err(e) => {
+                return Err(e);
+            }
+        };
+        let instruction = match frame {
+            Some(frame) =>
+                self
+                    .read_word(frame.pc & !3)
+                    .ok()
+                    .map(|word| if (frame.pc & 2) != 0 { (word >> 16) as u16 } else { word as u16 }),
+            None => None,
+        };
+        let report = FaultReport::new(pc, lr, xpsr, status.lockup, frame, unreadable_frame, instruction);
+        info!("Fault report: {}", report.summary());
+        Ok(report)
+    }
+    /// Fail with `NotHalted` unless the core is halted
+    pub fn ensure_halted(&mut self) -> Result<CoreStatus, DebugError> {
+        let status = self.core_status()?;
+        if !status.halted {
+            return Err(DebugError::NotHalted);
+        }
+        Ok(status)
+    }
 
     /// Write to memory-mapped register (equivalent to OpenOCD's mww command)
     pub fn write_word(
         &mut self,
         address: u32,
         value: u32
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let command = ProtocolHandler::new(SWDCommand::Write {
+    ) -> Result<(), DebugError> {
+        let command = SWDCommand::Write {
             write_address: address,
             write_data: vec![
                 ((value >> 24) & 0xff) as u8,
@@ -167,73 +678,49 @@ impl SerialLoader {
                 ((value >> 8) & 0xff) as u8,
                 (value & 0xff) as u8
             ],
-        });
-        // let command = format!("mww 0x{:08X} 0x{:08X}\n", address, value);
-        let port = self.get_port()?;
-        port.write_all(&command.write_frame())?;
-        port.flush()?;
-
-        // Wait for response
-        std::thread::sleep(Duration::from_millis(10));
-
-        // Read response to clear buffer
-        let mut buffer = [0; 256];
-        match port.read(&mut buffer) {
-            Ok(_) => {}
-            Err(_) => {} // Ignore timeout errors
-        }
-
+        };
+        self.transact(command)?;
         Ok(())
     }
     //read_bytes
-    /// Read from memory-mapped register (equivalent to OpenOCD's mrb command)
+    /// Read from memory-mapped register (equivalent to OpenOCD's mrb command).
+    /// Reads larger than the bridge's max payload are split into several frames.
     pub fn read_bytes(
         &mut self,
         address: u32,
         length: u32
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let command = ProtocolHandler::new(SWDCommand::ReadBytes {
-            start_address: address,
-            length,
-        });
-        let port = self.get_port()?;
-        port.write_all(&command.write_frame())?;
-        port.flush()?;
-
-        // Wait for response
-        std::thread::sleep(Duration::from_millis(50));
-
-        // Read response
-        let mut buffer = vec![0; length as usize];
-        match port.read_exact(&mut buffer) {
-            Ok(_) => Ok(buffer),
-            Err(e) => {
-                info!("Error reading bytes from address 0x{:08X}: {}", address, e);
-                Err(e.into())
-            }
-        }
-    }
-    pub fn read_word(&mut self, address: u32) -> Result<u32, Box<dyn std::error::Error>> {
-        let command = ProtocolHandler::new(SWDCommand::ReadWord { start_address: address });
-        let port = self.get_port()?;
-        port.write_all(&command.write_frame())?;
-        port.flush()?;
-        // Wait for response
-        std::thread::sleep(Duration::from_millis(50));
-        // Read response
-        let mut buffer = [0; 8]; // 4 bytes for a word
-        match port.read_exact(&mut buffer) {
-            Ok(_) => {
-                info!("Read word from address 0x{:08X}: {:?}", address, buffer);
-                info!("Buffer length: {}", buffer.len());
-                info!("Buffer content: {:02X?}", buffer);
-                // Convert buffer to u32 value
-                let value = u32::from_le_bytes(buffer[..4].try_into().unwrap());
-                Ok(value)
+    ) -> Result<Vec<u8>, DebugError> {
+        let chunk_size = self.bridge.max_payload.min(ProtocolHandler::MAX_DATA_LENGTH) as u32;
+        let mut data = Vec::with_capacity(length as usize);
+        let mut offset = 0;
+        while offset < length {
+            let count = (length - offset).min(chunk_size);
+            let command = SWDCommand::ReadBytes {
+                start_address: address.wrapping_add(offset),
+                length: count,
+            };
+            match self.transact(command) {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(e) => {
+                    info!("Error reading bytes from address 0x{:08X}: {}", address + offset, e);
+                    return Err(e);
+                }
+            }
+            offset += count;
+        }
+        Ok(data)
+    }
+    pub fn read_word(&mut self, address: u32) -> Result<u32, DebugError> {
+        let command = SWDCommand::ReadWord { start_address: address };
+        match self.transact(command) {
+            Ok(data) => {
+                info!("Read word from address 0x{:08X}: {:02X?}", address, data);
+                // Convert payload to u32 value
+                Ok(Self::first_word(&data)?)
             }
             Err(e) => {
                 info!("Error reading word from address 0x{:08X}: {}", address, e);
-                Err(e.into())
+                Err(e)
             }
         }
     }
@@ -242,76 +729,1195 @@ impl SerialLoader {
         &mut self,
         address: u32,
         length: u32
-    ) -> Result<u32, Box<dyn std::error::Error>> {
-        let command = ProtocolHandler::new(SWDCommand::ReadWords {
+    ) -> Result<u32, DebugError> {
+        let command = SWDCommand::ReadWords {
             start_address: address,
             length,
-        });
-        let port = self.get_port()?;
-        port.write_all(&command.write_frame())?;
-        port.flush()?;
-        // Wait for response
-        std::thread::sleep(Duration::from_millis(50));
-        // Read response
-        let mut buffer = vec![0; (length * 4) as usize]; // 4 bytes per word
-        match port.read_exact(&mut buffer) {
-            Ok(_) => {
-                // Convert buffer to u32 value
-                if buffer.len() < 4 {
-                    return Err("Buffer too short to read a word".into());
-                }
-                let value = u32::from_le_bytes(buffer[..4].try_into().unwrap());
-                Ok(value)
+        };
+        match self.transact(command) {
+            Ok(data) => {
+                // Convert payload to u32 value
+                Ok(Self::first_word(&data)?)
             }
             Err(e) => {
                 info!("Error reading words from address 0x{:08X}: {}", address, e);
-                Err(e.into())
+                Err(e)
             }
         }
     }
 
-    /// Write PC register index to DCRSR and read PC value from DCRDR
-    pub fn read_pc_register(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
-        // Step 1: Write register index (0x0F) to DCRSR at 0xE000EDF4
-        const DCRSR_ADDR: u32 = 0xe000edf4;
-        const DCRDR_ADDR: u32 = 0x00000100;
-        const PC_REG_INDEX: u32 = 0x04;
+    /// Read the Program Counter of the halted core
+    pub fn read_pc_register(&mut self) -> Result<u32, DebugError> {
+        let pc_value = self.read_register(REG_PC)?;
+        info!("Read PC value: 0x{:08X}", pc_value);
+        Ok(pc_value)
+    }
 
-        info!("Writing PC register index (0x{:02X}) to DCRSR (0x{:08X})", PC_REG_INDEX, DCRSR_ADDR);
-        self.write_word(DCRSR_ADDR, PC_REG_INDEX)?;
+    /// Read any ARM Cortex-M register by index
+    pub fn read_register(&mut self, reg_index: u32) -> Result<u32, DebugError> {
+        let value = self.read_registers(&[reg_index])?[0];
+        info!("Read register index 0x{:02X} value: 0x{:08X}", reg_index, value);
+        Ok(value)
+    }
 
-        // Small delay to ensure the register transfer completes
-        std::thread::sleep(Duration::from_millis(10));
+    /// Write any ARM Cortex-M register by index: the value goes to DCRDR, then
+    /// DCRSR with REGWnR set moves it into the register once S_REGRDY is back
+    pub fn write_register(&mut self, reg_index: u32, value: u32) -> Result<(), DebugError> {
+        self.write_registers(&[(reg_index, value)])?;
+        info!("Wrote register index 0x{:02X} value: 0x{:08X}", reg_index, value);
+        Ok(())
+    }
 
-        // Step 2: Read the PC value from DCRDR at 0xE000EDF8
-        let pc_value = self.read_words(DCRDR_ADDR, 1)?;
-        info!("Read PC value: 0x{:08X} from DCRDR (0x{:08X})", pc_value, DCRDR_ADDR);
-        Ok(pc_value)
+    /// Write several `(index, value)` core registers in as few bridge round-trips as possible
+    pub fn write_registers(&mut self, registers: &[(u32, u32)]) -> Result<(), DebugError> {
+        let mut ops = Vec::with_capacity(1 + registers.len() * 3);
+        ops.push(Self::halted_check());
+        for &(index, value) in registers {
+            ops.extend([
+                BatchOp::WriteWord { address: DCRDR_ADDR, value },
+                BatchOp::WriteWord { address: DCRSR_ADDR, value: index | DCRSR_REGWNR },
+                BatchOp::PollBitSet { address: DHCSR_ADDR, mask: S_REGRDY, attempts: REGRDY_POLL_ATTEMPTS },
+            ]);
+        }
+        self.execute_batch_checked(&ops)?;
+        Ok(())
     }
 
-    /// Read any ARM Cortex-M register by index
-    pub fn read_register(&mut self, reg_index: u32) -> Result<u32, Box<dyn std::error::Error>> {
-        // Debug Registers
-        const DCRSR_ADDR: u32 = 0xe000edf4;
-        const DCRDR_ADDR: u32 = 0xe000edf8;
+    /// Execute a single instruction on the halted core and return the new PC
+    /// with the halt reason (`Step`, or the breakpoint/watchpoint it ran into).
+    /// With `mask_interrupts` C_MASKINTS is held during the step so a pending
+    /// interrupt does not run instead; it is cleared again afterwards.
+    pub fn step(&mut self, mask_interrupts: bool) -> Result<HaltEvent, DebugError> {
+        let mask = if mask_interrupts { C_MASKINTS } else { 0 };
+        let mut ops = vec![
+            Self::halted_check(),
+            // Drop events left from the previous halt so DFSR only reports this step
+            BatchOp::WriteWord { address: DFSR_ADDR, value: DFSR_ALL },
+            // C_MASKINTS may only change while C_HALT is set
+            BatchOp::WriteWord { address: DHCSR_ADDR, value: DBGKEY | C_DEBUGEN | C_HALT | mask },
+            BatchOp::WriteWord { address: DHCSR_ADDR, value: DBGKEY | C_DEBUGEN | C_STEP | mask },
+            BatchOp::PollBitSet { address: DHCSR_ADDR, mask: S_HALT, attempts: HALT_POLL_ATTEMPTS },
+            BatchOp::WriteWord { address: DHCSR_ADDR, value: DBGKEY | C_DEBUGEN | C_HALT }
+        ];
+        ops.extend(Self::halt_event_ops(true));
+        self.halt_watchpoint = None;
+        let results = self.execute_batch_checked(&ops)?;
+        self.decode_halt_event(&results, true, true)
+    }
 
-        self.write_word(DCRSR_ADDR, reg_index)?;
+    /// Step `count` instructions, stopping early at a breakpoint or watchpoint
+    pub fn step_many(&mut self, count: u32, mask_interrupts: bool) -> Result<StepOutcome, DebugError> {
+        let mut event = HaltEvent { pc: self.read_register(REG_PC)?, reason: HaltReason::Step };
+        let mut steps = 0;
+        while steps < count {
+            event = self.step(mask_interrupts)?;
+            steps += 1;
+            if event.reason != HaltReason::Step {
+                break;
+            }
+        }
+        info!("Stepped {} instructions, PC: 0x{:08X} ({})", steps, event.pc, event.reason);
+        Ok(StepOutcome { pc: event.pc, steps, left_range: false, reason: event.reason })
+    }
 
-        std::thread::sleep(Duration::from_millis(10));
-        let value = self.read_words(DCRDR_ADDR, 1)?;
-        info!("Read register index 0x{:02X} value: 0x{:08X}", reg_index, value);
-        Ok(value)
+    /// Step while the PC stays inside `range`, at most `max_steps` times
+    pub fn step_until_outside(
+        &mut self,
+        range: Range<u32>,
+        max_steps: u32,
+        mask_interrupts: bool
+    ) -> Result<StepOutcome, DebugError> {
+        let mut event = HaltEvent { pc: self.read_register(REG_PC)?, reason: HaltReason::Step };
+        let mut steps = 0;
+        while range.contains(&event.pc) && steps < max_steps {
+            event = self.step(mask_interrupts)?;
+            steps += 1;
+            if event.reason != HaltReason::Step {
+                break;
+            }
+        }
+        let left_range = !range.contains(&event.pc);
+        info!("Stepped {} instructions, PC: 0x{:08X} ({})", steps, event.pc, event.reason);
+        Ok(StepOutcome { pc: event.pc, steps, left_range, reason: event.reason })
     }
-    pub fn set_breakpoint(&mut self, _address: u32) -> Result<(), Box<dyn std::error::Error>> {
-        // FPB Registers
-        const FPB_CTRL: u32 = 0xe0002000; // (Control register)
-        const FP_COMP0: u32 = 0xe0002008; // (Comparator 0)
-        const FP_COMP1: u32 = 0xe000200c; // (Comparator 1)
-        const FP_COMP2: u32 = 0xe0002014; // (Comparator 2)
-        const FP_COMP3: u32 = 0xe000201c; // (Comparator 3)
 
-        todo!("Implement set_breakpoint method");
+    /// Trailing batch operations that read the PC and DFSR of a halted core,
+    /// clearing the DFSR event bits (write one to clear) when `clear` is set
+    fn halt_event_ops(clear: bool) -> Vec<BatchOp> {
+        let mut ops = vec![
+            BatchOp::WriteWord { address: DCRSR_ADDR, value: REG_PC },
+            BatchOp::PollBitSet { address: DHCSR_ADDR, mask: S_REGRDY, attempts: REGRDY_POLL_ATTEMPTS },
+            BatchOp::ReadWord { address: DCRDR_ADDR },
+            BatchOp::ReadWord { address: DFSR_ADDR }
+        ];
+        if clear {
+            ops.push(BatchOp::WriteWord { address: DFSR_ADDR, value: DFSR_ALL });
+        }
+        ops
     }
+
+    /// Decode the results of a batch ending in `halt_event_ops`; `clear` as passed to it
+    fn decode_halt_event(
+        &mut self,
+        results: &[BatchResult],
+        stepped: bool,
+        clear: bool
+    ) -> Result<HaltEvent, DebugError> {
+        // PC and DFSR are the last two values read
+        let mut values = results.iter().rev().filter_map(|result| {
+            match result {
+                BatchResult::Value(value) => Some(*value),
+                _ => None,
+            }
+        });
+        match (values.next(), values.next()) {
+            (Some(dfsr), Some(pc)) => {
+                let reason = self.halt_reason(dfsr, pc, stepped)?;
+                self.halt_watchpoint = match reason {
+                    HaltReason::Watchpoint { watchpoint } if !clear => watchpoint,
+                    _ => None,
+                };
+                Ok(HaltEvent { pc, reason })
+            }
+            _ => Err(DebugError::Frame(FrameError::PayloadLength { expected: 8, received: 0 })),
+        }
+    }
+
+    /// Classify a DFSR value. BKPT is set both by BPU matches and by BKPT
+    /// instructions, so a breakpoint on the PC tells the two apart.
+    fn halt_reason(&mut self, dfsr: u32, pc: u32, stepped: bool) -> Result<HaltReason, DebugError> {
+        let reason = if (dfsr & DFSR_DWTTRAP) != 0 {
+            let watchpoint = match self.halt_watchpoint {
+                Some(watchpoint) => Some(watchpoint),
+                None => self.triggered_watchpoint()?,
+            };
+            HaltReason::Watchpoint { watchpoint }
+        } else if (dfsr & DFSR_BKPT) != 0 {
+            match self.breakpoints()?.into_iter().find(|breakpoint| breakpoint.address == (pc & !1)) {
+                Some(breakpoint) => HaltReason::Breakpoint { breakpoint },
+                None => HaltReason::Bkpt,
+            }
+        } else if (dfsr & DFSR_VCATCH) != 0 {
+            // Reset is caught in Thread mode, HardFault on entry to its handler
+            let xpsr = self.read_register(REG_XPSR)?;
+            if cortex_m::exception_number(xpsr) == HARDFAULT_EXCEPTION {
+                HaltReason::VectorCatch {
+                    vector: CaughtVector::HardFault,
+                    frame: self.exception_frame()?,
+                }
+            } else {
+                HaltReason::VectorCatch { vector: CaughtVector::Reset, frame: None }
+            }
+        } else if (dfsr & DFSR_EXTERNAL) != 0 {
+            HaltReason::External
+        } else if (dfsr & DFSR_HALTED) != 0 {
+            if stepped { HaltReason::Step } else { HaltReason::Request }
+        } else {
+            HaltReason::Unknown
+        };
+        Ok(reason)
+    }
+
+    /// Read several core registers, queueing the DCRSR/DHCSR/DCRDR sequence
+    /// for all of them into as few bridge round-trips as possible
+    pub fn read_registers(&mut self, indices: &[u32]) -> Result<Vec<u32>, DebugError> {
+        let mut ops = Vec::with_capacity(1 + indices.len() * 3);
+        ops.push(Self::halted_check());
+        for &index in indices {
+            ops.push(BatchOp::WriteWord { address: DCRSR_ADDR, value: index });
+            ops.push(BatchOp::PollBitSet {
+                address: DHCSR_ADDR,
+                mask: S_REGRDY,
+                attempts: REGRDY_POLL_ATTEMPTS,
+            });
+            ops.push(BatchOp::ReadWord { address: DCRDR_ADDR });
+        }
+        let results = self.execute_repeatable_batch(&ops)?;
+        // After the halted check, every third result is a DCRDR read
+        let values = results
+            .iter()
+            .skip(3)
+            .step_by(3)
+            .filter_map(|result| {
+                match result {
+                    BatchResult::Value(value) => Some(*value),
+                    _ => None,
+                }
+            })
+            .collect();
+        Ok(values)
+    }
+
+    /// First operation of every register transfer: DCRSR/DCRDR are only
+    /// meaningful while the core is halted
+    fn halted_check() -> BatchOp {
+        BatchOp::PollBitSet { address: DHCSR_ADDR, mask: S_HALT, attempts: 1 }
+    }
+
+    /// `execute_batch` that turns a failed or missing operation into an error.
+    /// A failed `halted_check` is reported as `NotHalted`.
+    pub fn execute_batch_checked(&mut self, ops: &[BatchOp]) -> Result<Vec<BatchResult>, DebugError> {
+        let results = self.execute_batch(ops, false)?;
+        Self::check_batch(ops, results)
+    }
+
+    /// `execute_batch_checked` for operations whose writes can be repeated, such
+    /// as setting C_HALT or selecting a register in DCRSR: the batch is retried
+    /// under the retry policy like a read
+    fn execute_repeatable_batch(&mut self, ops: &[BatchOp]) -> Result<Vec<BatchResult>, DebugError> {
+        let results = self.execute_batch(ops, true)?;
+        Self::check_batch(ops, results)
+    }
+
+    fn check_batch(ops: &[BatchOp], results: Vec<BatchResult>) -> Result<Vec<BatchResult>, DebugError> {
+        if let Some(&BatchResult::Failed { status }) = results.last() {
+            let op = &ops[results.len() - 1];
+            if status == BatchResult::STATUS_POLL_TIMEOUT && *op == Self::halted_check() {
+                return Err(DebugError::NotHalted);
+            }
+            return Err(Self::batch_failure(op, status));
+        }
+        if results.len() != ops.len() {
+            return Err(
+                DebugError::Frame(FrameError::PayloadLength { expected: ops.len(), received: results.len() })
+            );
+        }
+        Ok(results)
+    }
+
+    /// Run a list of memory operations, packed into BATCH frames when the bridge
+    /// supports them and one command at a time otherwise. Execution stops at the
+    /// first failed operation, which is the last entry of the returned list.
+    /// `repeatable` batches are retried like reads (see `SWDCommand::Batch`).
+    pub fn execute_batch(&mut self, ops: &[BatchOp], repeatable: bool) -> Result<Vec<BatchResult>, DebugError> {
+        if !self.bridge.supports(ProtocolHandler::BATCH_COMMAND) {
+            return self.execute_sequentially(ops);
+        }
+        let limit = self.bridge.max_payload.min(ProtocolHandler::MAX_DATA_LENGTH);
+        let mut results = Vec::with_capacity(ops.len());
+        let mut start = 0;
+        while start < ops.len() {
+            // Grow the chunk while both the request and the reply fit in one frame
+            let mut end = start;
+            let (mut request_len, mut result_len) = (0, 0);
+            while end < ops.len() {
+                let op = &ops[end];
+                if request_len + op.request_len() > limit || result_len + op.result_len() > limit {
+                    break;
+                }
+                request_len += op.request_len();
+                result_len += op.result_len();
+                end += 1;
+            }
+            if end == start {
+                return Err(
+                    DebugError::InvalidArgument(format!("Batch operation does not fit in a {} byte frame", limit))
+                );
+            }
+            let chunk = &ops[start..end];
+            let payload = self.transact(SWDCommand::Batch { ops: chunk.to_vec(), repeatable })?;
+            let chunk_results = BatchResult::decode_all(chunk, &payload)?;
+            let failed = chunk_results.len() < chunk.len() ||
+                matches!(chunk_results.last(), Some(BatchResult::Failed { .. }));
+            results.extend(chunk_results);
+            if failed {
+                break;
+            }
+            start = end;
+        }
+        Ok(results)
+    }
+
+    /// Error for a batch operation the bridge reported as failed
+    pub fn batch_failure(op: &BatchOp, status: u8) -> DebugError {
+        match *op {
+            BatchOp::PollBitSet { address, mask, .. } if status == BatchResult::STATUS_POLL_TIMEOUT => {
+                DebugError::PollTimeout { address, mask }
+            }
+            BatchOp::WriteWord { address, .. } |
+            BatchOp::ReadWord { address } |
+            BatchOp::PollBitSet { address, .. } => DebugError::TargetFault { address },
+        }
+    }
+
+    /// `execute_batch` for bridges without the BATCH command
+    fn execute_sequentially(&mut self, ops: &[BatchOp]) -> Result<Vec<BatchResult>, DebugError> {
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let result = match *op {
+                BatchOp::WriteWord { address, value } => {
+                    self.write_word(address, value)?;
+                    BatchResult::Written
+                }
+                BatchOp::ReadWord { address } => BatchResult::Value(self.read_word(address)?),
+                BatchOp::PollBitSet { address, mask, attempts } => {
+                    let mut outcome = BatchResult::Failed { status: BatchResult::STATUS_POLL_TIMEOUT };
+                    for _ in 0..attempts.max(1) {
+                        let value = self.read_word(address)?;
+                        if value & mask == mask {
+                            outcome = BatchResult::Value(value);
+                            break;
+                        }
+                    }
+                    outcome
+                }
+            };
+            let failed = matches!(result, BatchResult::Failed { .. });
+            results.push(result);
+            if failed {
+                break;
+            }
+        }
+        Ok(results)
+    }
+    /// Set C_DEBUGEN if it is not already, without touching C_HALT of a running core.
+    /// Breakpoints and watchpoints only halt the core while debug is enabled.
+    fn enable_debug(&mut self) -> Result<(), DebugError> {
+        let status = self.core_status()?;
+        if !status.debug_enabled {
+            self.write_word(DHCSR_ADDR, DBGKEY | C_DEBUGEN)?;
+        }
+        Ok(())
+    }
+
+    /// Number of instruction comparators in the BPU
+    pub fn breakpoint_comparators(&mut self) -> Result<usize, DebugError> {
+        let bp_ctrl = self.read_word(BP_CTRL_ADDR)?;
+        Ok(cortex_m::bp_comparator_count(bp_ctrl))
+    }
+
+    /// Current value of every BPU comparator
+    fn read_bp_comparators(&mut self) -> Result<Vec<u32>, DebugError> {
+        let count = self.breakpoint_comparators()?;
+        let ops: Vec<BatchOp> = (0..count)
+            .map(|index| BatchOp::ReadWord { address: cortex_m::bp_comp_addr(index) })
+            .collect();
+        let results = self.execute_batch_checked(&ops)?;
+        Ok(
+            results
+                .iter()
+                .map(|result| {
+                    match result {
+                        BatchResult::Value(value) => *value,
+                        _ => 0,
+                    }
+                })
+                .collect()
+        )
+    }
+
+    /// Active hardware breakpoints, read back from the BPU
+    pub fn breakpoints(&mut self) -> Result<Vec<Breakpoint>, DebugError> {
+        let comparators = self.read_bp_comparators()?;
+        Ok(
+            comparators
+                .iter()
+                .enumerate()
+                .flat_map(|(comparator, value)| {
+                    cortex_m::bp_comp_addresses(*value)
+                        .into_iter()
+                        .map(move |address| Breakpoint { comparator, address })
+                })
+                .collect()
+        )
+    }
+
+    /// Break on the instruction at `address` (Code region only). Two breakpoints in
+    /// the same word share a comparator using the upper/lower halfword match.
+    pub fn set_breakpoint(&mut self, address: u32) -> Result<Breakpoint, DebugError> {
+        let address = address & !1; // Thumb bit
+        if address > BP_MAX_ADDRESS {
+            return Err(
+                DebugError::InvalidArgument(
+                    format!("Breakpoint address 0x{:08X} is outside the Code region", address)
+                )
+            );
+        }
+        let comparators = self.read_bp_comparators()?;
+        if let Some(comparator) = comparators
+            .iter()
+            .position(|value| cortex_m::bp_comp_addresses(*value).contains(&address))
+        {
+            return Ok(Breakpoint { comparator, address });
+        }
+        let comparator = comparators
+            .iter()
+            .position(|value| cortex_m::bp_comp_same_word(*value, address))
+            .or_else(|| comparators.iter().position(|value| (value & BP_COMP_ENABLE) == 0))
+            .ok_or(DebugError::NoFreeComparator { unit: "breakpoint", available: comparators.len() })?;
+        self.enable_debug()?;
+        self.execute_batch_checked(
+            &[
+                BatchOp::WriteWord { address: BP_CTRL_ADDR, value: BP_CTRL_KEY | BP_CTRL_ENABLE },
+                BatchOp::WriteWord {
+                    address: cortex_m::bp_comp_addr(comparator),
+                    value: cortex_m::bp_comp_encode(address, comparators[comparator]),
+                },
+            ]
+        )?;
+        info!("Breakpoint at 0x{:08X} in comparator {}", address, comparator);
+        Ok(Breakpoint { comparator, address })
+    }
+
+    /// Remove the breakpoint at `address`
+    pub fn clear_breakpoint(&mut self, address: u32) -> Result<(), DebugError> {
+        let address = address & !1;
+        let comparators = self.read_bp_comparators()?;
+        let comparator = comparators
+            .iter()
+            .position(|value| cortex_m::bp_comp_addresses(*value).contains(&address))
+            .ok_or_else(|| {
+                DebugError::InvalidArgument(format!("No breakpoint at 0x{:08X}", address))
+            })?;
+        self.write_word(
+            cortex_m::bp_comp_addr(comparator),
+            cortex_m::bp_comp_remove(address, comparators[comparator])
+        )?;
+        info!("Cleared breakpoint at 0x{:08X}", address);
+        Ok(())
+    }
+
+    /// Remove every breakpoint
+    pub fn clear_all_breakpoints(&mut self) -> Result<(), DebugError> {
+        let count = self.breakpoint_comparators()?;
+        let ops: Vec<BatchOp> = (0..count)
+            .map(|index| BatchOp::WriteWord { address: cortex_m::bp_comp_addr(index), value: 0 })
+            .collect();
+        self.execute_batch_checked(&ops)?;
+        Ok(())
+    }
+
+    /// Number of comparators in the DWT
+    pub fn watchpoint_comparators(&mut self) -> Result<usize, DebugError> {
+        let dwt_ctrl = self.read_word(DWT_CTRL_ADDR)?;
+        Ok(cortex_m::dwt_comparator_count(dwt_ctrl))
+    }
+
+    /// (COMP, MASK, FUNCTION) of every DWT comparator. Reading FUNCTION clears
+    /// its MATCHED flag, so check `triggered_watchpoint` first after a halt.
+    fn read_dwt_comparators(&mut self) -> Result<Vec<(u32, u32, u32)>, DebugError> {
+        let count = self.watchpoint_comparators()?;
+        let ops: Vec<BatchOp> = (0..count)
+            .flat_map(|index| {
+                [
+                    BatchOp::ReadWord { address: cortex_m::dwt_comp_addr(index) },
+                    BatchOp::ReadWord { address: cortex_m::dwt_mask_addr(index) },
+                    BatchOp::ReadWord { address: cortex_m::dwt_function_addr(index) },
+                ]
+            })
+            .collect();
+        let values: Vec<u32> = self
+            .execute_batch_checked(&ops)?
+            .iter()
+            .map(|result| {
+                match result {
+                    BatchResult::Value(value) => *value,
+                    _ => 0,
+                }
+            })
+            .collect();
+        Ok(
+            values
+                .chunks(3)
+                .map(|c| (c[0], c[1], c[2]))
+                .collect()
+        )
+    }
+
+    fn decode_watchpoint(comparator: usize, (comp, mask, function): (u32, u32, u32)) -> Option<Watchpoint> {
+        WatchKind::from_function(function).map(|kind| Watchpoint {
+            comparator,
+            address: comp,
+            size: 1 << (mask & 0x1f),
+            kind,
+        })
+    }
+
+    /// Active data watchpoints, read back from the DWT
+    pub fn watchpoints(&mut self) -> Result<Vec<Watchpoint>, DebugError> {
+        let comparators = self.read_dwt_comparators()?;
+        Ok(
+            comparators
+                .into_iter()
+                .enumerate()
+                .filter_map(|(comparator, registers)| Self::decode_watchpoint(comparator, registers))
+                .collect()
+        )
+    }
+
+    /// Halt when the core makes a `kind` access to the `size` bytes at `address`.
+    /// `size` must be a power of two and `address` aligned to it.
+    pub fn set_watchpoint(&mut self, address: u32, size: u32, kind: WatchKind) -> Result<Watchpoint, DebugError> {
+        if !size.is_power_of_two() || size > MAX_WATCH_SIZE {
+            return Err(
+                DebugError::InvalidArgument(
+                    format!("Watchpoint size {} must be a power of two up to {}", size, MAX_WATCH_SIZE)
+                )
+            );
+        }
+        if !address.is_multiple_of(size) {
+            return Err(
+                DebugError::InvalidArgument(
+                    format!("Watchpoint address 0x{:08X} is not aligned to its size {}", address, size)
+                )
+            );
+        }
+        let comparators = self.read_dwt_comparators()?;
+        if let Some(existing) = comparators
+            .iter()
+            .enumerate()
+            .filter_map(|(index, registers)| Self::decode_watchpoint(index, *registers))
+            .find(|w| w.address == address && w.size == size && w.kind == kind)
+        {
+            return Ok(existing);
+        }
+        let comparator = comparators
+            .iter()
+            .position(|(_, _, function)| (function & DWT_FUNCTION_MASK) == 0)
+            .ok_or(DebugError::NoFreeComparator { unit: "watchpoint", available: comparators.len() })?;
+        self.enable_debug()?;
+        let demcr = self.read_word(DEMCR_ADDR)?;
+        self.execute_batch_checked(
+            &[
+                BatchOp::WriteWord { address: DEMCR_ADDR, value: demcr | DEMCR_DWTENA },
+                BatchOp::WriteWord { address: cortex_m::dwt_comp_addr(comparator), value: address },
+                BatchOp::WriteWord { address: cortex_m::dwt_mask_addr(comparator), value: size.trailing_zeros() },
+                BatchOp::WriteWord { address: cortex_m::dwt_function_addr(comparator), value: kind.function() },
+            ]
+        )?;
+        info!("Watchpoint ({}) at 0x{:08X}+{} in comparator {}", kind, address, size, comparator);
+        Ok(Watchpoint { comparator, address, size, kind })
+    }
+
+    /// Remove the watchpoint at `address`
+    pub fn clear_watchpoint(&mut self, address: u32) -> Result<(), DebugError> {
+        let watchpoint = self
+            .watchpoints()?
+            .into_iter()
+            .find(|watchpoint| watchpoint.address == address)
+            .ok_or_else(|| DebugError::InvalidArgument(format!("No watchpoint at 0x{:08X}", address)))?;
+        self.write_word(cortex_m::dwt_function_addr(watchpoint.comparator), 0)?;
+        info!("Cleared watchpoint at 0x{:08X}", address);
+        Ok(())
+    }
+
+    /// Remove every watchpoint
+    pub fn clear_all_watchpoints(&mut self) -> Result<(), DebugError> {
+        let count = self.watchpoint_comparators()?;
+        let ops: Vec<BatchOp> = (0..count)
+            .map(|index| BatchOp::WriteWord { address: cortex_m::dwt_function_addr(index), value: 0 })
+            .collect();
+        self.execute_batch_checked(&ops)?;
+        Ok(())
+    }
+
+    /// The watchpoint whose MATCHED flag is set, i.e. the one that halted the core
+    pub fn triggered_watchpoint(&mut self) -> Result<Option<Watchpoint>, DebugError> {
+        let comparators = self.read_dwt_comparators()?;
+        Ok(
+            comparators
+                .into_iter()
+                .enumerate()
+                .filter(|(_, (_, _, function))| (function & DWT_FUNCTION_MATCHED) != 0)
+                .find_map(|(comparator, registers)| Self::decode_watchpoint(comparator, registers))
+        )
+    }
+
+    /// Erase the 1 KB main flash sector containing `address`. The core must be halted.
+    pub fn flash_erase_sector(&mut self, address: u32) -> Result<(), DebugError> {
+        Self::check_flash_range(address, 1)?;
+        self.ensure_halted()?;
+        let sector = address & !(FLASH_SECTOR_SIZE - 1);
+        let (protect_address, protect_value) = mspm0::flash_unprotect(sector);
+        self.flash_erase(
+            &[BatchOp::WriteWord { address: protect_address, value: protect_value }],
+            FLASHCTL_CMDTYPE_SIZE_SECTOR,
+            sector
+        )?;
+        info!("Erased flash sector {} at 0x{:08X}", mspm0::flash_sector(sector), sector);
+        Ok(())
+    }
+
+    /// Erase all of main flash. NONMAIN (the boot configuration) is left alone.
+    /// The core must be halted.
+    #[cfg(feature = "cli")]
+    pub fn flash_mass_erase(&mut self) -> Result<(), DebugError> {
+        self.ensure_halted()?;
+        self.flash_erase(
+            &[
+                BatchOp::WriteWord { address: FLASHCTL_CMDWEPROTA_ADDR, value: 0 },
+                BatchOp::WriteWord { address: FLASHCTL_CMDWEPROTB_ADDR, value: 0 },
+            ],
+            FLASHCTL_CMDTYPE_SIZE_BANK,
+            FLASH_BASE
+        )?;
+        info!("Erased main flash (0x{:08X}-0x{:08X})", FLASH_BASE, FLASH_BASE + FLASH_SIZE - 1);
+        Ok(())
+    }
+
+    /// Program `data` into main flash at `address`, one 64-bit flash word with
+    /// controller-generated ECC per FLASHCTL command. The range must be erased, as
+    /// an ECC flash word can be programmed only once per erase. Partial words are
+    /// padded with 0xFF and words that are all 0xFF are skipped (left erased).
+    /// `progress` is called after every batch of words. The core must be halted.
+    pub fn flash_program<F>(&mut self, address: u32, data: &[u8], mut progress: F) -> Result<(), DebugError>
+        where F: FnMut(FlashProgress)
+    {
+        Self::check_flash_range(address, data.len())?;
+        self.ensure_halted()?;
+        let start = address & !(FLASH_WORD_SIZE - 1);
+        let mut image = vec![0xff; (address - start) as usize];
+        image.extend_from_slice(data);
+        image.resize(image.len().next_multiple_of(FLASH_WORD_SIZE as usize), 0xff);
+        let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let words: Vec<(u32, u32, u32)> = image
+            .chunks(FLASH_WORD_SIZE as usize)
+            .enumerate()
+            .map(|(index, bytes)| (start + (index as u32) * FLASH_WORD_SIZE, word(&bytes[..4]), word(&bytes[4..])))
+            .filter(|&(_, low, high)| low != u32::MAX || high != u32::MAX)
+            .collect();
+
+        let total = data.len();
+        for chunk in words.chunks(FLASH_WORDS_PER_BATCH) {
+            let ops: Vec<BatchOp> = chunk
+                .iter()
+                .flat_map(|&(word_address, low, high)| Self::flash_program_ops(word_address, low, high))
+                .collect();
+            let result = self.execute_batch_checked(&ops);
+            self.flash_result(result)?;
+            let (last, _, _) = chunk[chunk.len() - 1];
+            let done = ((last + FLASH_WORD_SIZE).saturating_sub(address) as usize).min(total);
+            progress(FlashProgress { address: last, done, total });
+        }
+        if words.is_empty() {
+            progress(FlashProgress { address, done: total, total });
+        }
+        info!("Programmed {} bytes ({} flash words) at 0x{:08X}", total, words.len(), address);
+        Ok(())
+    }
+
+    /// Write `image` to main flash, one sector at a time. Each sector is read back
+    /// first and bytes the image does not cover keep their contents. Sectors that
+    /// already match are skipped; changed words that are still erased are programmed
+    /// directly, anything else erases and reprograms the sector. Data for NONMAIN is
+    /// skipped. `progress` is called after every sector. The core must be halted.
+    pub fn flash_image<F>(&mut self, image: &FirmwareImage, mut progress: F) -> Result<FlashSummary, DebugError>
+        where F: FnMut(FlashProgress)
+    {
+        let sectors = self.image_sectors(image)?;
+        let total = sectors.len() * (FLASH_SECTOR_SIZE as usize);
+        let mut summary = FlashSummary { sectors: sectors.len(), ..FlashSummary::default() };
+        for (index, (sector, wanted)) in sectors.iter().enumerate() {
+            match self.plan_sector(*sector, wanted)? {
+                SectorPlan::Unchanged => {
+                    summary.unchanged += 1;
+                }
+                SectorPlan::Program(runs) => {
+                    for (address, data) in &runs {
+                        self.flash_program(*address, data, |_| {})?;
+                        summary.words += Self::flash_words(data);
+                    }
+                    summary.programmed += 1;
+                }
+                SectorPlan::Erase(data) => {
+                    self.flash_erase_sector(*sector)?;
+                    self.flash_program(*sector, &data, |_| {})?;
+                    summary.erased += 1;
+                    summary.words += Self::flash_words(&data);
+                }
+            }
+            progress(FlashProgress { address: *sector, done: (index + 1) * (FLASH_SECTOR_SIZE as usize), total });
+        }
+        info!("Flashed {}", summary);
+        Ok(summary)
+    }
+
+    /// `flash_image` through a flash algorithm run from SRAM instead of FLASHCTL
+    /// commands sent over SWD. All sectors are read back and compared first, then
+    /// the algorithm erases the sectors that need it and programs the changed
+    /// data a page at a time. Pages are double-buffered: the next page is written
+    /// to one SRAM buffer while the core programs the other. `progress` is called
+    /// after every page. The core must be halted; SRAM is overwritten.
+    pub fn flash_image_with_algorithm<F>(
+        &mut self,
+        image: &FirmwareImage,
+        algorithm: &FlashAlgorithm,
+        mut progress: F
+    ) -> Result<FlashSummary, DebugError>
+        where F: FnMut(FlashProgress)
+    {
+        if algorithm.sector_size != FLASH_SECTOR_SIZE || algorithm.erased_value != 0xff {
+            return Err(
+                DebugError::InvalidArgument(
+                    format!(
+                        "{} erases {} byte sectors to 0x{:02X}; MSPM0 flash has {} byte sectors erased to 0xFF",
+                        algorithm.name,
+                        algorithm.sector_size,
+                        algorithm.erased_value,
+                        FLASH_SECTOR_SIZE
+                    )
+                )
+            );
+        }
+        let sectors = self.image_sectors(image)?;
+        if let Some((sector, _)) = sectors.iter().find(|(sector, _)| !algorithm.covers(**sector, FLASH_SECTOR_SIZE)) {
+            return Err(
+                DebugError::InvalidArgument(format!("{} does not cover the sector at 0x{:08X}", algorithm.name, sector))
+            );
+        }
+        let mut summary = FlashSummary { sectors: sectors.len(), ..FlashSummary::default() };
+        let mut erase = Vec::new();
+        let mut runs = Vec::new();
+        for (sector, wanted) in &sectors {
+            match self.plan_sector(*sector, wanted)? {
+                SectorPlan::Unchanged => {
+                    summary.unchanged += 1;
+                }
+                SectorPlan::Program(sector_runs) => {
+                    summary.programmed += 1;
+                    runs.extend(sector_runs);
+                }
+                SectorPlan::Erase(data) => {
+                    summary.erased += 1;
+                    erase.push(*sector);
+                    runs.push((*sector, data));
+                }
+            }
+        }
+        summary.words = runs
+            .iter()
+            .map(|(_, data)| Self::flash_words(data))
+            .sum();
+        if runs.is_empty() {
+            info!("Flashed {}", summary);
+            return Ok(summary);
+        }
+
+        // ProgramPage takes at most one page, which must not straddle a page boundary
+        let page_size = algorithm.page_size;
+        let mut pages: Vec<(u32, &[u8])> = Vec::new();
+        for (address, data) in &runs {
+            let mut offset = 0;
+            while offset < data.len() {
+                let page_address = address + (offset as u32);
+                let length = ((page_size - (page_address % page_size)) as usize).min(data.len() - offset);
+                pages.push((page_address, &data[offset..offset + length]));
+                offset += length;
+            }
+        }
+
+        let layout = algorithm.layout()?;
+        self.ensure_halted()?;
+        self.write_words(layout.load_address, &algorithm.image())?;
+        info!("Loaded flash algorithm {} at 0x{:08X}", algorithm, layout.load_address);
+        if !erase.is_empty() {
+            self.call_algorithm(algorithm, &layout, "Init", algorithm.init, &[algorithm.flash_base, 0, FLM_ERASE])?;
+            for sector in &erase {
+                self.call_algorithm(algorithm, &layout, "EraseSector", Some(algorithm.erase_sector), &[*sector])?;
+            }
+            self.call_algorithm(algorithm, &layout, "UnInit", algorithm.uninit, &[FLM_ERASE])?;
+        }
+        self.call_algorithm(algorithm, &layout, "Init", algorithm.init, &[algorithm.flash_base, 0, FLM_PROGRAM])?;
+        self.program_pages(algorithm, &layout, &pages, &mut progress)?;
+        self.call_algorithm(algorithm, &layout, "UnInit", algorithm.uninit, &[FLM_PROGRAM])?;
+        info!("Flashed {}", summary);
+        Ok(summary)
+    }
+
+    /// Program `pages` with the algorithm's ProgramPage through the two SRAM
+    /// buffers of `layout`, filling one while the core programs the other
+    fn program_pages<F>(
+        &mut self,
+        algorithm: &FlashAlgorithm,
+        layout: &AlgorithmLayout,
+        pages: &[(u32, &[u8])],
+        progress: &mut F
+    ) -> Result<(), DebugError>
+        where F: FnMut(FlashProgress)
+    {
+        let total = pages
+            .iter()
+            .map(|(_, data)| data.len())
+            .sum();
+        let entry = layout.code_address + algorithm.program_page;
+        let mut done = 0;
+        if let Some((_, data)) = pages.first() {
+            self.write_words(layout.buffers[0], &Self::padded_words(data))?;
+        }
+        for (index, (address, data)) in pages.iter().enumerate() {
+            self.start_routine(entry, &[*address, data.len() as u32, layout.buffers[index % 2]], &layout.frame)?;
+            // The core can be running while the bridge writes SRAM
+            let filled = match pages.get(index + 1) {
+                Some((_, next)) => self.write_words(layout.buffers[(index + 1) % 2], &Self::padded_words(next)),
+                None => Ok(()),
+            };
+            let result = self.finish_routine(&layout.frame, algorithm.program_timeout)?;
+            filled?;
+            Self::algorithm_result("ProgramPage", *address, entry, result)?;
+            done += data.len();
+            progress(FlashProgress { address: *address, done, total });
+        }
+        Ok(())
+    }
+
+    /// Run one algorithm function to completion; an optional function that is
+    /// missing (`offset` of `None`) is skipped
+    fn call_algorithm(
+        &mut self,
+        algorithm: &FlashAlgorithm,
+        layout: &AlgorithmLayout,
+        name: &str,
+        offset: Option<u32>,
+        args: &[u32]
+    ) -> Result<(), DebugError> {
+        let Some(offset) = offset else {
+            return Ok(());
+        };
+        let entry = layout.code_address + offset;
+        let timeout = if name == "EraseSector" { algorithm.erase_timeout } else { ROUTINE_TIMEOUT };
+        let result = self.run_routine(entry, args, &layout.frame, timeout)?;
+        Self::algorithm_result(name, args.first().copied().unwrap_or(0), entry, result)
+    }
+
+    /// Flash algorithm functions return 0 on success
+    fn algorithm_result(name: &str, argument: u32, entry: u32, result: u32) -> Result<(), DebugError> {
+        if result != 0 {
+            return Err(DebugError::RoutineFailed {
+                pc: entry,
+                reason: format!("{}(0x{:08X}) returned 0x{:08X}", name, argument, result),
+            });
+        }
+        Ok(())
+    }
+
+    /// Range-check the image and group it into the main flash sectors it touches
+    fn image_sectors(&mut self, image: &FirmwareImage) -> Result<BTreeMap<u32, Vec<Option<u8>>>, DebugError> {
+        for segment in &image.segments {
+            if mspm0::in_nonmain(segment.address) {
+                info!("Skipping {} bytes for NONMAIN at 0x{:08X}", segment.data.len(), segment.address);
+                continue;
+            }
+            Self::check_flash_range(segment.address, segment.data.len())?;
+        }
+        self.ensure_halted()?;
+        Ok(
+            image
+                .sectors(FLASH_SECTOR_SIZE)
+                .into_iter()
+                .filter(|(sector, _)| mspm0::in_flash(*sector))
+                .collect()
+        )
+    }
+
+    /// Read a sector back, overlay the bytes of the image and decide how to get
+    /// there: not at all, by programming changed words that are still erased, or
+    /// by erasing and reprogramming the whole sector
+    fn plan_sector(&mut self, sector: u32, wanted: &[Option<u8>]) -> Result<SectorPlan, DebugError> {
+        let current = self.read_bytes(sector, FLASH_SECTOR_SIZE)?;
+        let desired: Vec<u8> = wanted
+            .iter()
+            .zip(&current)
+            .map(|(wanted, current)| wanted.unwrap_or(*current))
+            .collect();
+        let word_size = FLASH_WORD_SIZE as usize;
+        let changed: Vec<usize> = (0..desired.len() / word_size)
+            .filter(|word| desired[word * word_size..(word + 1) * word_size] != current[word * word_size..(word + 1) * word_size])
+            .collect();
+        let erased = |word: &usize| current[word * word_size..(word + 1) * word_size].iter().all(|byte| *byte == 0xff);
+        if changed.is_empty() {
+            return Ok(SectorPlan::Unchanged);
+        }
+        if !changed.iter().all(erased) {
+            return Ok(SectorPlan::Erase(desired));
+        }
+        // Runs of consecutive changed words
+        let mut runs = Vec::new();
+        let mut start = 0;
+        while start < changed.len() {
+            let mut end = start + 1;
+            while end < changed.len() && changed[end] == changed[end - 1] + 1 {
+                end += 1;
+            }
+            let (first, last) = (changed[start], changed[end - 1]);
+            runs.push((sector + ((first * word_size) as u32), desired[first * word_size..(last + 1) * word_size].to_vec()));
+            start = end;
+        }
+        Ok(SectorPlan::Program(runs))
+    }
+
+    /// Flash words in `data` that get programmed, i.e. are not all 0xFF
+    fn flash_words(data: &[u8]) -> usize {
+        data.chunks(FLASH_WORD_SIZE as usize)
+            .filter(|word| word.iter().any(|byte| *byte != 0xff))
+            .count()
+    }
+
+    /// Little-endian words of `data`, padded with 0xFF
+    fn padded_words(data: &[u8]) -> Vec<u32> {
+        data.chunks(4)
+            .map(|bytes| {
+                let mut word = [0xff; 4];
+                word[..bytes.len()].copy_from_slice(bytes);
+                u32::from_le_bytes(word)
+            })
+            .collect()
+    }
+
+    /// Fail unless `address..address + length` lies in main flash
+    fn check_flash_range(address: u32, length: usize) -> Result<(), DebugError> {
+        let end = (address as u64) + (length as u64);
+        if !mspm0::in_flash(address) || end > ((FLASH_BASE + FLASH_SIZE) as u64) {
+            return Err(
+                DebugError::InvalidArgument(
+                    format!("0x{:08X}+{} is outside main flash (0x{:08X}-0x{:08X})", address, length, FLASH_BASE, FLASH_BASE + FLASH_SIZE - 1)
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// FLASHCTL command sequence for one flash word, ending with a STATCMD poll that
+    /// fails (and stops the batch) unless the command passed
+    fn flash_program_ops(address: u32, low: u32, high: u32) -> [BatchOp; 8] {
+        let (protect_address, protect_value) = mspm0::flash_unprotect(address);
+        [
+            BatchOp::WriteWord { address: protect_address, value: protect_value },
+            BatchOp::WriteWord {
+                address: FLASHCTL_CMDTYPE_ADDR,
+                value: FLASHCTL_CMDTYPE_PROGRAM | FLASHCTL_CMDTYPE_SIZE_ONEWORD,
+            },
+            BatchOp::WriteWord { address: FLASHCTL_CMDBYTEN_ADDR, value: FLASHCTL_CMDBYTEN_WORD_ECC },
+            BatchOp::WriteWord { address: FLASHCTL_CMDADDR_ADDR, value: address },
+            BatchOp::WriteWord { address: FLASHCTL_CMDDATA0_ADDR, value: low },
+            BatchOp::WriteWord { address: FLASHCTL_CMDDATA1_ADDR, value: high },
+            BatchOp::WriteWord { address: FLASHCTL_CMDEXEC_ADDR, value: FLASHCTL_CMDEXEC_EXECUTE },
+            BatchOp::PollBitSet {
+                address: FLASHCTL_STATCMD_ADDR,
+                mask: FLASHCTL_STATCMD_DONE | FLASHCTL_STATCMD_PASS,
+                attempts: FLASH_PROGRAM_POLL_ATTEMPTS,
+            },
+        ]
+    }
+
+    /// Start an erase after the `unprotect` writes and poll STATCMD from the host,
+    /// since an erase takes milliseconds
+    fn flash_erase(&mut self, unprotect: &[BatchOp], size: u32, address: u32) -> Result<(), DebugError> {
+        let mut ops = unprotect.to_vec();
+        ops.extend([
+            BatchOp::WriteWord { address: FLASHCTL_CMDTYPE_ADDR, value: FLASHCTL_CMDTYPE_ERASE | size },
+            BatchOp::WriteWord { address: FLASHCTL_CMDADDR_ADDR, value: address },
+            BatchOp::WriteWord { address: FLASHCTL_CMDEXEC_ADDR, value: FLASHCTL_CMDEXEC_EXECUTE },
+        ]);
+        self.execute_batch_checked(&ops)?;
+        let deadline = Instant::now() + FLASH_ERASE_TIMEOUT;
+        loop {
+            let status = self.read_word(FLASHCTL_STATCMD_ADDR)?;
+            if (status & FLASHCTL_STATCMD_DONE) != 0 {
+                if (status & FLASHCTL_STATCMD_PASS) == 0 {
+                    return Err(DebugError::FlashFailed { address, status });
+                }
+                return Ok(());
+            }
+            // An idle controller without DONE never started the command
+            if (status & FLASHCTL_STATCMD_IN_PROGRESS) == 0 || Instant::now() >= deadline {
+                return Err(DebugError::FlashFailed { address, status });
+            }
+            std::thread::sleep(FLASH_POLL_INTERVAL);
+        }
+    }
+
+    /// Turn a failed STATCMD poll into `FlashFailed` for the command the
+    /// controller last ran, with its status bits
+    fn flash_result<T>(&mut self, result: Result<T, DebugError>) -> Result<T, DebugError> {
+        match result {
+            Err(DebugError::PollTimeout { address: FLASHCTL_STATCMD_ADDR, .. }) => {
+                let status = self.read_word(FLASHCTL_STATCMD_ADDR)?;
+                let address = self.read_word(FLASHCTL_CMDADDR_ADDR)?;
+                Err(DebugError::FlashFailed { address, status })
+            }
+            result => result,
+        }
+    }
+
+    /// Write consecutive words starting at `address`, batched
+    fn write_words(&mut self, address: u32, words: &[u32]) -> Result<(), DebugError> {
+        let ops: Vec<BatchOp> = words
+            .iter()
+            .enumerate()
+            .map(|(i, value)| BatchOp::WriteWord { address: address + (i as u32) * 4, value: *value })
+            .collect();
+        self.execute_batch_checked(&ops)?;
+        Ok(())
+    }
+
+    /// Start code already in SRAM at `entry` with `args` in R0-R3, SP, LR and
+    /// R9 set from `frame`, and interrupts masked. The core must be halted; it
+    /// runs until the routine returns to the BKPT at `frame.breakpoint`.
+    pub fn start_routine(&mut self, entry: u32, args: &[u32], frame: &RoutineFrame) -> Result<(), DebugError> {
+        if args.len() > 4 {
+            return Err(DebugError::InvalidArgument(format!("{} routine arguments, at most 4 fit in R0-R3", args.len())));
+        }
+        let mut registers: Vec<(u32, u32)> = args
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (index as u32, *value))
+            .collect();
+        if let Some(static_base) = frame.static_base {
+            registers.push((REG_R9, static_base));
+        }
+        registers.extend([
+            (REG_SP, frame.stack_pointer),
+            (REG_LR, frame.breakpoint | 1),
+            (REG_PC, entry),
+            (REG_XPSR, XPSR_THUMB),
+        ]);
+        self.write_registers(&registers)?;
+        self.halt_watchpoint = None;
+        self.execute_batch_checked(
+            &[
+                BatchOp::WriteWord { address: DFSR_ADDR, value: DFSR_ALL },
+                // C_MASKINTS may only change while C_HALT is set
+                BatchOp::WriteWord { address: DHCSR_ADDR, value: DBGKEY | C_DEBUGEN | C_HALT | C_MASKINTS },
+                BatchOp::WriteWord { address: DHCSR_ADDR, value: DBGKEY | C_DEBUGEN | C_MASKINTS },
+            ]
+        )?;
+        Ok(())
+    }
+
+    /// Wait for a routine started by `start_routine` to stop and return R0. A
+    /// routine that stops anywhere but `frame.breakpoint` or outlives `timeout` is
+    /// halted and reported as `RoutineFailed`.
+    pub fn finish_routine(&mut self, frame: &RoutineFrame, timeout: Duration) -> Result<u32, DebugError> {
+        let halted = self.wait_for_halt(timeout);
+        // Stops a routine that overran and drops C_MASKINTS either way
+        self.write_word(DHCSR_ADDR, DBGKEY | C_DEBUGEN | C_HALT)?;
+        let event = match halted {
+            Ok(event) => event,
+            Err(DebugError::PollTimeout { .. }) => {
+                let pc = self.read_register(REG_PC)?;
+                return Err(DebugError::RoutineFailed {
+                    pc,
+                    reason: format!("still running after {} ms", timeout.as_millis()),
+                });
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        };
+        if event.reason != HaltReason::Bkpt || event.pc != frame.breakpoint {
+            return Err(DebugError::RoutineFailed {
+                pc: event.pc,
+                reason: format!("stopped on {} instead of the BKPT at 0x{:08X}", event.reason, frame.breakpoint),
+            });
+        }
+        self.read_register(0)
+    }
+
+    /// `start_routine` followed by `finish_routine`
+    pub fn run_routine(
+        &mut self,
+        entry: u32,
+        args: &[u32],
+        frame: &RoutineFrame,
+        timeout: Duration
+    ) -> Result<u32, DebugError> {
+        self.start_routine(entry, args, frame)?;
+        self.finish_routine(frame, timeout)
+    }
+
+    /// CRC-32 (ISO-HDLC) of each `(address, length)` range, computed by a routine
+    /// run from SRAM so only the 32-bit results cross the bridge. The core must be
+    /// halted; the SRAM and registers the routine uses are restored afterwards.
+    pub fn crc32_on_target(&mut self, ranges: &[(u32, u32)]) -> Result<Vec<u32>, DebugError> {
+        let code = routines::words(&routines::CRC32);
+        let workspace = ROUTINE_ADDRESS..ROUTINE_ADDRESS + (code.len() as u32) * 4;
+        if
+            let Some(&(address, _)) = ranges
+                .iter()
+                .find(|(address, length)| *address < workspace.end && address.saturating_add(*length) > workspace.start)
+        {
+            return Err(
+                DebugError::InvalidArgument(
+                    format!("Range at 0x{:08X} overlaps the CRC routine at 0x{:08X}", address, workspace.start)
+                )
+            );
+        }
+        self.ensure_halted()?;
+        let saved_memory = self.read_bytes(workspace.start, workspace.end - workspace.start)?;
+        let saved_registers = self.read_registers(&ROUTINE_REGISTERS)?;
+        self.write_words(workspace.start, &code)?;
+        let frame = RoutineFrame {
+            breakpoint: workspace.start + routines::CRC32_BKPT_OFFSET,
+            stack_pointer: mspm0::SRAM_BASE + mspm0::SRAM_SIZE,
+            static_base: None,
+        };
+        let crcs = ranges
+            .iter()
+            .map(|&(address, length)| {
+                let timeout = ROUTINE_TIMEOUT + CRC_TIME_PER_KB * length.div_ceil(1024);
+                let crc = self.run_routine(workspace.start, &[address, length, 0], &frame, timeout)?;
+                info!("Target CRC-32 of 0x{:08X}+{}: 0x{:08X}", address, length, crc);
+                Ok(crc)
+            })
+            .collect::<Result<Vec<u32>, DebugError>>();
+        // Put back what the routine displaced, even when it failed
+        let saved_words: Vec<u32> = saved_memory
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        self.write_words(workspace.start, &saved_words)?;
+        let registers: Vec<(u32, u32)> = ROUTINE_REGISTERS.iter().copied().zip(saved_registers).collect();
+        self.write_registers(&registers)?;
+        crcs
+    }
+
+    /// Check target memory against `image`. The CRC-32 of every segment is
+    /// computed on the target and only segments whose CRC differs are read back
+    /// and compared byte by byte. With `read_back`, or when the CRC routine cannot
+    /// run, every segment is read back. A running core is halted for the CRC and
+    /// resumed afterwards.
+    pub fn verify_image(&mut self, image: &FirmwareImage, read_back: bool) -> Result<VerificationResult, DebugError> {
+        let verifier = ElfFlashVerifier::from_image(image);
+        let target_crcs = if read_back {
+            None
+        } else {
+            let ranges: Vec<(u32, u32)> = verifier.sections
+                .iter()
+                .map(|section| (section.address, section.size))
+                .collect();
+            let running = !self.core_status()?.halted;
+            if running {
+                self.halt()?;
+            }
+            let crcs = self.crc32_on_target(&ranges);
+            if running {
+                self.resume()?;
+            }
+            match crcs {
+                Ok(crcs) => Some(crcs),
+                Err(e) => {
+                    info!("On-target CRC unavailable ({}), reading back the whole image", e);
+                    None
+                }
+            }
+        };
+        // Keep the read error itself for the caller; the report only gets its text
+        let mut read_error = None;
+        let result = verifier
+            .verify_flash(target_crcs.as_deref(), |address, length| {
+                self.read_bytes(address, length).map_err(|e| {
+                    let message = e.to_string();
+                    read_error = Some(e);
+                    message
+                })
+            })
+            .map_err(|e| DebugError::InvalidArgument(e.to_string()))?;
+        if let Some(e) = read_error {
+            return Err(e);
+        }
+        Ok(result)
+    }
+
     fn software_crc(data: &[u8], length: usize) -> [u8; 4] {
         const CRC32_POLYNOMIAL: u32 = 0xedb88320; // IEEE 802.3 CRC-32 polynomial
         let mut crc = 0xffffffff_u32;
@@ -334,7 +1940,7 @@ impl SerialLoader {
         ]
     }
 
-    pub fn check_crc(frame: &[u8]) -> Result<bool, String> {
+    pub fn check_crc(frame: &[u8]) -> Result<bool, DebugError> {
         let data_length = ((frame[3] as u16) << 8) | (frame[2] as u16);
         let data = &frame[4..4 + (data_length as usize)];
         let crc = Self::software_crc(data, data_length as usize);
@@ -354,14 +1960,128 @@ impl SerialLoader {
 
         if check_crc_value != frame_crc_value {
             // self.debug("CRC Check Failed");
-            return Err("CRC Check Failed".to_string());
+            return Err(DebugError::CrcMismatch { expected: check_crc_value, received: frame_crc_value });
         }
 
         Ok(true)
     }
 
-    /// Helper method to get a mutable reference to the port
-    fn get_port(&mut self) -> Result<&mut Box<dyn SerialPort>, Box<dyn std::error::Error>> {
-        self.port.as_mut().ok_or("Serial port is not connected".into())
+    /// Run a command under the retry policy. Idempotent commands are retried on
+    /// timeouts, corrupted frames and NACKs with exponential backoff; the number
+    /// of retries used is available from `last_retries`.
+    fn transact(&mut self, command: SWDCommand) -> Result<Vec<u8>, DebugError> {
+        self.get_transport()?;
+        let max_retries = if command.is_idempotent() { self.retry_policy.max_retries } else { 0 };
+        let mut delay = self.retry_policy.backoff.min(self.retry_policy.max_backoff);
+        let mut retries = 0;
+        loop {
+            match self.transact_once(command.clone()) {
+                Ok(data) => {
+                    if retries > 0 {
+                        info!("Command {:#04x} succeeded after {} retries", command.opcode(), retries);
+                    }
+                    self.last_retries = retries;
+                    return Ok(data);
+                }
+                Err(e) if retries < max_retries && e.is_transient() => {
+                    info!(
+                        "Command {:#04x} failed ({}), retry {}/{} in {:?}",
+                        command.opcode(),
+                        e,
+                        retries + 1,
+                        max_retries,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                    delay = self.retry_policy.next_backoff(delay);
+                    retries += 1;
+                    self.total_retries += 1;
+                }
+                Err(e) => {
+                    self.last_retries = retries;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Send a command frame and decode the bridge's response, returning the payload.
+    /// When the bridge supports tagged frames each request carries a fresh tag and
+    /// replies with any other tag are stale answers to earlier requests and are dropped.
+    fn transact_once(&mut self, command: SWDCommand) -> Result<Vec<u8>, DebugError> {
+        let mut command = ProtocolHandler::new(command);
+        if self.bridge.tagged_frames {
+            self.next_tag = self.next_tag.wrapping_add(1);
+            command = command.with_tag(self.next_tag);
+        }
+        let transport = self.get_transport()?;
+        transport.send_frame(&command.write_frame()?)?;
+        let deadline = Instant::now() + transport.timeout();
+        loop {
+            let frame = transport.receive_frame().inspect_err(|e| {
+                info!("No response to command {:#04x}: {}", command.opcode(), e);
+            })?;
+            let response = match command.read_frame(&frame) {
+                Ok(response) => response,
+                Err(e @ (FrameError::TagMismatch { .. } | FrameError::UnexpectedCommand { .. })) => {
+                    info!("Discarding stale response: {}", e);
+                    if Instant::now() >= deadline {
+                        return Err(DebugError::Timeout);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            };
+            return match response {
+                Response::Ack { .. } => Ok(Vec::new()),
+                Response::Data { data, .. } => Ok(data),
+                Response::Nack { command, code } => Err(DebugError::Nack { opcode: command, code }),
+            };
+        }
+    }
+
+    /// First little-endian word of a read payload
+    fn first_word(data: &[u8]) -> Result<u32, FrameError> {
+        match data.get(..4) {
+            Some(bytes) => Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            None => Err(FrameError::PayloadLength { expected: 4, received: data.len() }),
+        }
+    }
+
+    /// Helper method to get a mutable reference to the transport
+    fn get_transport(&mut self) -> Result<&mut Box<dyn Transport>, DebugError> {
+        self.transport.as_mut().ok_or(DebugError::NotConnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_up_to_its_limit() {
+        let policy = RetryPolicy::default();
+        let delays: Vec<Duration> = std::iter
+            ::successors(Some(policy.backoff), |delay| Some(policy.next_backoff(*delay)))
+            .take(8)
+            .collect();
+        assert_eq!(delays[..3], [Duration::from_millis(20), Duration::from_millis(40), Duration::from_millis(80)]);
+        assert_eq!(delays[7], policy.max_backoff);
+
+        // Many retries with a large factor saturate instead of overflowing
+        let policy = RetryPolicy {
+            max_retries: u32::MAX,
+            backoff: Duration::from_secs(u64::MAX / 2),
+            backoff_multiplier: u32::MAX,
+            max_backoff: Duration::MAX,
+            ..RetryPolicy::default()
+        };
+        let mut delay = policy.backoff;
+        for _ in 0..1000 {
+            delay = policy.next_backoff(delay);
+        }
+        assert_eq!(delay, Duration::MAX);
     }
 }
@@ -3,11 +3,25 @@ mod loader;
 mod protocol;
 mod socketio;
 mod models;
-// mod elf_reader;
+mod transport;
+mod simulator;
+mod error;
+mod cortex_m;
+mod mspm0;
+mod symbols;
+mod fault;
+mod image;
+mod elf_reader;
+mod routines;
+mod flash_algorithm;
+use std::ops::Range;
+use cortex_m::WatchKind;
+use mspm0::ResetKind;
+use image::ImageFormat;
 use std::thread;
 use std::time::Duration;
 use tracing_subscriber::FmtSubscriber;
-use clap::{ Parser, Subcommand };
+use clap::{ builder::BoolishValueParser, Parser, Subcommand };
 use tracing::{ info, error as einfo };
 
 // ARM Cortex-M Register Indices
@@ -29,6 +43,8 @@ pub mod registers {
     pub const LR: u32 = 0x0e; // Link Register (R14)
     pub const PC: u32 = 0x0f; // Program Counter (R15)
     pub const XPSR: u32 = 0x10; // Program Status Register
+    pub const MSP: u32 = 0x11; // Main Stack Pointer
+    pub const PSP: u32 = 0x12; // Process Stack Pointer
 }
 
 #[derive(Parser)]
@@ -36,14 +52,30 @@ pub mod registers {
 #[command(about = "ARM Cortex-M SWD Debugger CLI")]
 #[command(version = "1.0")]
 struct Cli {
-    /// Serial port path (e.g., /dev/tty.usbmodem1234561)
-    #[arg(short, long, default_value = "/dev/tty.usbmodem1234561")]
-    port: String,
+    /// Serial port path (e.g., /dev/tty.usbmodem1234561); auto-detected by USB PID when omitted
+    #[arg(short, long)]
+    port: Option<String>,
 
     /// Baud rate for serial communication
     #[arg(short, long, default_value = "115200")]
     baud: u32,
 
+    /// Reach the bridge over TCP instead of USB serial (e.g., 192.168.1.50:3333)
+    #[arg(long, value_name = "HOST:PORT")]
+    tcp: Option<String>,
+
+    /// Response timeout for each bridge command in milliseconds
+    #[arg(long, default_value = "1000")]
+    timeout_ms: u64,
+
+    /// Retries for idempotent commands (reads, halt) that time out or fail
+    #[arg(long, default_value = "3")]
+    retries: u32,
+
+    /// Talk to the built-in bridge simulator instead of hardware
+    #[arg(long, conflicts_with = "tcp")]
+    simulator: bool,
+
     /// Enable verbose output
     #[arg(short, long)]
     verbose: bool,
@@ -58,6 +90,53 @@ enum Commands {
     Halt,
     /// Resume the target processor
     Resume,
+    /// Show whether the core is running, halted, sleeping or locked up
+    Status,
+    /// Reset the target, optionally halting on the first instruction
+    Reset {
+        /// core (CPURST), system (SYSRESETREQ) or boot (BOOTRST)
+        #[arg(short, long, default_value = "system")]
+        kind: ResetKind,
+        /// Halt at the reset handler through DEMCR.VC_CORERESET
+        #[arg(long)]
+        halt: bool,
+    },
+    /// Show or change which exceptions halt the core (DEMCR vector catch)
+    VectorCatch {
+        /// Halt on entry to the HardFault handler (on/off)
+        #[arg(long, value_parser = BoolishValueParser::new())]
+        hard_fault: Option<bool>,
+        /// Halt on the first instruction after a core reset (on/off)
+        #[arg(long, value_parser = BoolishValueParser::new())]
+        reset: Option<bool>,
+    },
+    /// Explain a HardFault: decode the stacked frame and locate the faulting code
+    FaultReport {
+        /// Firmware ELF used to resolve addresses to functions and source lines
+        #[arg(short, long)]
+        elf: Option<String>,
+    },
+    /// Wait for the running core to halt and show why it stopped
+    Wait {
+        /// Give up after this many milliseconds
+        #[arg(short, long, default_value = "10000")]
+        timeout: u64,
+    },
+    /// Execute instructions one at a time on the halted core
+    Step {
+        /// Number of instructions to step
+        #[arg(short = 'n', long, default_value = "1")]
+        count: u32,
+        /// Keep stepping while PC is inside START..END (hex, e.g., 0x100..0x140)
+        #[arg(long, value_name = "START..END", value_parser = parse_range, conflicts_with = "count")]
+        until_outside: Option<Range<u32>>,
+        /// Upper bound on instructions for --until-outside
+        #[arg(long, default_value = "10000")]
+        max_steps: u32,
+        /// Let pending interrupts run during the step instead of masking them
+        #[arg(long)]
+        allow_interrupts: bool,
+    },
     /// Read from a memory address
     ReadBytes {
         /// Memory address to read from (hex format, e.g., 0x20000000)
@@ -89,15 +168,107 @@ enum Commands {
         #[arg(value_parser = parse_hex)]
         value: u32,
     },
+    /// Erase main flash: the sectors covering a range, or all of it
+    Erase {
+        /// Start of the range to erase (hex format, e.g., 0x00000400)
+        #[arg(value_parser = parse_hex, required_unless_present = "mass")]
+        address: Option<u32>,
+        /// Bytes to erase; every 1 KB sector the range touches is erased
+        #[arg(short, long, default_value = "1", value_parser = parse_hex)]
+        length: u32,
+        /// Erase all of main flash (NONMAIN is kept)
+        #[arg(long, conflicts_with = "address")]
+        mass: bool,
+    },
+    /// Write firmware to flash from an ELF, Intel HEX or raw binary file
+    Flash {
+        /// Firmware file (.elf, .hex or .bin)
+        file: String,
+        /// Load address of a raw binary (hex format, e.g., 0x00000000)
+        #[arg(long, value_parser = parse_hex, default_value = "0x00000000")]
+        base: u32,
+        /// File format when the extension does not tell (elf, hex or bin)
+        #[arg(short, long)]
+        format: Option<ImageFormat>,
+        /// Program through a flash algorithm run from SRAM: `builtin` or a CMSIS-Pack .FLM file
+        #[arg(short, long)]
+        algorithm: Option<String>,
+        /// Reset the target and let it run once flashing is done
+        #[arg(long)]
+        reset: bool,
+    },
+    /// Check that flash holds an ELF, Intel HEX or raw binary image
+    Verify {
+        /// Firmware file (.elf, .hex or .bin)
+        file: String,
+        /// Load address of a raw binary (hex format, e.g., 0x00000000)
+        #[arg(long, value_parser = parse_hex, default_value = "0x00000000")]
+        base: u32,
+        /// File format when the extension does not tell (elf, hex or bin)
+        #[arg(short, long)]
+        format: Option<ImageFormat>,
+        /// Print the verification result as JSON instead of the text report
+        #[arg(long)]
+        json: bool,
+        /// Read back every section instead of comparing CRC-32s computed on the target
+        #[arg(long)]
+        read_back: bool,
+    },
     /// Read a CPU register
     ReadReg {
         /// Register name (r0, r1, ..., r15, sp, lr, pc, xpsr) or index (0-16)
         register: String,
     },
+    /// Write a CPU register
+    WriteReg {
+        /// Register name (r0, r1, ..., r15, sp, lr, pc, xpsr) or index (0-16)
+        register: String,
+        /// Value to write (hex format, e.g., 0x20000100)
+        #[arg(value_parser = parse_hex)]
+        value: u32,
+    },
     /// Read the Program Counter (PC) register
     ReadPc,
     /// Read all CPU registers
     ReadAll,
+    /// Set a hardware breakpoint
+    Break {
+        /// Instruction address (hex format, e.g., 0x000001c4)
+        #[arg(value_parser = parse_hex)]
+        address: u32,
+    },
+    /// Remove a hardware breakpoint
+    Delete {
+        /// Address of the breakpoint to remove; all breakpoints when omitted
+        #[arg(value_parser = parse_hex)]
+        address: Option<u32>,
+    },
+    /// List active hardware breakpoints
+    Breakpoints,
+    /// Halt when the core accesses a memory range
+    Watch {
+        /// Start of the watched range (hex format, e.g., 0x20200010)
+        #[arg(value_parser = parse_hex)]
+        address: u32,
+        /// Bytes to watch; a power of two the address is aligned to
+        #[arg(short, long, default_value = "4")]
+        size: u32,
+        /// Accesses that trigger: read, write or access
+        #[arg(short, long, default_value = "write")]
+        kind: WatchKind,
+    },
+    /// Remove a watchpoint
+    Unwatch {
+        /// Address of the watchpoint to remove; all watchpoints when omitted
+        #[arg(value_parser = parse_hex)]
+        address: Option<u32>,
+    },
+    /// List active watchpoints
+    Watchpoints,
+    /// Show the bridge firmware version and capabilities
+    BridgeInfo,
+    /// Run the bridge simulator on a pseudo-terminal until interrupted
+    Simulate,
 }
 
 fn parse_hex(s: &str) -> Result<u32, std::num::ParseIntError> {
@@ -108,6 +279,13 @@ fn parse_hex(s: &str) -> Result<u32, std::num::ParseIntError> {
     }
 }
 
+fn parse_range(s: &str) -> Result<Range<u32>, String> {
+    let (start, end) = s.split_once("..").ok_or_else(|| format!("Expected START..END, got {}", s))?;
+    let start = parse_hex(start).map_err(|e| e.to_string())?;
+    let end = parse_hex(end).map_err(|e| e.to_string())?;
+    Ok(start..end)
+}
+
 fn parse_register_name(reg_name: &str) -> Result<u32, String> {
     match reg_name.to_lowercase().as_str() {
         "r0" => Ok(registers::R0),
@@ -127,6 +305,8 @@ fn parse_register_name(reg_name: &str) -> Result<u32, String> {
         "r14" | "lr" => Ok(registers::LR),
         "r15" | "pc" => Ok(registers::PC),
         "xpsr" | "psr" => Ok(registers::XPSR),
+        "msp" => Ok(registers::MSP),
+        "psp" => Ok(registers::PSP),
         _ => {
             // Try parsing as a number
             if let Ok(index) = reg_name.parse::<u32>() {
@@ -161,9 +341,22 @@ fn get_register_name(reg_index: u32) -> &'static str {
         0x0e => "LR",
         0x0f => "PC",
         0x10 => "XPSR",
+        0x11 => "MSP",
+        0x12 => "PSP",
         _ => "UNKNOWN",
     }
 }
+
+/// Log where the core stopped, with the stacked frame of a caught HardFault
+#[cfg(feature = "cli")]
+fn log_halt(event: &loader::HaltEvent) {
+    info!("Target halted at 0x{:08X} ({})", event.pc, event.reason);
+    if let cortex_m::HaltReason::VectorCatch { frame: Some(frame), .. } = event.reason {
+        for line in frame.to_string().lines() {
+            info!("{}", line);
+        }
+    }
+}
 #[tokio::main]
 async fn main() {
     #[cfg(feature = "cli")]
@@ -181,27 +374,151 @@ async fn main() {
             ::set_global_default(subscriber)
             .expect("Failed to set global default subscriber");
 
-        // Create the serial loader
-        let mut debug = match loader::SerialLoader::new(None, cli.baud) {
-            Ok(loader) => {
-                info!("Connected to {} at {} baud", cli.port, cli.baud);
-                loader
+        if let Commands::Simulate = cli.command {
+            match simulator::spawn_pty(simulator::BridgeSimulator::new()) {
+                Ok(path) => {
+                    info!("Simulated bridge ready, connect with: --port {}", path);
+                    loop {
+                        thread::park();
+                    }
+                }
+                Err(e) => {
+                    einfo!("Failed to start simulator: {}", e);
+                    std::process::exit(e.exit_code());
+                }
             }
-            Err(e) => {
-                einfo!("Failed to connect to {}: {}", cli.port, e);
-                std::process::exit(1);
+        }
+        let port_label = cli.port.clone().unwrap_or_else(|| "auto-detected port".to_string());
+
+        // Create the serial loader
+        let mut debug = match &cli.tcp {
+            Some(address) =>
+                match loader::SerialLoader::connect_tcp(address) {
+                    Ok(loader) => {
+                        info!("Connected to bridge at {}", address);
+                        loader
+                    }
+                    Err(e) => {
+                        einfo!("Failed to connect to {}: {}", address, e);
+                        std::process::exit(e.exit_code());
+                    }
+                }
+            None if cli.simulator => {
+                info!("Using simulated bridge");
+                match
+                    loader::SerialLoader::with_transport(
+                        Box::new(simulator::SimulatorTransport::new(simulator::BridgeSimulator::new()))
+                    )
+                {
+                    Ok(loader) => loader,
+                    Err(e) => {
+                        einfo!("Failed to start simulator: {}", e);
+                        std::process::exit(e.exit_code());
+                    }
+                }
             }
+            None =>
+                match loader::SerialLoader::new(cli.port.as_deref(), cli.baud) {
+                    Ok(loader) => {
+                        info!("Connected to {} at {} baud", port_label, cli.baud);
+                        loader
+                    }
+                    Err(e) => {
+                        einfo!("Failed to connect to {}: {}", port_label, e);
+                        std::process::exit(e.exit_code());
+                    }
+                }
         };
 
+        let policy = loader::RetryPolicy {
+            timeout: Duration::from_millis(cli.timeout_ms),
+            max_retries: cli.retries,
+            ..Default::default()
+        };
+        if let Err(e) = debug.set_retry_policy(policy) {
+            einfo!("Failed to apply retry policy: {}", e);
+            std::process::exit(e.exit_code());
+        }
+
         // Execute the command
         let result = match cli.command {
             Commands::Halt => {
                 info!("Halting target processor...");
-                debug.halt()
+                debug.halt().map(|event| log_halt(&event))
             }
             Commands::Resume => {
                 info!("Resuming target processor...");
-                debug.resume()
+                debug.resume().map(|status| info!("Target {}", status))
+            }
+            Commands::Status => {
+                debug.core_status().and_then(|status| {
+                    info!("Target {}", status);
+                    // Peek at DFSR without clearing it so the reason survives for the next command
+                    if let Some(event) = debug.poll_halt(false)? {
+                        log_halt(&event);
+                    }
+                    Ok(())
+                })
+            }
+            Commands::Reset { kind, halt } => {
+                info!("Resetting target ({})...", kind);
+                debug.reset(kind, halt).map(|event| {
+                    match event {
+                        Some(event) => log_halt(&event),
+                        None => info!("Target running"),
+                    }
+                })
+            }
+            Commands::Wait { timeout } => {
+                info!("Waiting up to {} ms for the target to halt...", timeout);
+                debug
+                    .wait_for_halt(Duration::from_millis(timeout))
+                    .map(|event| log_halt(&event))
+            }
+            Commands::FaultReport { elf } => {
+                let symbols = match elf {
+                    Some(path) => symbols::ElfSymbols::from_elf_file(&path).map(Some),
+                    None => Ok(None),
+                };
+                symbols.and_then(|symbols| {
+                    let mut report = debug.fault_report()?;
+                    if let Some(symbols) = &symbols {
+                        if !symbols.has_line_info() {
+                            info!("The ELF has no .debug_line section; showing function names only");
+                        }
+                        report.symbolize(symbols);
+                    }
+                    for line in report.to_string().lines() {
+                        info!("{}", line);
+                    }
+                    Ok(())
+                })
+            }
+            Commands::VectorCatch { hard_fault, reset } => {
+                debug
+                    .vector_catch()
+                    .and_then(|current| {
+                        if hard_fault.is_none() && reset.is_none() {
+                            return Ok(current);
+                        }
+                        debug.set_vector_catch(cortex_m::VectorCatch {
+                            hard_fault: hard_fault.unwrap_or(current.hard_fault),
+                            core_reset: reset.unwrap_or(current.core_reset),
+                        })
+                    })
+                    .map(|catch| info!("Vector catch: {}", catch))
+            }
+            Commands::Step { count, until_outside, max_steps, allow_interrupts } => {
+                let outcome = match until_outside {
+                    Some(range) => debug.step_until_outside(range, max_steps, !allow_interrupts),
+                    None => debug.step_many(count, !allow_interrupts),
+                };
+                outcome.map(|outcome| {
+                    info!("PC: 0x{:08X} after {} step(s)", outcome.pc, outcome.steps);
+                    if outcome.reason != cortex_m::HaltReason::Step {
+                        info!("Stopped early: {}", outcome.reason);
+                    }
+                })
             }
             Commands::ReadBytes { address, length } => {
                 info!("Reading from address 0x{:08X}...", address);
@@ -245,6 +562,67 @@ async fn main() {
                 info!("Writing 0x{:08X} to address 0x{:08X}...", value, address);
                 debug.write_word(address, value)
             }
+            Commands::Erase { address, length, mass } => {
+                match address {
+                    Some(address) if !mass => {
+                        let first = address & !(mspm0::FLASH_SECTOR_SIZE - 1);
+                        let end = address.saturating_add(length.max(1));
+                        info!("Erasing flash sectors 0x{:08X}-0x{:08X}...", first, end - 1);
+                        (first..end)
+                            .step_by(mspm0::FLASH_SECTOR_SIZE as usize)
+                            .try_for_each(|sector| debug.flash_erase_sector(sector))
+                    }
+                    _ => {
+                        info!("Erasing all of main flash...");
+                        debug.flash_mass_erase()
+                    }
+                }
+            }
+            Commands::Flash { file, base, format, algorithm, reset } => {
+                image::FirmwareImage::load(&file, format, base).and_then(|image| {
+                    info!("Flashing {}: {}", file, image);
+                    let algorithm = algorithm.as_deref().map(flash_algorithm::FlashAlgorithm::load).transpose()?;
+                    if !debug.core_status()?.halted {
+                        debug.halt()?;
+                    }
+                    let report = |progress: loader::FlashProgress| {
+                        info!(
+                            "Sector 0x{:08X} done ({}/{} bytes)",
+                            progress.address,
+                            progress.done,
+                            progress.total
+                        );
+                    };
+                    let summary = match &algorithm {
+                        Some(algorithm) => {
+                            info!("Using flash algorithm {}", algorithm);
+                            debug.flash_image_with_algorithm(&image, algorithm, report)?
+                        }
+                        None => debug.flash_image(&image, report)?,
+                    };
+                    info!("Flash complete: {}", summary);
+                    if reset {
+                        debug.reset(ResetKind::System, false)?;
+                        info!("Target reset and running");
+                    }
+                    Ok(())
+                })
+            }
+            Commands::Verify { file, base, format, json, read_back } => {
+                image::FirmwareImage::load(&file, format, base).and_then(|image| {
+                    info!("Verifying {}: {}", file, image);
+                    let result = debug.verify_image(&image, read_back)?;
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&result).unwrap_or_default());
+                    } else {
+                        println!("{}", result);
+                    }
+                    if !result.success {
+                        return Err(error::DebugError::VerifyFailed { mismatches: result.mismatch_count() });
+                    }
+                    Ok(())
+                })
+            }
             Commands::ReadReg { register } => {
                 match parse_register_name(&register) {
                     Ok(reg_index) => {
@@ -257,10 +635,16 @@ async fn main() {
                             Err(e) => Err(e),
                         }
                     }
-                    Err(e) => {
-                        einfo!("Error: {}", e);
-                        std::process::exit(1);
+                    Err(e) => Err(error::DebugError::InvalidArgument(e)),
+                }
+            }
+            Commands::WriteReg { register, value } => {
+                match parse_register_name(&register) {
+                    Ok(reg_index) => {
+                        info!("Writing 0x{:08X} to register {}...", value, get_register_name(reg_index));
+                        debug.write_register(reg_index, value)
                     }
+                    Err(e) => Err(error::DebugError::InvalidArgument(e)),
                 }
             }
             Commands::ReadPc => {
@@ -275,48 +659,111 @@ async fn main() {
             }
             Commands::ReadAll => {
                 info!("Reading all CPU registers...");
-                let mut errors = Vec::new();
 
-                // Read all registers R0-R15 and XPSR
-                for reg_index in 0..=16 {
-                    match debug.read_register(reg_index) {
-                        Ok(value) => {
-                            info!("{}: 0x{:08X}", get_register_name(reg_index), value);
+                // Read all registers R0-R15 and XPSR in as few round-trips as the bridge allows
+                let indices: Vec<u32> = (0..=16).collect();
+                match debug.read_registers(&indices) {
+                    Ok(values) => {
+                        for (reg_index, value) in indices.iter().zip(values) {
+                            info!("{}: 0x{:08X}", get_register_name(*reg_index), value);
                         }
-                        Err(e) => {
-                            errors.push(
-                                format!("Failed to read {}: {}", get_register_name(reg_index), e)
-                            );
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Commands::Break { address } => {
+                debug.set_breakpoint(address).map(|breakpoint| {
+                    info!(
+                        "Breakpoint at 0x{:08X} (comparator {})",
+                        breakpoint.address,
+                        breakpoint.comparator
+                    );
+                })
+            }
+            Commands::Delete { address } => {
+                match address {
+                    Some(address) => debug.clear_breakpoint(address),
+                    None => debug.clear_all_breakpoints(),
+                }
+            }
+            Commands::Breakpoints => {
+                match (debug.breakpoint_comparators(), debug.breakpoints()) {
+                    (Ok(comparators), Ok(breakpoints)) => {
+                        let mut used: Vec<usize> = breakpoints.iter().map(|b| b.comparator).collect();
+                        used.dedup();
+                        info!(
+                            "{} breakpoint(s), {} of {} comparators in use",
+                            breakpoints.len(),
+                            used.len(),
+                            comparators
+                        );
+                        for breakpoint in breakpoints {
+                            info!("  #{}: 0x{:08X}", breakpoint.comparator, breakpoint.address);
                         }
+                        Ok(())
                     }
-                    // Small delay between reads
-                    thread::sleep(Duration::from_millis(10));
+                    (Err(e), _) | (_, Err(e)) => Err(e),
                 }
-
-                if !errors.is_empty() {
-                    for error in errors {
-                        einfo!("Error: {}", error);
+            }
+            Commands::Watch { address, size, kind } => {
+                debug.set_watchpoint(address, size, kind).map(|watchpoint| {
+                    info!(
+                        "Watchpoint ({}) at 0x{:08X}+{} (comparator {})",
+                        watchpoint.kind,
+                        watchpoint.address,
+                        watchpoint.size,
+                        watchpoint.comparator
+                    );
+                })
+            }
+            Commands::Unwatch { address } => {
+                match address {
+                    Some(address) => debug.clear_watchpoint(address),
+                    None => debug.clear_all_watchpoints(),
+                }
+            }
+            Commands::Watchpoints => {
+                match (debug.watchpoint_comparators(), debug.watchpoints()) {
+                    (Ok(comparators), Ok(watchpoints)) => {
+                        info!("{} of {} comparators in use", watchpoints.len(), comparators);
+                        for watchpoint in watchpoints {
+                            info!(
+                                "  #{}: {} 0x{:08X}+{}",
+                                watchpoint.comparator,
+                                watchpoint.kind,
+                                watchpoint.address,
+                                watchpoint.size
+                            );
+                        }
+                        Ok(())
                     }
-                    Err("Some register reads failed".into())
-                } else {
-                    Ok(())
+                    (Err(e), _) | (_, Err(e)) => Err(e),
                 }
             }
+            Commands::BridgeInfo => {
+                let bridge = debug.bridge_info();
+                info!("Bridge: {}", bridge);
+                Ok(())
+            }
+            // Handled before connecting
+            Commands::Simulate => Ok(()),
         };
 
         match result {
             Ok(_) => {
                 if cli.verbose {
-                    info!("Command completed successfully");
+                    let retries = debug.total_retries();
+                    info!("Command completed successfully ({} retries)", retries);
                 }
             }
             Err(e) => {
-                einfo!("Command failed: {}", e);
-                std::process::exit(1);
+                einfo!("Command failed [{}]: {}", e.code(), e);
+                std::process::exit(e.exit_code());
             }
         }
     }
-    #[cfg(feature = "socket")]
+    #[cfg(all(feature = "socket", not(feature = "cli")))]
     {
         use socketioxide::SocketIo;
         use axum::routing::get;
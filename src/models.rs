@@ -6,4 +6,13 @@ pub struct CommandResponse {
     pub message: String,
     pub command: String,
     pub args: Vec<String>,
+    /// Retries the bridge command needed under the retry policy
+    #[serde(default)]
+    pub retries: u32,
+    /// Machine-readable failure kind (`DebugError::code`), absent on success
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    /// Structured result for commands that return more than a message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
 }
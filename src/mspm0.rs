@@ -0,0 +1,144 @@
+use serde::Serialize;
+use std::fmt;
+
+// MSPM0G3507 memory map
+pub const FLASH_BASE: u32 = 0x00000000;
+pub const FLASH_SIZE: u32 = 0x00020000; // 128 KB main flash
+pub const SRAM_BASE: u32 = 0x20200000;
+pub const SRAM_SIZE: u32 = 0x00008000; // 32 KB SRAM
+
+/// NONMAIN: boot configuration (BCR/BSL), never written by the debugger
+pub const NONMAIN_BASE: u32 = 0x41c00000;
+pub const NONMAIN_SIZE: u32 = 0x00000400;
+
+pub fn in_flash(address: u32) -> bool {
+    (FLASH_BASE..FLASH_BASE + FLASH_SIZE).contains(&address)
+}
+
+pub fn in_sram(address: u32) -> bool {
+    (SRAM_BASE..SRAM_BASE + SRAM_SIZE).contains(&address)
+}
+
+pub fn in_nonmain(address: u32) -> bool {
+    (NONMAIN_BASE..NONMAIN_BASE + NONMAIN_SIZE).contains(&address)
+}
+
+// FLASHCTL: main flash is erased in 1 KB sectors and programmed in 64-bit flash
+// words, each stored with 8 ECC bits generated by the controller
+pub const FLASH_SECTOR_SIZE: u32 = 0x400;
+pub const FLASH_WORD_SIZE: u32 = 8;
+pub const FLASHCTL_BASE: u32 = 0x400cd000;
+pub const FLASHCTL_CMDEXEC_ADDR: u32 = FLASHCTL_BASE + 0x1100;
+pub const FLASHCTL_CMDTYPE_ADDR: u32 = FLASHCTL_BASE + 0x1104;
+pub const FLASHCTL_CMDADDR_ADDR: u32 = FLASHCTL_BASE + 0x1120;
+pub const FLASHCTL_CMDBYTEN_ADDR: u32 = FLASHCTL_BASE + 0x1124;
+pub const FLASHCTL_CMDDATA0_ADDR: u32 = FLASHCTL_BASE + 0x1130;
+pub const FLASHCTL_CMDDATA1_ADDR: u32 = FLASHCTL_BASE + 0x1134;
+/// Write protection of main flash sectors 0-31, one bit per sector
+pub const FLASHCTL_CMDWEPROTA_ADDR: u32 = FLASHCTL_BASE + 0x11d0;
+/// Write protection of main flash sectors 32 and up, one bit per 8 sectors
+pub const FLASHCTL_CMDWEPROTB_ADDR: u32 = FLASHCTL_BASE + 0x11d4;
+pub const FLASHCTL_STATCMD_ADDR: u32 = FLASHCTL_BASE + 0x13d0;
+pub const FLASHCTL_CMDEXEC_EXECUTE: u32 = 1 << 0;
+pub const FLASHCTL_CMDTYPE_PROGRAM: u32 = 0x1;
+pub const FLASHCTL_CMDTYPE_ERASE: u32 = 0x2;
+pub const FLASHCTL_CMDTYPE_SIZE_ONEWORD: u32 = 0x0 << 4;
+pub const FLASHCTL_CMDTYPE_SIZE_SECTOR: u32 = 0x4 << 4;
+pub const FLASHCTL_CMDTYPE_SIZE_BANK: u32 = 0x5 << 4;
+/// CMDBYTEN for a full flash word: 8 data bytes plus the ECC byte
+pub const FLASHCTL_CMDBYTEN_WORD_ECC: u32 = 0x1ff;
+pub const FLASHCTL_STATCMD_DONE: u32 = 1 << 0;
+pub const FLASHCTL_STATCMD_PASS: u32 = 1 << 1;
+pub const FLASHCTL_STATCMD_IN_PROGRESS: u32 = 1 << 2;
+pub const FLASHCTL_STATCMD_FAIL_WEPROT: u32 = 1 << 4;
+pub const FLASHCTL_STATCMD_FAIL_VERIFY: u32 = 1 << 5;
+pub const FLASHCTL_STATCMD_FAIL_ILLADDR: u32 = 1 << 6;
+pub const FLASHCTL_STATCMD_FAIL_MODE: u32 = 1 << 7;
+pub const FLASHCTL_STATCMD_FAIL_MISC: u32 = 1 << 12;
+
+/// Sector number of a main flash address
+pub fn flash_sector(address: u32) -> u32 {
+    (address - FLASH_BASE) / FLASH_SECTOR_SIZE
+}
+
+/// CMDWEPROTA/B write that unprotects only the sector at `address`; the controller
+/// protects every sector again once a command completes
+pub fn flash_unprotect(address: u32) -> (u32, u32) {
+    let sector = flash_sector(address);
+    if sector < 32 {
+        (FLASHCTL_CMDWEPROTA_ADDR, !(1 << sector))
+    } else {
+        (FLASHCTL_CMDWEPROTB_ADDR, !(1 << (sector / 8)))
+    }
+}
+
+/// Failure bits of STATCMD in words, for error messages
+pub fn flash_status_text(status: u32) -> String {
+    let failures = [
+        (FLASHCTL_STATCMD_FAIL_WEPROT, "write/erase protected"),
+        (FLASHCTL_STATCMD_FAIL_VERIFY, "verify failed"),
+        (FLASHCTL_STATCMD_FAIL_ILLADDR, "illegal address"),
+        (FLASHCTL_STATCMD_FAIL_MODE, "invalid command mode"),
+        (FLASHCTL_STATCMD_FAIL_MISC, "other failure"),
+    ];
+    let reasons: Vec<&str> = failures
+        .iter()
+        .filter(|(bit, _)| (status & bit) != 0)
+        .map(|(_, text)| *text)
+        .collect();
+    if reasons.is_empty() {
+        if (status & FLASHCTL_STATCMD_IN_PROGRESS) != 0 {
+            "still in progress".to_string()
+        } else if (status & FLASHCTL_STATCMD_DONE) == 0 {
+            "did not start".to_string()
+        } else {
+            "failed".to_string()
+        }
+    } else {
+        reasons.join(", ")
+    }
+}
+
+// SYSCTL SOCLOCK block: application-triggered resets
+pub const SYSCTL_RESETLEVEL_ADDR: u32 = 0x400b0200;
+pub const SYSCTL_RESETCMD_ADDR: u32 = 0x400b0204;
+/// RESETCMD writes must carry the key in the top byte
+pub const SYSCTL_RESETCMD_KEY: u32 = 0xe4000000;
+pub const SYSCTL_RESETCMD_GO: u32 = 1 << 0;
+pub const SYSCTL_RESETLEVEL_CPU: u32 = 0;
+pub const SYSCTL_RESETLEVEL_BOOT: u32 = 1;
+
+/// How the target is reset, from the lightest to the most complete
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetKind {
+    /// CPURST through SYSCTL: only the Cortex-M0+ core restarts, peripherals keep their state
+    Core,
+    /// SYSRST through AIRCR.SYSRESETREQ: core and peripherals
+    System,
+    /// BOOTRST through SYSCTL: like SYSRST, but the boot configuration routine runs again
+    Boot,
+}
+
+impl std::str::FromStr for ResetKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "core" | "cpu" | "cpurst" => Ok(ResetKind::Core),
+            "system" | "sys" | "sysrst" => Ok(ResetKind::System),
+            "boot" | "bootrst" => Ok(ResetKind::Boot),
+            _ => Err(format!("Unknown reset kind: {} (core, system or boot)", s)),
+        }
+    }
+}
+
+impl fmt::Display for ResetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetKind::Core => write!(f, "core reset (CPURST)"),
+            ResetKind::System => write!(f, "system reset (SYSRST)"),
+            ResetKind::Boot => write!(f, "boot reset (BOOTRST)"),
+        }
+    }
+}
@@ -1,8 +1,14 @@
+use serde::Serialize;
+use std::fmt;
 use std::result;
 use tracing::info;
+use crate::error::DebugError;
 use crc::{ Crc, * };
 
+#[derive(Debug, Clone)]
 pub enum SWDCommand {
+    /// Ask the bridge for its firmware version and capabilities
+    Info,
     Halt,
     Resume,
     ReadBytes {
@@ -20,14 +26,369 @@ pub enum SWDCommand {
         write_address: u32,
         write_data: Vec<u8>,
     },
+    /// Several SWD operations executed by the bridge in one round trip
+    Batch {
+        ops: Vec<BatchOp>,
+        /// Set when the writes in `ops` can be repeated without harm (e.g. C_HALT),
+        /// so the batch is retried like a read
+        repeatable: bool,
+    },
+}
+
+impl SWDCommand {
+    /// Commands that can be resent without changing target state
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            | SWDCommand::Info
+            | SWDCommand::Halt
+            | SWDCommand::ReadBytes { .. }
+            | SWDCommand::ReadWord { .. }
+            | SWDCommand::ReadWords { .. } => true,
+            SWDCommand::Batch { ops, repeatable } => {
+                *repeatable || ops.iter().all(|op| !matches!(op, BatchOp::WriteWord { .. }))
+            }
+            SWDCommand::Resume | SWDCommand::Write { .. } => false,
+        }
+    }
+
+    /// Opcode byte sent in the command field of the frame
+    pub fn opcode(&self) -> u8 {
+        match self {
+            SWDCommand::Info => ProtocolHandler::INFO_COMMAND,
+            SWDCommand::Halt => ProtocolHandler::HALT_COMMAND,
+            SWDCommand::Resume => ProtocolHandler::RESUME_COMMAND,
+            SWDCommand::ReadBytes { .. } => ProtocolHandler::READ_BYTES_COMMAND,
+            SWDCommand::ReadWord { .. } => ProtocolHandler::READ_WORD,
+            SWDCommand::ReadWords { .. } => ProtocolHandler::READ_WORDS_COMMAND,
+            SWDCommand::Write { .. } => ProtocolHandler::WRITE_COMMAND,
+            SWDCommand::Batch { .. } => ProtocolHandler::BATCH_COMMAND,
+        }
+    }
+}
+
+/// One operation inside a BATCH frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    WriteWord {
+        address: u32,
+        value: u32,
+    },
+    ReadWord {
+        address: u32,
+    },
+    /// Read `address` until `(value & mask) == mask`, at most `attempts` times
+    PollBitSet {
+        address: u32,
+        mask: u32,
+        attempts: u16,
+    },
+}
+
+impl BatchOp {
+    pub const WRITE_WORD: u8 = 0x01;
+    pub const READ_WORD: u8 = 0x02;
+    pub const POLL_BIT_SET: u8 = 0x03;
+
+    /// Encoded size of the operation in the request
+    pub fn request_len(&self) -> usize {
+        match self {
+            BatchOp::WriteWord { .. } => 9,
+            BatchOp::ReadWord { .. } => 5,
+            BatchOp::PollBitSet { .. } => 11,
+        }
+    }
+
+    /// Size of the operation's result in the response
+    pub fn result_len(&self) -> usize {
+        match self {
+            BatchOp::WriteWord { .. } => 1,
+            BatchOp::ReadWord { .. } | BatchOp::PollBitSet { .. } => 5,
+        }
+    }
+
+    /// Op Format: type addr0..addr3 [value0..value3 | mask0..mask3 att0 att1]
+    pub fn encode(&self, data: &mut Vec<u8>) {
+        match self {
+            BatchOp::WriteWord { address, value } => {
+                data.push(Self::WRITE_WORD);
+                data.extend_from_slice(&address.to_be_bytes());
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+            BatchOp::ReadWord { address } => {
+                data.push(Self::READ_WORD);
+                data.extend_from_slice(&address.to_be_bytes());
+            }
+            BatchOp::PollBitSet { address, mask, attempts } => {
+                data.push(Self::POLL_BIT_SET);
+                data.extend_from_slice(&address.to_be_bytes());
+                data.extend_from_slice(&mask.to_be_bytes());
+                data.extend_from_slice(&attempts.to_be_bytes());
+            }
+        }
+    }
+
+    /// Parse a BATCH request payload back into operations
+    pub fn decode_all(mut data: &[u8]) -> Option<Vec<BatchOp>> {
+        let word = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let mut ops = Vec::new();
+        while let Some(&kind) = data.first() {
+            let op = match kind {
+                Self::WRITE_WORD if data.len() >= 9 =>
+                    BatchOp::WriteWord { address: word(&data[1..]), value: word(&data[5..]) },
+                Self::READ_WORD if data.len() >= 5 => BatchOp::ReadWord { address: word(&data[1..]) },
+                Self::POLL_BIT_SET if data.len() >= 11 =>
+                    BatchOp::PollBitSet {
+                        address: word(&data[1..]),
+                        mask: word(&data[5..]),
+                        attempts: ((data[9] as u16) << 8) | (data[10] as u16),
+                    },
+                _ => {
+                    return None;
+                }
+            };
+            data = &data[op.request_len()..];
+            ops.push(op);
+        }
+        Some(ops)
+    }
+}
+
+/// Outcome of one `BatchOp`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchResult {
+    Written,
+    /// Value read (for polls, the last value read)
+    Value(u32),
+    /// Bus fault or poll timeout; the bridge stops the batch here
+    Failed {
+        status: u8,
+    },
+}
+
+impl BatchResult {
+    pub const STATUS_OK: u8 = 0x00;
+    pub const STATUS_FAULT: u8 = 0x01;
+    pub const STATUS_POLL_TIMEOUT: u8 = 0x02;
+
+    /// Result Format: status [value0..value3 (little-endian, for reads and polls)]
+    pub fn encode(&self, data: &mut Vec<u8>) {
+        match self {
+            BatchResult::Written => data.push(Self::STATUS_OK),
+            BatchResult::Value(value) => {
+                data.push(Self::STATUS_OK);
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            BatchResult::Failed { status } => data.push(*status),
+        }
+    }
+
+    /// Decode the results for `ops`; a failure ends the list early
+    pub fn decode_all(ops: &[BatchOp], mut data: &[u8]) -> Result<Vec<BatchResult>, FrameError> {
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let status = match data.first() {
+                Some(&status) => status,
+                None => {
+                    break;
+                }
+            };
+            if status != Self::STATUS_OK {
+                results.push(BatchResult::Failed { status });
+                break;
+            }
+            if data.len() < op.result_len() {
+                return Err(FrameError::PayloadLength { expected: op.result_len(), received: data.len() });
+            }
+            results.push(match op {
+                BatchOp::WriteWord { .. } => BatchResult::Written,
+                _ => BatchResult::Value(u32::from_le_bytes([data[1], data[2], data[3], data[4]])),
+            });
+            data = &data[op.result_len()..];
+        }
+        Ok(results)
+    }
+}
+
+/// Decoded response from the ESP32 bridge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Command acknowledged without payload (halt, resume, write)
+    Ack {
+        command: u8,
+    },
+    /// Command acknowledged with payload (reads)
+    Data {
+        command: u8,
+        data: Vec<u8>,
+    },
+    /// Bridge reported an error code (e.g. 0xe1, 0xe3) for the command
+    Nack {
+        command: u8,
+        code: u8,
+    },
+}
+
+/// Reasons a response frame could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    TooShort {
+        length: usize,
+    },
+    BadHeader([u8; 2]),
+    BadFooter([u8; 2]),
+    LengthMismatch {
+        declared: usize,
+        actual: usize,
+    },
+    CrcMismatch {
+        expected: u8,
+        received: u8,
+    },
+    UnexpectedCommand {
+        expected: u8,
+        received: u8,
+    },
+    UnknownAck {
+        command: u8,
+        ack: u8,
+    },
+    PayloadLength {
+        expected: usize,
+        received: usize,
+    },
+    TagMismatch {
+        expected: Option<u8>,
+        received: Option<u8>,
+    },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooShort { length } => write!(f, "Frame too short: {} bytes", length),
+            FrameError::BadHeader(header) => write!(f, "Bad frame header: {:02x?}", header),
+            FrameError::BadFooter(footer) => write!(f, "Bad frame footer: {:02x?}", footer),
+            FrameError::LengthMismatch { declared, actual } =>
+                write!(f, "Length mismatch: header says {}, frame has {}", declared, actual),
+            FrameError::CrcMismatch { expected, received } =>
+                write!(f, "CRC mismatch: expected {:#04x}, got {:#04x}", expected, received),
+            FrameError::UnexpectedCommand { expected, received } =>
+                write!(f, "Unexpected command in response: expected {:#04x}, got {:#04x}", expected, received),
+            FrameError::UnknownAck { command, ack } =>
+                write!(f, "Unknown ACK {:#04x} for command {:#04x}", ack, command),
+            FrameError::PayloadLength { expected, received } =>
+                write!(f, "Payload length mismatch: expected {} bytes, got {}", expected, received),
+            FrameError::TagMismatch { expected, received } =>
+                write!(f, "Sequence tag mismatch: expected {:02x?}, got {:02x?}", expected, received),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Firmware version and capabilities reported by the bridge's INFO command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BridgeInfo {
+    pub version: (u8, u8, u8),
+    /// Largest data payload the bridge accepts or returns in one frame
+    pub max_payload: usize,
+    /// SWD clock in Hz, 0 if unknown
+    pub swd_clock_hz: u32,
+    /// Command opcodes the firmware implements
+    pub opcodes: Vec<u8>,
+    /// Firmware echoes sequence tags from `TAGGED_HEADER` frames
+    pub tagged_frames: bool,
+}
+
+impl BridgeInfo {
+    /// Major protocol version this host speaks
+    pub const PROTOCOL_MAJOR: u8 = 1;
+
+    /// Capabilities assumed for firmware that predates the INFO command
+    pub fn legacy() -> Self {
+        BridgeInfo {
+            version: (0, 0, 0),
+            max_payload: ProtocolHandler::MAX_DATA_LENGTH,
+            swd_clock_hz: 0,
+            opcodes: vec![
+                ProtocolHandler::HALT_COMMAND,
+                ProtocolHandler::RESUME_COMMAND,
+                ProtocolHandler::READ_WORD,
+                ProtocolHandler::WRITE_COMMAND,
+                ProtocolHandler::READ_BYTES_COMMAND,
+                ProtocolHandler::READ_WORDS_COMMAND
+            ],
+            tagged_frames: false,
+        }
+    }
+
+    /// Flag bits in the optional byte after the opcode list
+    pub const FLAG_TAGGED_FRAMES: u8 = 0x01;
+
+    /// Payload Format: major minor patch max0 max1 clk0 clk1 clk2 clk3 count opcodes.. [flags]
+    pub fn parse(payload: &[u8]) -> Result<Self, FrameError> {
+        if payload.len() < 10 || payload.len() < 10 + (payload[9] as usize) {
+            return Err(FrameError::PayloadLength {
+                expected: 10 + (payload.get(9).copied().unwrap_or(0) as usize),
+                received: payload.len(),
+            });
+        }
+        let flags = payload.get(10 + (payload[9] as usize)).copied().unwrap_or(0);
+        Ok(BridgeInfo {
+            version: (payload[0], payload[1], payload[2]),
+            max_payload: (((payload[3] as u16) << 8) | (payload[4] as u16)) as usize,
+            swd_clock_hz: u32::from_be_bytes([payload[5], payload[6], payload[7], payload[8]]),
+            opcodes: payload[10..10 + (payload[9] as usize)].to_vec(),
+            tagged_frames: (flags & Self::FLAG_TAGGED_FRAMES) != 0,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![self.version.0, self.version.1, self.version.2];
+        payload.push((self.max_payload >> 8) as u8);
+        payload.push((self.max_payload & 0xff) as u8);
+        payload.extend_from_slice(&self.swd_clock_hz.to_be_bytes());
+        payload.push(self.opcodes.len() as u8);
+        payload.extend_from_slice(&self.opcodes);
+        payload.push(if self.tagged_frames { Self::FLAG_TAGGED_FRAMES } else { 0 });
+        payload
+    }
+
+    pub fn supports(&self, opcode: u8) -> bool {
+        self.opcodes.contains(&opcode)
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == (0, 0, 0)
+    }
+}
+
+impl fmt::Display for BridgeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_legacy() {
+            write!(f, "legacy firmware (no INFO support)")?;
+        } else {
+            write!(f, "firmware {}.{}.{}", self.version.0, self.version.1, self.version.2)?;
+        }
+        write!(
+            f,
+            ", max payload {} bytes, SWD clock {} Hz, opcodes {:02x?}{}",
+            self.max_payload,
+            self.swd_clock_hz,
+            self.opcodes,
+            if self.tagged_frames { ", tagged frames" } else { "" }
+        )
+    }
 }
 
 pub struct ProtocolHandler {
     command: SWDCommand,
+    tag: Option<u8>,
 }
 
 impl ProtocolHandler {
     pub const HEADER: [u8; 2] = [0xff, 0xf9];
+    pub const TAGGED_HEADER: [u8; 2] = [0xff, 0xfa]; // Header of frames carrying a sequence tag
     pub const FOOTER: [u8; 2] = [0xf5, 0xe7];
     pub const MAX_DATA_LENGTH: usize = 4096; // Maximum
     pub const HALT_COMMAND: u8 = 0xc1;
@@ -36,7 +397,11 @@ impl ProtocolHandler {
     pub const READ_BYTES_COMMAND: u8 = 0xc6;
     pub const READ_WORDS_COMMAND: u8 = 0xc7;
     pub const WRITE_COMMAND: u8 = 0xc4;
+    pub const INFO_COMMAND: u8 = 0xc8;
+    pub const BATCH_COMMAND: u8 = 0xc9;
     pub const ACK_OFFSET: usize = 5; // Offset for ACK in the response frame
+    pub const MIN_BODY_LENGTH: usize = 2; // cmd + crc
+    pub const MAX_BODY_LENGTH: usize = Self::MAX_DATA_LENGTH + 8; // cmd, ack/addr, len, crc around the data
     pub const HALT_ACK: u8 = 0xd1;
     pub const HALT_ERROR: u8 = 0xe1;
     pub const RESUME_ACK: u8 = 0xd1;
@@ -45,10 +410,20 @@ impl ProtocolHandler {
     pub const READ_ERROR: u8 = 0xe3;
     pub const WRITE_ACK: u8 = 0xd1;
     pub const WRITE_ERROR: u8 = 0xe1;
+    pub const INFO_ACK: u8 = 0xd2;
+    pub const INFO_ERROR: u8 = 0xe1;
+    pub const BATCH_ACK: u8 = 0xd2;
+    pub const BATCH_ERROR: u8 = 0xe1;
     pub const WORD_SIZE: usize = 4; // Size of a word in bytes
 
     pub fn new(command: SWDCommand) -> Self {
-        ProtocolHandler { command }
+        ProtocolHandler { command, tag: None }
+    }
+
+    /// Send the command with a sequence tag; the bridge echoes it in the reply
+    pub fn with_tag(mut self, tag: u8) -> Self {
+        self.tag = Some(tag);
+        self
     }
 
     pub fn compute_crc(data: &[u8], length: usize) -> u8 {
@@ -61,10 +436,29 @@ impl ProtocolHandler {
         }
         crc
     }
-    pub fn write_frame(&self) -> Vec<u8> {
+    pub fn write_frame(&self) -> Result<Vec<u8>, DebugError> {
         //match based SWDCommand
         let mut data = Vec::new();
         match &self.command {
+            SWDCommand::Info => {
+                // Frame Format: ff f9 00 02 c8 crc f5 e7
+                data = Self::build_frame(&[Self::INFO_COMMAND]);
+            }
+            SWDCommand::Batch { ops, .. } => {
+                // Frame Format: ff f9 len0 len1 c9 op.. crc f5 e7
+                let mut body = vec![Self::BATCH_COMMAND];
+                for op in ops {
+                    op.encode(&mut body);
+                }
+                if body.len() - 1 > Self::MAX_DATA_LENGTH {
+                    return Err(
+                        DebugError::InvalidArgument(
+                            format!("Batch of {} bytes exceeds {} byte limit", body.len() - 1, Self::MAX_DATA_LENGTH)
+                        )
+                    );
+                }
+                data = Self::build_frame(&body);
+            }
             SWDCommand::Halt => {
                 // Frame Format: ff f9 len0 len1 cmd crc f5 e7
                 data.extend_from_slice(&Self::HEADER);
@@ -102,7 +496,11 @@ impl ProtocolHandler {
             SWDCommand::ReadBytes { start_address, length } => {
                 // Frame Format: ff f9 len0 len1 cmd addr0 addr1 addr2 addr3 crc f5 e7
                 if *length > (Self::MAX_DATA_LENGTH as u32) {
-                    panic!("Length exceeds maximum allowed value");
+                    return Err(
+                        DebugError::InvalidArgument(
+                            format!("Read of {} bytes exceeds {} byte limit", length, Self::MAX_DATA_LENGTH)
+                        )
+                    );
                 }
                 data.extend_from_slice(&Self::HEADER);
                 data.push(0x00); // Length (high byte)
@@ -147,8 +545,9 @@ impl ProtocolHandler {
                 data[data_len - 3] = crc; // Replace the placeholder with the computed CRC
             }
             SWDCommand::ReadWords { start_address, length } => {
-                if *length * (Self::WORD_SIZE as u32) * u8::BITS > (Self::MAX_DATA_LENGTH as u32) {
-                    panic!("Length exceeds maximum allowed value");
+                let bytes = length.checked_mul(Self::WORD_SIZE as u32);
+                if bytes.is_none_or(|bytes| bytes > (Self::MAX_DATA_LENGTH as u32)) {
+                    return Err(DebugError::InvalidArgument(format!("Read of {} words is too long", length)));
                 }
                 // Frame Format: ff f9 len0 len1 cmd addr0 addr1 addr2 addr3 crc f5 e7
                 data.extend_from_slice(&Self::HEADER);
@@ -175,7 +574,15 @@ impl ProtocolHandler {
             SWDCommand::Write { write_address: start_address, write_data } => {
                 // check data is is not larger than MAX_DATA_LENGTH
                 if write_data.len() > Self::MAX_DATA_LENGTH {
-                    panic!("Data length exceeds maximum allowed value");
+                    return Err(
+                        DebugError::InvalidArgument(
+                            format!(
+                                "Write of {} bytes exceeds {} byte limit",
+                                write_data.len(),
+                                Self::MAX_DATA_LENGTH
+                            )
+                        )
+                    );
                 }
                 data.extend_from_slice(&Self::HEADER);
                 data.push(0x00); // Length (high byte)
@@ -201,76 +608,366 @@ impl ProtocolHandler {
                 data[data_len - 3] = crc;
             }
         }
+        if let Some(tag) = self.tag {
+            data = Self::build_tagged_frame(tag, &data[Self::HEADER.len() + 2..data.len() - 3]);
+        }
         info!("Generated SWD frame: {:02x?}", data);
+        Ok(data)
+    }
+    /// Opcode of the command this handler frames
+    pub fn opcode(&self) -> u8 {
+        self.command.opcode()
+    }
+
+    /// Number of payload bytes following the ACK in a successful response,
+    /// `None` when the size is only known from the frame
+    fn expected_payload_len(&self) -> Option<usize> {
+        match &self.command {
+            SWDCommand::Info | SWDCommand::Batch { .. } => None,
+            SWDCommand::Halt | SWDCommand::Resume | SWDCommand::Write { .. } => Some(0),
+            SWDCommand::ReadBytes { length, .. } => Some(*length as usize),
+            SWDCommand::ReadWord { .. } => Some(Self::WORD_SIZE),
+            SWDCommand::ReadWords { length, .. } => Some((*length as usize) * Self::WORD_SIZE),
+        }
+    }
+
+    /// Wrap a frame body (command byte onwards, without CRC) in header, length, CRC and footer
+    pub fn build_frame(body: &[u8]) -> Vec<u8> {
+        Self::assemble_frame(None, body)
+    }
+
+    /// Like `build_frame`, but with a sequence tag in front of the body
+    /// Frame Format: ff fa len0 len1 tag cmd .. crc f5 e7
+    pub fn build_tagged_frame(tag: u8, body: &[u8]) -> Vec<u8> {
+        Self::assemble_frame(Some(tag), body)
+    }
+
+    fn assemble_frame(tag: Option<u8>, body: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(body.len() + 8);
+        match tag {
+            Some(tag) => {
+                data.extend_from_slice(&Self::TAGGED_HEADER);
+                let length = (body.len() + 2) as u16; // tag + body + crc
+                data.push((length >> 8) as u8);
+                data.push((length & 0xff) as u8);
+                data.push(tag);
+            }
+            None => {
+                data.extend_from_slice(&Self::HEADER);
+                let length = (body.len() + 1) as u16; // body + crc
+                data.push((length >> 8) as u8);
+                data.push((length & 0xff) as u8);
+            }
+        }
+        data.extend_from_slice(body);
+        data.push(0x00); // Placeholder for CRC
+        data.extend_from_slice(&Self::FOOTER);
+        let data_len = data.len();
+        data[data_len - 3] = Self::compute_crc(&data, data_len);
         data
     }
-    pub fn read_frame(&self, data: &[u8]) -> Result<Vec<u8>, String> {
-        let crc = Self::compute_crc(data, data.len());
-        info!("Computed CRC: {:#02x}", crc);
-        if crc != data[data.len() - 3] {
-            // todo!();
-            // return Err(
-            //     format!("CRC mismatch: expected {:#02x}, got {:#02x}", crc, data[data.len() - 3])
-            // );
+
+    /// Build a response frame as the bridge would send it, echoing the request's tag
+    pub fn encode_response(tag: Option<u8>, command: u8, ack: u8, payload: &[u8]) -> Vec<u8> {
+        let mut body = Vec::with_capacity(payload.len() + 2);
+        body.push(command);
+        body.push(ack);
+        body.extend_from_slice(payload);
+        Self::assemble_frame(tag, &body)
+    }
+
+    /// Sequence tag of a frame, `None` for untagged frames
+    pub fn frame_tag(data: &[u8]) -> Option<u8> {
+        if data.len() > 4 && data[..2] == Self::TAGGED_HEADER { Some(data[4]) } else { None }
+    }
+
+    /// Check header, length, CRC and footer and return the optional tag and
+    /// the frame body (command byte onwards, without CRC)
+    pub fn validate_frame(data: &[u8]) -> Result<(Option<u8>, &[u8]), FrameError> {
+        if data.len() < Self::HEADER.len() + 2 + Self::MIN_BODY_LENGTH + Self::FOOTER.len() {
+            return Err(FrameError::TooShort { length: data.len() });
         }
-        match &self.command {
-            SWDCommand::Halt => {
-                match data[Self::ACK_OFFSET] {
-                    Self::HALT_ACK => Ok(vec![Self::HALT_ACK]),
-                    Self::HALT_ERROR => Err("Halt command failed".to_string()),
-                    _ => Err("Unknown response to Halt command".to_string()),
-                }
+        let tagged = data[..2] == Self::TAGGED_HEADER;
+        if data[..2] != Self::HEADER && !tagged {
+            return Err(FrameError::BadHeader([data[0], data[1]]));
+        }
+        let footer = [data[data.len() - 2], data[data.len() - 1]];
+        if footer != Self::FOOTER {
+            return Err(FrameError::BadFooter(footer));
+        }
+        // Length covers everything between the length field and the footer
+        let declared = (((data[2] as u16) << 8) | (data[3] as u16)) as usize;
+        let actual = data.len() - (Self::HEADER.len() + Self::FOOTER.len() + 2);
+        if declared != actual {
+            return Err(FrameError::LengthMismatch { declared, actual });
+        }
+        let expected = Self::compute_crc(data, data.len());
+        let received = data[data.len() - 3];
+        if expected != received {
+            return Err(FrameError::CrcMismatch { expected, received });
+        }
+        if tagged {
+            Ok((Some(data[4]), &data[Self::HEADER.len() + 3..data.len() - 3]))
+        } else {
+            Ok((None, &data[Self::HEADER.len() + 2..data.len() - 3]))
+        }
+    }
+
+    /// Validate the framing of a raw response and split it into (command, ack, payload).
+    /// Frame Format: ff f9 len0 len1 cmd ack payload.. crc f5 e7
+    pub fn decode_frame(data: &[u8]) -> Result<(u8, u8, &[u8]), FrameError> {
+        let (_, body) = Self::validate_frame(data)?;
+        if body.len() < 2 {
+            return Err(FrameError::TooShort { length: data.len() });
+        }
+        Ok((body[0], body[1], &body[2..]))
+    }
+
+    /// Decode a response frame for the command this handler was built with
+    pub fn read_frame(&self, data: &[u8]) -> Result<Response, FrameError> {
+        let (command, ack, payload) = Self::decode_frame(data)?;
+        let tag = Self::frame_tag(data);
+        if tag != self.tag {
+            return Err(FrameError::TagMismatch { expected: self.tag, received: tag });
+        }
+        info!("Decoded response: cmd {:#02x}, ack {:#02x}, {} payload bytes", command, ack, payload.len());
+        let opcode = self.command.opcode();
+        if command != opcode {
+            return Err(FrameError::UnexpectedCommand { expected: opcode, received: command });
+        }
+        let (ack_code, error_code) = match &self.command {
+            SWDCommand::Info => (Self::INFO_ACK, Self::INFO_ERROR),
+            SWDCommand::Batch { .. } => (Self::BATCH_ACK, Self::BATCH_ERROR),
+            SWDCommand::Halt => (Self::HALT_ACK, Self::HALT_ERROR),
+            SWDCommand::Resume => (Self::RESUME_ACK, Self::RESUME_ERROR),
+            SWDCommand::Write { .. } => (Self::WRITE_ACK, Self::WRITE_ERROR),
+            | SWDCommand::ReadBytes { .. }
+            | SWDCommand::ReadWord { .. }
+            | SWDCommand::ReadWords { .. } => (Self::READ_ACK, Self::READ_ERROR),
+        };
+        if ack == error_code {
+            return Ok(Response::Nack { command, code: ack });
+        }
+        if ack != ack_code {
+            return Err(FrameError::UnknownAck { command, ack });
+        }
+        match self.expected_payload_len() {
+            Some(expected) if payload.len() != expected => {
+                Err(FrameError::PayloadLength { expected, received: payload.len() })
             }
-            SWDCommand::Resume => {
-                match data[Self::ACK_OFFSET] {
-                    Self::RESUME_ACK => Ok(vec![Self::RESUME_ACK]),
-                    Self::RESUME_ERROR => Err("Halt command failed".to_string()),
-                    _ => Err("Unknown response to Halt command".to_string()),
+            Some(0) => Ok(Response::Ack { command }),
+            _ => Ok(Response::Data { command, data: payload.to_vec() }),
+        }
+    }
+}
+
+/// Incremental parser that pulls complete frames out of the raw byte stream
+/// coming from the bridge. Bytes can be pushed in arbitrary pieces; anything
+/// that does not start with `ProtocolHandler::HEADER` is dropped.
+#[derive(Debug, Default)]
+pub struct FrameReassembler {
+    buffer: Vec<u8>,
+}
+
+impl FrameReassembler {
+    pub fn new() -> Self {
+        FrameReassembler { buffer: Vec::new() }
+    }
+
+    /// Append bytes received from the link
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Return the next complete frame, or `None` if more bytes are needed.
+    /// The frame is only checked for header, length and footer; CRC and
+    /// content are left to `ProtocolHandler::read_frame`.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let header_len = ProtocolHandler::HEADER.len();
+        let footer_len = ProtocolHandler::FOOTER.len();
+        loop {
+            // Resync: discard garbage in front of the next header
+            let is_header = |w: &[u8]| w == ProtocolHandler::HEADER || w == ProtocolHandler::TAGGED_HEADER;
+            match self.buffer.windows(header_len).position(is_header) {
+                Some(0) => {}
+                Some(position) => {
+                    info!("Discarding {} stray bytes before frame header", position);
+                    self.buffer.drain(..position);
                 }
-            }
-            SWDCommand::ReadBytes { start_address: _, length: data_length } => {
-                match data[Self::ACK_OFFSET] {
-                    Self::READ_ACK => {
-                        let result =
-                            data[
-                                Self::ACK_OFFSET + 1..Self::ACK_OFFSET + (*data_length as usize) + 1 // Extract the data bytes
-                            ].to_vec();
-                        Ok(result)
+                None => {
+                    // Keep a trailing partial header byte for the next push
+                    let keep = match self.buffer.last() {
+                        Some(&byte) if byte == ProtocolHandler::HEADER[0] => 1,
+                        _ => 0,
+                    };
+                    let discard = self.buffer.len() - keep;
+                    if discard > 0 {
+                        info!("Discarding {} stray bytes without frame header", discard);
+                        self.buffer.drain(..discard);
                     }
-                    Self::READ_ERROR => Err("Read command failed".to_string()),
-                    _ => Err("Unknown response to Read command".to_string()),
+                    return None;
                 }
             }
-            SWDCommand::ReadWord { start_address: _ } => {
-                match data[Self::ACK_OFFSET] {
-                    Self::READ_ACK => {
-                        let result = data[Self::ACK_OFFSET + 1..Self::ACK_OFFSET + 5].to_vec(); // Extract the 4 bytes of the word
-                        Ok(result)
-                    }
-                    Self::READ_ERROR => Err("Read command failed".to_string()),
-                    _ => Err("Unknown response to Read command".to_string()),
-                }
+
+            if self.buffer.len() < header_len + 2 {
+                return None;
             }
-            SWDCommand::ReadWords { start_address: _, length: data_length } => {
-                match data[Self::ACK_OFFSET] {
-                    Self::READ_ACK => {
-                        let result =
-                            data[
-                                Self::ACK_OFFSET + 1..Self::ACK_OFFSET + (*data_length as usize) + 1 // Extract the data bytes
-                            ].to_vec();
-                        Ok(result)
-                    }
-                    Self::READ_ERROR => Err("Read command failed".to_string()),
-                    _ => Err("Unknown response to Read command".to_string()),
-                }
+            let declared = (((self.buffer[2] as u16) << 8) | (self.buffer[3] as u16)) as usize;
+            if !(ProtocolHandler::MIN_BODY_LENGTH..=ProtocolHandler::MAX_BODY_LENGTH).contains(&declared) {
+                // Not a real header, skip it and scan again
+                self.buffer.drain(..1);
+                continue;
             }
-            SWDCommand::Write { write_address: _, write_data: _ } => {
-                match data[Self::ACK_OFFSET] {
-                    Self::WRITE_ACK => { Ok(vec![Self::WRITE_ACK]) }
-                    Self::WRITE_ERROR => Err("Write command failed".to_string()),
-                    _ => Err("Unknown response to Write command".to_string()),
-                }
+
+            let total = header_len + 2 + declared + footer_len;
+            if self.buffer.len() < total {
+                return None;
+            }
+            if self.buffer[total - footer_len..total] != ProtocolHandler::FOOTER {
+                self.buffer.drain(..1);
+                continue;
             }
+            return Some(self.buffer.drain(..total).collect());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_word_response(tag: Option<u8>, value: u32) -> Vec<u8> {
+        ProtocolHandler::encode_response(tag, ProtocolHandler::READ_WORD, ProtocolHandler::READ_ACK, &value.to_be_bytes())
+    }
+
+    #[test]
+    fn reassembles_a_frame_split_across_pushes() {
+        let frame = read_word_response(None, 0x12345678);
+        let mut reassembler = FrameReassembler::new();
+        for byte in &frame[..frame.len() - 1] {
+            reassembler.push(&[*byte]);
+            assert_eq!(reassembler.next_frame(), None);
+        }
+        reassembler.push(&frame[frame.len() - 1..]);
+        assert_eq!(reassembler.next_frame(), Some(frame));
+        assert_eq!(reassembler.next_frame(), None);
+    }
+
+    #[test]
+    fn skips_garbage_before_the_header() {
+        let first = read_word_response(None, 1);
+        let second = read_word_response(Some(7), 2);
+        let mut reassembler = FrameReassembler::new();
+        // A lone 0xff, then a header followed by an impossible length
+        reassembler.push(&[0x00, 0x42, 0xff, 0x13, 0xff, 0xf9, 0xff, 0xff]);
+        reassembler.push(&first);
+        reassembler.push(&[0xaa, 0xbb]);
+        reassembler.push(&second);
+        assert_eq!(reassembler.next_frame(), Some(first));
+        assert_eq!(reassembler.next_frame(), Some(second));
+        assert_eq!(reassembler.next_frame(), None);
+    }
+
+    #[test]
+    fn keeps_a_partial_header_for_the_next_push() {
+        let frame = read_word_response(None, 3);
+        let mut reassembler = FrameReassembler::new();
+        reassembler.push(&[0x01, 0x02, frame[0]]);
+        assert_eq!(reassembler.next_frame(), None);
+        reassembler.push(&frame[1..]);
+        assert_eq!(reassembler.next_frame(), Some(frame));
+    }
+
+    #[test]
+    fn validates_plain_and_tagged_frames() {
+        let plain = ProtocolHandler::build_frame(&[ProtocolHandler::INFO_COMMAND]);
+        assert_eq!(ProtocolHandler::validate_frame(&plain), Ok((None, &[ProtocolHandler::INFO_COMMAND][..])));
+        let tagged = ProtocolHandler::build_tagged_frame(0x5a, &[ProtocolHandler::INFO_COMMAND]);
+        assert_eq!(tagged[..2], ProtocolHandler::TAGGED_HEADER);
+        assert_eq!(ProtocolHandler::validate_frame(&tagged), Ok((Some(0x5a), &[ProtocolHandler::INFO_COMMAND][..])));
+        assert_eq!(ProtocolHandler::frame_tag(&tagged), Some(0x5a));
+        assert_eq!(ProtocolHandler::frame_tag(&plain), None);
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let frame = read_word_response(None, 0xdeadbeef);
+        assert_eq!(ProtocolHandler::validate_frame(&frame[..5]), Err(FrameError::TooShort { length: 5 }));
+
+        let mut bad_crc = frame.clone();
+        let crc_index = bad_crc.len() - 3;
+        bad_crc[crc_index] ^= 0x01;
+        assert!(matches!(ProtocolHandler::validate_frame(&bad_crc), Err(FrameError::CrcMismatch { .. })));
+
+        let mut bad_length = frame.clone();
+        bad_length[3] += 1;
+        assert_eq!(
+            ProtocolHandler::validate_frame(&bad_length),
+            Err(FrameError::LengthMismatch { declared: frame.len() - 5, actual: frame.len() - 6 })
+        );
+
+        let mut bad_header = frame.clone();
+        bad_header[1] = 0x00;
+        assert_eq!(ProtocolHandler::validate_frame(&bad_header), Err(FrameError::BadHeader([0xff, 0x00])));
+
+        let mut bad_footer = frame.clone();
+        let last = bad_footer.len() - 1;
+        bad_footer[last] = 0x00;
+        assert_eq!(ProtocolHandler::validate_frame(&bad_footer), Err(FrameError::BadFooter([0xf5, 0x00])));
+
+        // Valid framing, but a body too short to hold command and ACK
+        let short_body = ProtocolHandler::build_frame(&[ProtocolHandler::READ_WORD]);
+        assert!(matches!(ProtocolHandler::decode_frame(&short_body), Err(FrameError::TooShort { .. })));
+    }
+
+    #[test]
+    fn only_repeatable_batches_with_writes_are_retried() {
+        let read = BatchOp::ReadWord { address: 0xe000edf0 };
+        let write = BatchOp::WriteWord { address: 0xe000edf0, value: 0xa05f0003 };
+        assert!((SWDCommand::Batch { ops: vec![read.clone()], repeatable: false }).is_idempotent());
+        assert!(!(SWDCommand::Batch { ops: vec![write.clone(), read.clone()], repeatable: false }).is_idempotent());
+        assert!((SWDCommand::Batch { ops: vec![write, read], repeatable: true }).is_idempotent());
+        assert!(!(SWDCommand::Write { write_address: 0, write_data: vec![0; 4] }).is_idempotent());
+    }
+
+    #[test]
+    fn decodes_a_response_for_its_command() {
+        let frame = read_word_response(None, 0xcafef00d);
+        assert_eq!(
+            ProtocolHandler::decode_frame(&frame),
+            Ok((ProtocolHandler::READ_WORD, ProtocolHandler::READ_ACK, &[0xca, 0xfe, 0xf0, 0x0d][..]))
+        );
+        let handler = ProtocolHandler::new(SWDCommand::ReadWord { start_address: 0x20000000 });
+        assert_eq!(
+            handler.read_frame(&frame),
+            Ok(Response::Data { command: ProtocolHandler::READ_WORD, data: vec![0xca, 0xfe, 0xf0, 0x0d] })
+        );
+        // The reply to a tagged request must carry the same tag
+        let tagged = handler.with_tag(9);
+        assert_eq!(tagged.read_frame(&frame), Err(FrameError::TagMismatch { expected: Some(9), received: None }));
+        assert!(tagged.read_frame(&read_word_response(Some(9), 1)).is_ok());
+
+        let nack = ProtocolHandler::encode_response(None, ProtocolHandler::READ_WORD, ProtocolHandler::READ_ERROR, &[]);
+        let handler = ProtocolHandler::new(SWDCommand::ReadWord { start_address: 0 });
+        assert_eq!(
+            handler.read_frame(&nack),
+            Ok(Response::Nack { command: ProtocolHandler::READ_WORD, code: ProtocolHandler::READ_ERROR })
+        );
+        let short = ProtocolHandler::encode_response(None, ProtocolHandler::READ_WORD, ProtocolHandler::READ_ACK, &[1, 2]);
+        assert_eq!(handler.read_frame(&short), Err(FrameError::PayloadLength { expected: 4, received: 2 }));
+    }
+
+    #[test]
+    fn limits_reads_to_the_payload() {
+        let read_words = |length| ProtocolHandler::new(SWDCommand::ReadWords { start_address: 0, length }).write_frame();
+        let longest = (ProtocolHandler::MAX_DATA_LENGTH / ProtocolHandler::WORD_SIZE) as u32;
+        let frame = read_words(longest).unwrap();
+        assert_eq!(frame[9..11], (longest as u16).to_be_bytes());
+        for length in [longest + 1, 1 << 30, u32::MAX] {
+            assert!(matches!(read_words(length), Err(DebugError::InvalidArgument(_))));
         }
+        let read_bytes = |length| ProtocolHandler::new(SWDCommand::ReadBytes { start_address: 0, length }).write_frame();
+        assert!(read_bytes(ProtocolHandler::MAX_DATA_LENGTH as u32).is_ok());
+        assert!(matches!(read_bytes(u32::MAX), Err(DebugError::InvalidArgument(_))));
     }
 }
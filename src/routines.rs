@@ -0,0 +1,240 @@
+// Position-independent Thumb code the host loads into SRAM and runs on the
+// target. Only ARMv6-M (Cortex-M0+) instructions are used; the listings were
+// produced with `llvm-mc -triple=thumbv6m-none-eabi`.
+
+/// CRC-32 (ISO-HDLC, the zlib/Ethernet CRC) of R1 bytes starting at R0,
+/// continuing from the CRC passed in R2 (0 for a new CRC). The result is left in
+/// R0 and the core stops on the BKPT at `CRC32_BKPT_OFFSET`. Uses R0-R5, no stack.
+pub const CRC32: [u16; 20] = [
+    0x4b08, // ldr   r3, [pc, #32]   ; reflected polynomial
+    0x43d2, // mvns  r2, r2
+    0x2900, // cmp   r1, #0
+    0xd00a, // beq   done
+    // byte:
+    0x7804, // ldrb  r4, [r0]
+    0x3001, // adds  r0, #1
+    0x4062, // eors  r2, r4
+    0x2508, // movs  r5, #8
+    // bit:
+    0x0852, // lsrs  r2, r2, #1
+    0xd300, // bcc   skip
+    0x405a, // eors  r2, r3
+    // skip:
+    0x3d01, // subs  r5, #1
+    0xd1fa, // bne   bit
+    0x3901, // subs  r1, #1
+    0xd1f4, // bne   byte
+    // done:
+    0x43d0, // mvns  r0, r2
+    0xbe00, // bkpt  #0
+    0xbf00, // nop             ; aligns the literal
+    0x8320, // .word 0xedb88320
+    0xedb8,
+];
+
+/// Offset of the BKPT instruction the CRC-32 routine stops on
+pub const CRC32_BKPT_OFFSET: u32 = 0x20;
+
+/// A routine as the little-endian words written to SRAM
+pub fn words(code: &[u16]) -> Vec<u32> {
+    code.chunks(2)
+        .map(|pair| (pair[0] as u32) | ((pair.get(1).copied().unwrap_or(0xbf00) as u32) << 16))
+        .collect()
+}
+
+/// Flash algorithm for the MSPM0 FLASHCTL with the CMSIS-Pack calling convention:
+/// `Init(address, clock, function)`, `UnInit(function)`, `EraseSector(address)`,
+/// `EraseChip()` and `ProgramPage(address, size, buffer)`, each returning 0 on
+/// success or the failing STATCMD. `ProgramPage` takes a multiple of 8 bytes and
+/// leaves flash words of all 0xFF erased. Needs a stack of 20 bytes.
+pub const MSPM0_FLASH_LOADER: [u16; 86] = [
+    // Init, UnInit:
+    0x2000, // movs  r0, #0
+    0x4770, // bx    lr
+    // EraseSector:
+    0xb5f0, // push  {r4, r5, r6, r7, lr}
+    0x4b25, // ldr   r3, [pc, #148]  ; CMDEXEC
+    0xf000, // bl    unprotect
+    0xf82f,
+    0x2442, // movs  r4, #66         ; ERASE | SIZE_SECTOR
+    0x605c, // str   r4, [r3, #4]    ; CMDTYPE
+    0xf000, // bl    execute
+    0xf839,
+    0x0020, // movs  r0, r4
+    0xbdf0, // pop   {r4, r5, r6, r7, pc}
+    // EraseChip:
+    0xb5f0, // push  {r4, r5, r6, r7, lr}
+    0x4b20, // ldr   r3, [pc, #128]  ; CMDEXEC
+    0x4e20, // ldr   r6, [pc, #128]  ; CMDWEPROTA
+    0x2400, // movs  r4, #0
+    0x6034, // str   r4, [r6]        ; unprotect every sector
+    0x6074, // str   r4, [r6, #4]
+    0x2452, // movs  r4, #82         ; ERASE | SIZE_BANK
+    0x605c, // str   r4, [r3, #4]    ; CMDTYPE
+    0x2000, // movs  r0, #0          ; FLASH_BASE
+    0xf000, // bl    execute
+    0xf82c,
+    0x0020, // movs  r0, r4
+    0xbdf0, // pop   {r4, r5, r6, r7, pc}
+    // ProgramPage:
+    0xb5f0, // push  {r4, r5, r6, r7, lr}
+    0x4b19, // ldr   r3, [pc, #100]  ; CMDEXEC
+    0x2900, // cmp   r1, #0
+    0xd014, // beq   done
+    // word:
+    0x6814, // ldr   r4, [r2]
+    0x6855, // ldr   r5, [r2, #4]
+    0x631c, // str   r4, [r3, #48]   ; CMDDATA0
+    0x635d, // str   r5, [r3, #52]   ; CMDDATA1
+    0x402c, // ands  r4, r5
+    0x3401, // adds  r4, #1
+    0xd009, // beq   next            ; leave words of all 0xFF erased
+    0xf000, // bl    unprotect
+    0xf80f,
+    0x2401, // movs  r4, #1          ; PROGRAM | SIZE_ONEWORD
+    0x605c, // str   r4, [r3, #4]    ; CMDTYPE
+    0x4c14, // ldr   r4, [pc, #80]   ; 0x1ff
+    0x625c, // str   r4, [r3, #36]   ; CMDBYTEN
+    0xf000, // bl    execute
+    0xf817,
+    0x2c00, // cmp   r4, #0
+    0xd104, // bne   return
+    // next:
+    0x3008, // adds  r0, #8
+    0x3208, // adds  r2, #8
+    0x3908, // subs  r1, #8
+    0xd8ea, // bhi   word
+    // done:
+    0x2400, // movs  r4, #0
+    // return:
+    0x0020, // movs  r0, r4
+    0xbdf0, // pop   {r4, r5, r6, r7, pc}
+    // unprotect:
+    0x0a84, // lsrs  r4, r0, #10     ; sector number
+    0x2501, // movs  r5, #1
+    0x4e0c, // ldr   r6, [pc, #48]   ; CMDWEPROTA
+    0x2c20, // cmp   r4, #32
+    0xd203, // bhs   sector_32_up
+    0x40a5, // lsls  r5, r4
+    0x43ed, // mvns  r5, r5
+    0x6035, // str   r5, [r6]        ; CMDWEPROTA: one bit per sector
+    0x4770, // bx    lr
+    // sector_32_up:
+    0x08e4, // lsrs  r4, r4, #3
+    0x40a5, // lsls  r5, r4
+    0x43ed, // mvns  r5, r5
+    0x6075, // str   r5, [r6, #4]    ; CMDWEPROTB: one bit per 8 sectors
+    0x4770, // bx    lr
+    // execute:
+    0x6218, // str   r0, [r3, #32]   ; CMDADDR
+    0x2401, // movs  r4, #1          ; EXECUTE
+    0x601c, // str   r4, [r3]        ; CMDEXEC
+    0x4e06, // ldr   r6, [pc, #24]   ; STATCMD
+    // wait:
+    0x6834, // ldr   r4, [r6]
+    0x0865, // lsrs  r5, r4, #1      ; DONE
+    0xd3fc, // blo   wait
+    0x08a5, // lsrs  r5, r4, #2      ; PASS
+    0xd300, // blo   finished
+    0x2400, // movs  r4, #0          ; 0 on success, STATCMD on failure
+    // finished:
+    0x4770, // bx    lr
+    0xe100, // .word 0x400ce100 ; CMDEXEC
+    0x400c,
+    0xe1d0, // .word 0x400ce1d0 ; CMDWEPROTA
+    0x400c,
+    0x01ff, // .word 0x000001ff ; CMDBYTEN for a flash word with ECC
+    0x0000,
+    0xe3d0, // .word 0x400ce3d0 ; STATCMD
+    0x400c,
+];
+
+/// Offsets of the `MSPM0_FLASH_LOADER` functions
+pub const MSPM0_FLASH_INIT: u32 = 0x00;
+pub const MSPM0_FLASH_UNINIT: u32 = 0x00;
+pub const MSPM0_FLASH_ERASE_SECTOR: u32 = 0x04;
+pub const MSPM0_FLASH_PROGRAM_PAGE: u32 = 0x32;
+/// `flash_mass_erase` drives FLASHCTL itself, so only the tests call `EraseChip`
+#[cfg(test)]
+pub const MSPM0_FLASH_ERASE_CHIP: u32 = 0x18;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Word an `ldr rt, [pc, #imm8 * 4]` at halfword `index` of `code` loads
+    fn literal(code: &[u16], index: usize) -> u32 {
+        let address = ((index * 2 + 4) & !3) + ((code[index] & 0xff) as usize) * 4;
+        words(code)[address / 4]
+    }
+
+    #[test]
+    fn crc32_layout() {
+        assert_eq!(CRC32[0] >> 11, 0b01001);
+        assert_eq!(literal(&CRC32, 0), 0xedb88320);
+        assert_eq!(CRC32[(CRC32_BKPT_OFFSET / 2) as usize], 0xbe00);
+        assert_eq!(words(&CRC32[..3]), [0x43d24b08, 0xbf002900]);
+    }
+
+    #[test]
+    fn mspm0_flash_loader_layout() {
+        use crate::mspm0::*;
+        let loader = &MSPM0_FLASH_LOADER;
+        assert_eq!(loader[(MSPM0_FLASH_INIT / 2) as usize], 0x2000);
+        for function in [MSPM0_FLASH_ERASE_SECTOR, MSPM0_FLASH_ERASE_CHIP, MSPM0_FLASH_PROGRAM_PAGE] {
+            assert_eq!(loader[(function / 2) as usize], 0xb5f0);
+        }
+
+        // The literal pool (the last four words) holds FLASHCTL addresses and the ECC byte enable
+        let code = &loader[..loader.len() - 8];
+        let literals: Vec<u32> = (0..code.len())
+            .filter(|index| code[*index] >> 11 == 0b01001)
+            .map(|index| literal(loader, index))
+            .collect();
+        let pool = [FLASHCTL_CMDEXEC_ADDR, FLASHCTL_CMDWEPROTA_ADDR, FLASHCTL_CMDBYTEN_WORD_ECC, FLASHCTL_STATCMD_ADDR];
+        assert!(literals.iter().all(|value| pool.contains(value)));
+        assert!(pool.iter().all(|value| literals.contains(value)));
+
+        // Stores through R3 (CMDEXEC) and R6 (CMDWEPROTA) hit the command registers
+        let stores: Vec<(u16, u32)> = code
+            .iter()
+            .filter(|instruction| *instruction >> 11 == 0b01100)
+            .map(|instruction| ((instruction >> 3) & 7, (((instruction >> 6) & 0x1f) as u32) * 4))
+            .collect();
+        for (base, offset) in stores {
+            let register = match base {
+                3 => FLASHCTL_CMDEXEC_ADDR + offset,
+                6 => FLASHCTL_CMDWEPROTA_ADDR + offset,
+                _ => panic!("store through r{}", base),
+            };
+            assert!(
+                [
+                    FLASHCTL_CMDEXEC_ADDR,
+                    FLASHCTL_CMDTYPE_ADDR,
+                    FLASHCTL_CMDADDR_ADDR,
+                    FLASHCTL_CMDBYTEN_ADDR,
+                    FLASHCTL_CMDDATA0_ADDR,
+                    FLASHCTL_CMDDATA1_ADDR,
+                    FLASHCTL_CMDWEPROTA_ADDR,
+                    FLASHCTL_CMDWEPROTB_ADDR,
+                ].contains(&register),
+                "store to 0x{:08x}",
+                register
+            );
+        }
+
+        // Immediates written to CMDTYPE
+        let immediates: Vec<u32> = code
+            .iter()
+            .filter(|instruction| *instruction >> 8 == 0x24)
+            .map(|instruction| (instruction & 0xff) as u32)
+            .collect();
+        for command in [
+            FLASHCTL_CMDTYPE_ERASE | FLASHCTL_CMDTYPE_SIZE_SECTOR,
+            FLASHCTL_CMDTYPE_ERASE | FLASHCTL_CMDTYPE_SIZE_BANK,
+            FLASHCTL_CMDTYPE_PROGRAM | FLASHCTL_CMDTYPE_SIZE_ONEWORD,
+        ] {
+            assert!(immediates.contains(&command), "no movs r4, #0x{:02x}", command);
+        }
+    }
+}
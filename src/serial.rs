@@ -3,6 +3,7 @@ use rusb::{ Context, UsbContext };
 use std::io::{ Write, Read };
 use std::time::Duration;
 use tracing::info;
+use crate::error::DebugError;
 
 // Define the target PID as a macro - change this to your specific device PID
 macro_rules! TARGET_PID {
@@ -11,12 +12,12 @@ macro_rules! TARGET_PID {
     };
 }
 
-pub fn connect_to_device() -> Result<Box<dyn SerialPort>, Box<dyn std::error::Error>> {
+pub fn connect_to_device() -> Result<Box<dyn SerialPort>, DebugError> {
     let target_pid = TARGET_PID!();
 
     // First, check if a USB device with the target PID is connected
     if !is_device_connected(target_pid)? {
-        return Err(format!("Device with PID 0x{:04X} not found", target_pid).into());
+        return Err(DebugError::NotConnected);
     }
 
     info!("Device with PID 0x{:04X} found", target_pid);
@@ -25,7 +26,7 @@ pub fn connect_to_device() -> Result<Box<dyn SerialPort>, Box<dyn std::error::Er
     let ports = serialport::available_ports()?;
 
     if ports.is_empty() {
-        return Err("No serial ports found".into());
+        return Err(DebugError::NotConnected);
     }
 
     // Try to find a serial port that might correspond to our USB device
@@ -61,7 +62,7 @@ pub fn connect_to_device() -> Result<Box<dyn SerialPort>, Box<dyn std::error::Er
 pub fn write_to_device(
     port: &mut Box<dyn SerialPort>,
     data: &[u8]
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), DebugError> {
     // Send the data
     port.write_all(data)?;
     port.flush()?;
@@ -75,7 +76,7 @@ pub fn read_from_device(
     port: &mut Box<dyn SerialPort>,
     buffer_size: usize,
     timeout_ms: u64
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+) -> Result<Vec<u8>, DebugError> {
     // Set read timeout
     port.set_timeout(Duration::from_millis(timeout_ms))?;
 
@@ -110,7 +111,7 @@ pub fn write_and_read(
     data: &[u8],
     read_buffer_size: usize,
     read_timeout_ms: u64
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+) -> Result<Vec<u8>, DebugError> {
     // Send data first
     write_to_device(port, data)?;
 
@@ -121,7 +122,7 @@ pub fn write_and_read(
     read_from_device(port, read_buffer_size, read_timeout_ms)
 }
 
-pub fn is_device_connected(target_pid: u16) -> Result<bool, Box<dyn std::error::Error>> {
+pub fn is_device_connected(target_pid: u16) -> Result<bool, DebugError> {
     let context = Context::new()?;
 
     for device in context.devices()?.iter() {
@@ -141,14 +142,14 @@ pub fn is_device_connected(target_pid: u16) -> Result<bool, Box<dyn std::error::
 }
 
 // Helper function to check if device is connected using the macro
-pub fn check_target_device_connected() -> Result<bool, Box<dyn std::error::Error>> {
+pub fn check_target_device_connected() -> Result<bool, DebugError> {
     is_device_connected(TARGET_PID!())
 }
 
 // Convenience function to send the specific byte sequence
 pub fn send_debug_sequence(
     port: &mut Box<dyn SerialPort>
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), DebugError> {
     let debug_data: [u8; 8] = [0xff, 0xf9, 0x00, 0x02, 0xc2, 0x00, 0xf5, 0xe7];
     write_to_device(port, &debug_data)
 }
@@ -156,7 +157,7 @@ pub fn send_debug_sequence(
 // Convenience function to send the specific byte sequence and read response
 pub fn send_debug_sequence_and_read(
     port: &mut Box<dyn SerialPort>
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+) -> Result<Vec<u8>, DebugError> {
     let debug_data: [u8; 8] = [0xff, 0xf9, 0x00, 0x02, 0xc2, 0x00, 0xf5, 0xe7];
     write_and_read(port, &debug_data, 256, 2000) // 256 byte buffer, 2 second timeout
 }
@@ -0,0 +1,1714 @@
+use std::collections::{ HashMap, VecDeque };
+use std::time::Duration;
+use tracing::info;
+use crate::cortex_m::{
+    self,
+    AIRCR_ADDR,
+    AIRCR_SYSRESETREQ,
+    AIRCR_VECTKEY,
+    BP_COMP0_ADDR,
+    BP_CTRL_ADDR,
+    BP_CTRL_ENABLE,
+    C_DEBUGEN,
+    C_HALT,
+    C_MASKINTS,
+    C_STEP,
+    DBGKEY,
+    DCRDR_ADDR,
+    DCRSR_ADDR,
+    DCRSR_REGWNR,
+    DEMCR_ADDR,
+    DEMCR_VC_CORERESET,
+    DFSR_ADDR,
+    DFSR_BKPT,
+    DFSR_HALTED,
+    DFSR_VCATCH,
+    DHCSR_ADDR,
+    DWT_CTRL_ADDR,
+    DWT_FUNCTION_MATCHED,
+    S_HALT,
+    S_REGRDY,
+    S_RESET_ST,
+};
+use crate::error::DebugError;
+use crate::mspm0::{
+    self,
+    FLASHCTL_CMDADDR_ADDR,
+    FLASHCTL_CMDBYTEN_ADDR,
+    FLASHCTL_CMDDATA0_ADDR,
+    FLASHCTL_CMDDATA1_ADDR,
+    FLASHCTL_CMDEXEC_ADDR,
+    FLASHCTL_CMDEXEC_EXECUTE,
+    FLASHCTL_CMDTYPE_ADDR,
+    FLASHCTL_CMDTYPE_ERASE,
+    FLASHCTL_CMDTYPE_PROGRAM,
+    FLASHCTL_CMDTYPE_SIZE_BANK,
+    FLASHCTL_CMDTYPE_SIZE_ONEWORD,
+    FLASHCTL_CMDTYPE_SIZE_SECTOR,
+    FLASHCTL_CMDWEPROTA_ADDR,
+    FLASHCTL_CMDWEPROTB_ADDR,
+    FLASHCTL_STATCMD_ADDR,
+    FLASHCTL_STATCMD_DONE,
+    FLASHCTL_STATCMD_FAIL_ILLADDR,
+    FLASHCTL_STATCMD_FAIL_MODE,
+    FLASHCTL_STATCMD_FAIL_WEPROT,
+    FLASHCTL_STATCMD_PASS,
+    FLASH_BASE,
+    FLASH_SECTOR_SIZE,
+    FLASH_SIZE,
+    SRAM_BASE,
+    SRAM_SIZE,
+    SYSCTL_RESETCMD_ADDR,
+    SYSCTL_RESETCMD_GO,
+    SYSCTL_RESETCMD_KEY,
+    SYSCTL_RESETLEVEL_ADDR,
+};
+use crate::protocol::{ BatchOp, BatchResult, BridgeInfo, FrameReassembler, ProtocolHandler };
+use crate::transport::{ Transport, DEFAULT_TIMEOUT };
+
+// System Control Space, emulated as plain memory apart from the debug registers
+pub const SCS_BASE: u32 = 0xe000e000;
+pub const SCS_SIZE: u32 = 0x00001000;
+
+/// Instruction comparators in the emulated BPU (as on the MSPM0G3507)
+pub const BP_COMPARATORS: usize = 4;
+/// DWT comparators in the emulated core (as on the MSPM0G3507)
+pub const DWT_COMPARATORS: usize = 2;
+const DCRSR_REGSEL_MASK: u32 = 0x1f;
+/// Instructions the core executes before each command frame is answered
+const RUN_SLICE: u32 = 100_000;
+
+// DCRSR register numbers the instruction interpreter uses
+const REG_SP: usize = 0x0d;
+const REG_LR: usize = 0x0e;
+const REG_PC: usize = 0x0f;
+const REG_XPSR: usize = 0x10;
+
+// Condition flags in xPSR
+const XPSR_N: u32 = 1 << 31;
+const XPSR_Z: u32 = 1 << 30;
+const XPSR_C: u32 = 1 << 29;
+const XPSR_V: u32 = 1 << 28;
+
+const PAGE_SIZE: usize = 1024;
+
+struct Region {
+    base: u32,
+    size: u32,
+    fill: u8,
+}
+
+/// Sparse byte-addressable memory; pages are only allocated when written
+pub struct MemoryMap {
+    regions: Vec<Region>,
+    pages: HashMap<u32, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl MemoryMap {
+    /// Flash (erased to 0xff), SRAM and the System Control Space
+    pub fn mspm0g3507() -> Self {
+        MemoryMap {
+            regions: vec![
+                Region { base: FLASH_BASE, size: FLASH_SIZE, fill: 0xff },
+                Region { base: SRAM_BASE, size: SRAM_SIZE, fill: 0x00 },
+                Region { base: SCS_BASE, size: SCS_SIZE, fill: 0x00 }
+            ],
+            pages: HashMap::new(),
+        }
+    }
+
+    fn region(&self, address: u32) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|r| address >= r.base && address - r.base < r.size)
+    }
+
+    pub fn read_u8(&self, address: u32) -> Option<u8> {
+        let region = self.region(address)?;
+        let page = address & !((PAGE_SIZE as u32) - 1);
+        Some(match self.pages.get(&page) {
+            Some(data) => data[(address - page) as usize],
+            None => region.fill,
+        })
+    }
+
+    pub fn write_u8(&mut self, address: u32, value: u8) -> bool {
+        let fill = match self.region(address) {
+            Some(region) => region.fill,
+            None => {
+                return false;
+            }
+        };
+        let page = address & !((PAGE_SIZE as u32) - 1);
+        let data = self.pages.entry(page).or_insert_with(|| Box::new([fill; PAGE_SIZE]));
+        data[(address - page) as usize] = value;
+        true
+    }
+
+    pub fn read_u32(&self, address: u32) -> Option<u32> {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_u8(address.wrapping_add(i as u32))?;
+        }
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    pub fn write_u32(&mut self, address: u32, value: u32) -> bool {
+        if (0..4).any(|i| self.region(address.wrapping_add(i)).is_none()) {
+            return false;
+        }
+        for (i, byte) in value.to_le_bytes().iter().enumerate() {
+            self.write_u8(address.wrapping_add(i as u32), *byte);
+        }
+        true
+    }
+
+    /// Copy an image into memory, e.g. to preload flash
+    pub fn load(&mut self, address: u32, data: &[u8]) -> bool {
+        data.iter()
+            .enumerate()
+            .all(|(i, byte)| self.write_u8(address.wrapping_add(i as u32), *byte))
+    }
+}
+
+/// Cortex-M0+ core state as seen through the debug registers
+pub struct SimulatedCore {
+    /// R0-R15, xPSR, MSP, PSP, reserved, CONTROL/PRIMASK (DCRSR REGSEL order)
+    pub registers: [u32; 0x15],
+    pub halted: bool,
+    dhcsr_control: u32,
+    dcrdr: u32,
+    demcr: u32,
+    /// Debug events since DFSR was last cleared
+    pub dfsr: u32,
+    /// S_RESET_ST: a reset happened since DHCSR was last read
+    reset_seen: bool,
+    /// The core reached an instruction the simulator cannot execute; it looks
+    /// running but makes no progress until it is halted, resumed or reset
+    pub stalled: bool,
+}
+
+impl SimulatedCore {
+    pub fn new() -> Self {
+        let mut core = SimulatedCore {
+            registers: [0; 0x15],
+            halted: false,
+            dhcsr_control: 0,
+            dcrdr: 0,
+            demcr: 0,
+            dfsr: 0,
+            reset_seen: false,
+            stalled: false,
+        };
+        core.registers[0x10] = 0x01000000; // Thumb bit
+        core
+    }
+
+    /// Load SP and PC from the vector table like a power-on reset
+    pub fn reset(&mut self, memory: &MemoryMap) {
+        let sp = memory.read_u32(FLASH_BASE).unwrap_or(0);
+        let pc = memory.read_u32(FLASH_BASE + 4).unwrap_or(0);
+        self.registers = [0; 0x15];
+        self.registers[0x0d] = sp;
+        self.registers[0x11] = sp;
+        self.registers[0x0f] = pc & !1;
+        self.registers[0x10] = 0x01000000;
+        self.registers[0x0e] = 0xffffffff;
+        self.stalled = false;
+    }
+
+    /// Reset requested by the debugger (AIRCR or SYSCTL): restart from the vector
+    /// table, stopping on the first instruction when DEMCR.VC_CORERESET is set
+    fn requested_reset(&mut self, memory: &MemoryMap) {
+        self.reset(memory);
+        self.reset_seen = true;
+        if (self.demcr & DEMCR_VC_CORERESET) != 0 && (self.dhcsr_control & C_DEBUGEN) != 0 {
+            self.dhcsr_control |= C_HALT;
+            self.dfsr |= DFSR_VCATCH;
+            self.halted = true;
+        } else {
+            self.dhcsr_control &= !C_HALT;
+            self.halted = false;
+        }
+    }
+
+    pub fn halt(&mut self) {
+        self.dhcsr_control |= C_DEBUGEN | C_HALT;
+        if !self.halted {
+            self.dfsr |= DFSR_HALTED;
+        }
+        self.halted = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.dhcsr_control &= !C_HALT;
+        self.halted = false;
+        self.stalled = false;
+    }
+
+    /// Stop on a BKPT instruction at `pc`, as the core does while C_DEBUGEN is set
+    fn bkpt(&mut self, pc: u32) {
+        self.registers[0x0f] = pc;
+        self.dhcsr_control |= C_HALT;
+        self.dfsr |= DFSR_BKPT;
+        self.halted = true;
+    }
+
+    fn read_dhcsr(&mut self) -> u32 {
+        let mut value = self.dhcsr_control | S_REGRDY;
+        if self.halted {
+            value |= S_HALT;
+        }
+        if self.reset_seen {
+            // S_RESET_ST clears on read
+            value |= S_RESET_ST;
+            self.reset_seen = false;
+        }
+        value
+    }
+
+    /// Returns true when the write asks for a single step, which the caller executes
+    fn write_dhcsr(&mut self, value: u32) -> bool {
+        if (value & 0xffff0000) != DBGKEY {
+            info!("Simulator: DHCSR write without DBGKEY ignored");
+            return false;
+        }
+        self.dhcsr_control = value & (C_DEBUGEN | C_HALT | C_STEP | C_MASKINTS);
+        self.stalled = false;
+        if (value & C_DEBUGEN) == 0 {
+            self.halted = false;
+        } else if (value & C_HALT) != 0 {
+            if !self.halted {
+                self.dfsr |= DFSR_HALTED;
+            }
+            self.halted = true;
+        } else if self.halted && (value & C_STEP) != 0 {
+            self.dfsr |= DFSR_HALTED;
+            return true;
+        } else {
+            self.halted = false;
+        }
+        false
+    }
+
+    fn write_dcrsr(&mut self, value: u32) {
+        let index = (value & DCRSR_REGSEL_MASK) as usize;
+        if index >= self.registers.len() {
+            return;
+        }
+        if (value & DCRSR_REGWNR) != 0 {
+            self.registers[index] = self.dcrdr;
+        } else {
+            self.dcrdr = self.registers[index];
+        }
+    }
+}
+
+impl Default for SimulatedCore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FLASHCTL registers; commands complete as soon as CMDEXEC is written
+pub struct FlashController {
+    command_type: u32,
+    address: u32,
+    byte_enable: u32,
+    data: [u32; 2],
+    protect_a: u32,
+    protect_b: u32,
+    pub status: u32,
+}
+
+impl FlashController {
+    pub fn new() -> Self {
+        FlashController {
+            command_type: 0,
+            address: 0,
+            byte_enable: 0,
+            data: [u32::MAX; 2],
+            protect_a: u32::MAX,
+            protect_b: u32::MAX,
+            status: 0,
+        }
+    }
+
+    fn read(&self, address: u32) -> Option<u32> {
+        match address {
+            FLASHCTL_CMDTYPE_ADDR => Some(self.command_type),
+            FLASHCTL_CMDADDR_ADDR => Some(self.address),
+            FLASHCTL_CMDBYTEN_ADDR => Some(self.byte_enable),
+            FLASHCTL_CMDDATA0_ADDR => Some(self.data[0]),
+            FLASHCTL_CMDDATA1_ADDR => Some(self.data[1]),
+            FLASHCTL_CMDWEPROTA_ADDR => Some(self.protect_a),
+            FLASHCTL_CMDWEPROTB_ADDR => Some(self.protect_b),
+            FLASHCTL_STATCMD_ADDR => Some(self.status),
+            FLASHCTL_CMDEXEC_ADDR => Some(0),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u32, value: u32, memory: &mut MemoryMap) -> bool {
+        match address {
+            FLASHCTL_CMDTYPE_ADDR => {
+                self.command_type = value;
+            }
+            FLASHCTL_CMDADDR_ADDR => {
+                self.address = value;
+            }
+            FLASHCTL_CMDBYTEN_ADDR => {
+                self.byte_enable = value;
+            }
+            FLASHCTL_CMDDATA0_ADDR => {
+                self.data[0] = value;
+            }
+            FLASHCTL_CMDDATA1_ADDR => {
+                self.data[1] = value;
+            }
+            FLASHCTL_CMDWEPROTA_ADDR => {
+                self.protect_a = value;
+            }
+            FLASHCTL_CMDWEPROTB_ADDR => {
+                self.protect_b = value;
+            }
+            FLASHCTL_CMDEXEC_ADDR => {
+                if (value & FLASHCTL_CMDEXEC_EXECUTE) != 0 {
+                    self.status = FLASHCTL_STATCMD_DONE | self.execute(memory);
+                    // Every sector is protected again after a command
+                    self.protect_a = u32::MAX;
+                    self.protect_b = u32::MAX;
+                }
+            }
+            _ => {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn protected(&self, address: u32) -> bool {
+        let sector = mspm0::flash_sector(address);
+        if sector < 32 {
+            (self.protect_a & (1 << sector)) != 0
+        } else {
+            (self.protect_b & (1 << (sector / 8))) != 0
+        }
+    }
+
+    /// Run the command in CMDTYPE; returns PASS or the failure bits
+    fn execute(&mut self, memory: &mut MemoryMap) -> u32 {
+        let in_flash = self.address < FLASH_BASE + FLASH_SIZE;
+        match self.command_type {
+            // Program one flash word; NOR flash can only clear bits
+            kind if kind == (FLASHCTL_CMDTYPE_PROGRAM | FLASHCTL_CMDTYPE_SIZE_ONEWORD) => {
+                if !in_flash || !self.address.is_multiple_of(8) {
+                    return FLASHCTL_STATCMD_FAIL_ILLADDR;
+                }
+                if self.protected(self.address) {
+                    return FLASHCTL_STATCMD_FAIL_WEPROT;
+                }
+                for (i, value) in self.data.iter().enumerate() {
+                    let address = self.address + (i as u32) * 4;
+                    let current = memory.read_u32(address).unwrap_or(u32::MAX);
+                    memory.write_u32(address, current & value);
+                }
+                FLASHCTL_STATCMD_PASS
+            }
+            // Sector erase
+            kind if kind == (FLASHCTL_CMDTYPE_ERASE | FLASHCTL_CMDTYPE_SIZE_SECTOR) => {
+                if !in_flash {
+                    return FLASHCTL_STATCMD_FAIL_ILLADDR;
+                }
+                if self.protected(self.address) {
+                    return FLASHCTL_STATCMD_FAIL_WEPROT;
+                }
+                let sector = self.address & !(FLASH_SECTOR_SIZE - 1);
+                memory.load(sector, &[0xff; FLASH_SECTOR_SIZE as usize]);
+                FLASHCTL_STATCMD_PASS
+            }
+            // Bank erase: every sector must be unprotected
+            kind if kind == (FLASHCTL_CMDTYPE_ERASE | FLASHCTL_CMDTYPE_SIZE_BANK) => {
+                if self.protect_a != 0 || self.protect_b != 0 {
+                    return FLASHCTL_STATCMD_FAIL_WEPROT;
+                }
+                memory.load(FLASH_BASE, &vec![0xff; FLASH_SIZE as usize]);
+                FLASHCTL_STATCMD_PASS
+            }
+            _ => FLASHCTL_STATCMD_FAIL_MODE,
+        }
+    }
+}
+
+impl Default for FlashController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Software ESP32 bridge: answers protocol frames against an emulated MSPM0
+pub struct BridgeSimulator {
+    pub memory: MemoryMap,
+    pub core: SimulatedCore,
+    /// What the simulated firmware reports for INFO
+    pub info: BridgeInfo,
+    bp_ctrl: u32,
+    sysctl_resetlevel: u32,
+    /// BPU comparators, matched before every instruction while BP_CTRL is enabled
+    pub bp_comp: [u32; BP_COMPARATORS],
+    /// COMP, MASK and FUNCTION of each DWT comparator
+    pub dwt: [[u32; 3]; DWT_COMPARATORS],
+    pub flashctl: FlashController,
+}
+
+impl BridgeSimulator {
+    pub fn new() -> Self {
+        let memory = MemoryMap::mspm0g3507();
+        let mut core = SimulatedCore::new();
+        core.reset(&memory);
+        let mut info = BridgeInfo::legacy();
+        info.version = (BridgeInfo::PROTOCOL_MAJOR, 0, 0);
+        info.swd_clock_hz = 1_000_000;
+        info.opcodes.push(ProtocolHandler::INFO_COMMAND);
+        info.opcodes.push(ProtocolHandler::BATCH_COMMAND);
+        info.tagged_frames = true;
+        BridgeSimulator { memory, core, info, bp_ctrl: 0, sysctl_resetlevel: 0, bp_comp: [0; BP_COMPARATORS], dwt: [[0; 3]; DWT_COMPARATORS], flashctl: FlashController::new() }
+    }
+
+    /// Answer one command frame. Frames that fail validation are dropped
+    /// like the real bridge does, so the host sees a timeout.
+    pub fn handle_frame(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let (tag, body) = match ProtocolHandler::validate_frame(frame) {
+            Ok((tag, body)) if !body.is_empty() => (tag, body),
+            Ok(_) => {
+                return None;
+            }
+            Err(e) => {
+                info!("Simulator: dropping invalid frame: {}", e);
+                return None;
+            }
+        };
+        // The core runs while the host is busy on the link
+        self.run();
+        let command = body[0];
+        let (ack, payload) = self.execute(command, &body[1..]);
+        Some(ProtocolHandler::encode_response(tag, command, ack, &payload))
+    }
+
+    fn execute(&mut self, command: u8, args: &[u8]) -> (u8, Vec<u8>) {
+        if !self.info.supports(command) {
+            info!("Simulator: unsupported command {:#04x}", command);
+            return (ProtocolHandler::HALT_ERROR, Vec::new());
+        }
+        match command {
+            ProtocolHandler::INFO_COMMAND => (ProtocolHandler::INFO_ACK, self.info.encode()),
+            ProtocolHandler::HALT_COMMAND => {
+                self.core.halt();
+                (ProtocolHandler::HALT_ACK, Vec::new())
+            }
+            ProtocolHandler::RESUME_COMMAND => {
+                self.core.resume();
+                (ProtocolHandler::RESUME_ACK, Vec::new())
+            }
+            ProtocolHandler::READ_WORD => {
+                match Self::address(args).and_then(|address| self.read_word(address)) {
+                    Some(value) => (ProtocolHandler::READ_ACK, value.to_le_bytes().to_vec()),
+                    None => (ProtocolHandler::READ_ERROR, Vec::new()),
+                }
+            }
+            ProtocolHandler::READ_BYTES_COMMAND => {
+                let data = Self::address_and_count(args).and_then(|(address, length)| {
+                    (0..length)
+                        .map(|i| self.read_byte(address.wrapping_add(i)))
+                        .collect::<Option<Vec<u8>>>()
+                });
+                match data {
+                    Some(data) => (ProtocolHandler::READ_ACK, data),
+                    None => (ProtocolHandler::READ_ERROR, Vec::new()),
+                }
+            }
+            ProtocolHandler::READ_WORDS_COMMAND => {
+                let data = Self::address_and_count(args).and_then(|(address, count)| {
+                    let mut data = Vec::with_capacity((count as usize) * 4);
+                    for i in 0..count {
+                        data.extend_from_slice(
+                            &self.read_word(address.wrapping_add(i * 4))?.to_le_bytes()
+                        );
+                    }
+                    Some(data)
+                });
+                match data {
+                    Some(data) => (ProtocolHandler::READ_ACK, data),
+                    None => (ProtocolHandler::READ_ERROR, Vec::new()),
+                }
+            }
+            ProtocolHandler::WRITE_COMMAND => {
+                // Payload is a sequence of big-endian words, as sent by SerialLoader::write_word
+                let ok = match Self::address(args) {
+                    Some(address) if (args.len() - 4).is_multiple_of(4) =>
+                        args[4..]
+                            .chunks(4)
+                            .enumerate()
+                            .all(|(i, word)| {
+                                let value = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+                                self.write_word(address.wrapping_add((i as u32) * 4), value)
+                            }),
+                    _ => false,
+                };
+                if ok {
+                    (ProtocolHandler::WRITE_ACK, Vec::new())
+                } else {
+                    (ProtocolHandler::WRITE_ERROR, Vec::new())
+                }
+            }
+            ProtocolHandler::BATCH_COMMAND => {
+                match BatchOp::decode_all(args) {
+                    Some(ops) => (ProtocolHandler::BATCH_ACK, self.run_batch(&ops)),
+                    None => (ProtocolHandler::BATCH_ERROR, Vec::new()),
+                }
+            }
+            _ => {
+                info!("Simulator: unknown command {:#04x}", command);
+                (ProtocolHandler::HALT_ERROR, Vec::new())
+            }
+        }
+    }
+
+    /// Execute batch operations in order, stopping at the first failure
+    fn run_batch(&mut self, ops: &[BatchOp]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for op in ops {
+            let result = match *op {
+                BatchOp::WriteWord { address, value } => {
+                    if self.write_word(address, value) {
+                        BatchResult::Written
+                    } else {
+                        BatchResult::Failed { status: BatchResult::STATUS_FAULT }
+                    }
+                }
+                BatchOp::ReadWord { address } => {
+                    match self.read_word(address) {
+                        Some(value) => BatchResult::Value(value),
+                        None => BatchResult::Failed { status: BatchResult::STATUS_FAULT },
+                    }
+                }
+                BatchOp::PollBitSet { address, mask, attempts } => {
+                    let mut outcome = BatchResult::Failed { status: BatchResult::STATUS_POLL_TIMEOUT };
+                    for _ in 0..attempts.max(1) {
+                        match self.read_word(address) {
+                            Some(value) if value & mask == mask => {
+                                outcome = BatchResult::Value(value);
+                                break;
+                            }
+                            Some(_) => {}
+                            None => {
+                                outcome = BatchResult::Failed { status: BatchResult::STATUS_FAULT };
+                                break;
+                            }
+                        }
+                    }
+                    outcome
+                }
+            };
+            result.encode(&mut payload);
+            if let BatchResult::Failed { .. } = result {
+                break;
+            }
+        }
+        payload
+    }
+
+    fn address(args: &[u8]) -> Option<u32> {
+        let bytes: [u8; 4] = args.get(..4)?.try_into().ok()?;
+        Some(u32::from_be_bytes(bytes))
+    }
+
+    fn address_and_count(args: &[u8]) -> Option<(u32, u32)> {
+        let address = Self::address(args)?;
+        let count = ((*args.get(4)? as u32) << 8) | (*args.get(5)? as u32);
+        Some((address, count))
+    }
+
+    /// 32-bit access as the AHB-AP would perform it
+    pub fn read_word(&mut self, address: u32) -> Option<u32> {
+        if !address.is_multiple_of(4) {
+            return None;
+        }
+        if let Some(index) = Self::bp_comp_index(address) {
+            return Some(self.bp_comp[index]);
+        }
+        if let Some((index, register)) = Self::dwt_index(address) {
+            let value = self.dwt[index][register];
+            if register == 2 {
+                // Reading FUNCTION clears MATCHED
+                self.dwt[index][2] &= !DWT_FUNCTION_MATCHED;
+            }
+            return Some(value);
+        }
+        if let Some(value) = self.flashctl.read(address) {
+            return Some(value);
+        }
+        match address {
+            DHCSR_ADDR => Some(self.core.read_dhcsr()),
+            DCRSR_ADDR => Some(0),
+            DCRDR_ADDR => Some(self.core.dcrdr),
+            DEMCR_ADDR => Some(self.core.demcr),
+            DFSR_ADDR => Some(self.core.dfsr),
+            AIRCR_ADDR => Some(0xfa050000),
+            SYSCTL_RESETLEVEL_ADDR => Some(self.sysctl_resetlevel),
+            BP_CTRL_ADDR => Some(((BP_COMPARATORS as u32) << 4) | self.bp_ctrl),
+            DWT_CTRL_ADDR => Some((DWT_COMPARATORS as u32) << 28),
+            _ => self.memory.read_u32(address),
+        }
+    }
+
+    pub fn write_word(&mut self, address: u32, value: u32) -> bool {
+        if !address.is_multiple_of(4) {
+            return false;
+        }
+        if let Some(index) = Self::bp_comp_index(address) {
+            self.bp_comp[index] = value;
+            return true;
+        }
+        if let Some((index, register)) = Self::dwt_index(address) {
+            self.dwt[index][register] = value & !DWT_FUNCTION_MATCHED;
+            return true;
+        }
+        if self.flashctl.write(address, value, &mut self.memory) {
+            return true;
+        }
+        match address {
+            DHCSR_ADDR => {
+                if self.core.write_dhcsr(value) {
+                    self.step();
+                }
+            }
+            DCRSR_ADDR => self.core.write_dcrsr(value),
+            DCRDR_ADDR => {
+                self.core.dcrdr = value;
+            }
+            DEMCR_ADDR => {
+                self.core.demcr = value;
+            }
+            DFSR_ADDR => {
+                // Write one to clear
+                self.core.dfsr &= !value;
+            }
+            AIRCR_ADDR => {
+                if (value & 0xffff0000) == AIRCR_VECTKEY && (value & AIRCR_SYSRESETREQ) != 0 {
+                    self.core.requested_reset(&self.memory);
+                }
+            }
+            SYSCTL_RESETLEVEL_ADDR => {
+                self.sysctl_resetlevel = value & 0x7;
+            }
+            SYSCTL_RESETCMD_ADDR => {
+                if (value & 0xff000000) == SYSCTL_RESETCMD_KEY && (value & SYSCTL_RESETCMD_GO) != 0 {
+                    self.core.requested_reset(&self.memory);
+                }
+            }
+            BP_CTRL_ADDR => {
+                // KEY (bit 1) must be set for the write to take effect
+                if (value & 2) != 0 {
+                    self.bp_ctrl = value & 1;
+                }
+            }
+            _ => {
+                return self.memory.write_u32(address, value);
+            }
+        }
+        true
+    }
+
+    fn bp_comp_index(address: u32) -> Option<usize> {
+        let index = (address.checked_sub(BP_COMP0_ADDR)? / 4) as usize;
+        (index < BP_COMPARATORS).then_some(index)
+    }
+
+    /// Comparator and register (0 COMP, 1 MASK, 2 FUNCTION) at a DWT address
+    fn dwt_index(address: u32) -> Option<(usize, usize)> {
+        let offset = address.checked_sub(cortex_m::dwt_comp_addr(0))?;
+        let (index, register) = ((offset / 16) as usize, ((offset % 16) / 4) as usize);
+        (index < DWT_COMPARATORS && register < 3).then_some((index, register))
+    }
+
+    fn read_byte(&mut self, address: u32) -> Option<u8> {
+        let word = self.read_word(address & !3)?;
+        Some((word >> ((address & 3) * 8)) as u8)
+    }
+}
+
+/// Execution of ARMv6-M code: every 16-bit Thumb instruction, BL and the
+/// barriers. Exceptions are not modelled; a bus fault, UDF, SVC, MSR or MRS
+/// stalls the core where the real one would enter HardFault or a handler.
+impl BridgeSimulator {
+    /// Run up to `RUN_SLICE` instructions unless the core is halted or stalled,
+    /// stopping before an instruction an enabled BPU comparator matches
+    fn run(&mut self) {
+        for _ in 0..RUN_SLICE {
+            if self.core.halted || self.core.stalled {
+                return;
+            }
+            let pc = self.core.registers[REG_PC];
+            if self.breakpoint_at(pc) {
+                self.core.bkpt(pc);
+                return;
+            }
+            if let Err(reason) = self.execute_instruction() {
+                info!("Simulator: core stalled at 0x{:08X}: {}", pc, reason);
+                self.core.stalled = true;
+            }
+        }
+    }
+
+    /// Single step. An instruction the simulator cannot execute is skipped, so
+    /// stepping through firmware that touches unmodelled peripherals still advances.
+    fn step(&mut self) {
+        let pc = self.core.registers[REG_PC];
+        if let Err(reason) = self.execute_instruction() {
+            info!("Simulator: skipping the instruction at 0x{:08X}: {}", pc, reason);
+            let wide = self.fetch(pc).is_ok_and(|halfword| (halfword >> 11) >= 0b11101);
+            self.core.registers[REG_PC] = pc.wrapping_add(if wide { 4 } else { 2 });
+        }
+    }
+
+    fn breakpoint_at(&self, pc: u32) -> bool {
+        (self.bp_ctrl & BP_CTRL_ENABLE) != 0 &&
+            (self.core.dhcsr_control & C_DEBUGEN) != 0 &&
+            self.bp_comp.iter().any(|comp| cortex_m::bp_comp_addresses(*comp).contains(&pc))
+    }
+
+    /// Instruction halfword at `address`
+    fn fetch(&self, address: u32) -> Result<u16, String> {
+        let word = self.memory
+            .read_u32(address & !3)
+            .ok_or_else(|| format!("no memory to fetch from at 0x{:08X}", address))?;
+        Ok((word >> ((address & 2) * 8)) as u16)
+    }
+
+    /// Execute the instruction at PC and move PC on
+    fn execute_instruction(&mut self) -> Result<(), String> {
+        let pc = self.core.registers[REG_PC];
+        let instruction = self.fetch(pc)? as u32;
+        // Operands as the instruction sees them: PC reads 4 bytes ahead
+        let mut registers = self.core.registers;
+        registers[REG_PC] = pc.wrapping_add(4);
+        let r = |index: u32| registers[index as usize];
+        let low = |shift: u32| (instruction >> shift) & 7;
+        let undefined = || format!("undefined instruction {:04x}", instruction);
+        let mut next = pc.wrapping_add(2);
+        match instruction >> 11 {
+            // LSLS, LSRS, ASRS (immediate); LSR and ASR encode a shift by 32 as 0
+            kind @ 0b00000..=0b00010 => {
+                let amount = match (instruction >> 6) & 0x1f {
+                    0 if kind != 0 => 32,
+                    amount => amount,
+                };
+                let (result, carry) = shift(kind, r(low(3)), amount, self.carry());
+                self.set_flags(result, Some(carry), None);
+                self.core.registers[low(0) as usize] = result;
+            }
+            // ADDS, SUBS (register or 3-bit immediate)
+            0b00011 => {
+                let operand = if (instruction & (1 << 10)) != 0 { low(6) } else { r(low(6)) };
+                let result = if (instruction & (1 << 9)) != 0 {
+                    self.add_with_carry(r(low(3)), !operand, true)
+                } else {
+                    self.add_with_carry(r(low(3)), operand, false)
+                };
+                self.core.registers[low(0) as usize] = result;
+            }
+            // MOVS, CMP, ADDS, SUBS (8-bit immediate)
+            0b00100..=0b00111 => {
+                let (rdn, immediate) = (low(8), instruction & 0xff);
+                let result = match (instruction >> 11) & 3 {
+                    0 => {
+                        self.set_flags(immediate, None, None);
+                        immediate
+                    }
+                    1 | 3 => self.add_with_carry(r(rdn), !immediate, true),
+                    _ => self.add_with_carry(r(rdn), immediate, false),
+                };
+                if (instruction >> 11) & 3 != 1 {
+                    self.core.registers[rdn as usize] = result;
+                }
+            }
+            0b01000 if (instruction & (1 << 10)) == 0 => self.data_processing(instruction, &registers),
+            // ADD, CMP, MOV with high registers, BX, BLX
+            0b01000 => {
+                let rm = (instruction >> 3) & 0xf;
+                let rdn = ((instruction >> 4) & 8) | low(0);
+                match (instruction >> 8) & 3 {
+                    0 | 2 => {
+                        let value = if (instruction & (1 << 9)) == 0 { r(rdn).wrapping_add(r(rm)) } else { r(rm) };
+                        if rdn == (REG_PC as u32) {
+                            next = value & !1;
+                        } else {
+                            self.core.registers[rdn as usize] = value;
+                        }
+                    }
+                    1 => {
+                        self.add_with_carry(r(rdn), !r(rm), true);
+                    }
+                    _ => {
+                        if (r(rm) & 1) == 0 {
+                            return Err(format!("interworking branch to ARM state at 0x{:08X}", r(rm)));
+                        }
+                        if (instruction & (1 << 7)) != 0 {
+                            self.core.registers[REG_LR] = next | 1;
+                        }
+                        next = r(rm) & !1;
+                    }
+                }
+            }
+            // LDR (literal)
+            0b01001 => {
+                let value = self.load((r(REG_PC as u32) & !3).wrapping_add((instruction & 0xff) * 4), 4)?;
+                self.core.registers[low(8) as usize] = value;
+            }
+            // Loads and stores with a register offset
+            0b01010 | 0b01011 => {
+                let (address, rt) = (r(low(3)).wrapping_add(r(low(6))), low(0));
+                let value = match (instruction >> 9) & 7 {
+                    opcode @ 0..=2 => {
+                        self.store(address, [4, 2, 1][opcode as usize], r(rt))?;
+                        None
+                    }
+                    3 => Some((self.load(address, 1)? as u8) as i8 as u32),
+                    4 => Some(self.load(address, 4)?),
+                    5 => Some(self.load(address, 2)?),
+                    6 => Some(self.load(address, 1)?),
+                    _ => Some((self.load(address, 2)? as u16) as i16 as u32),
+                };
+                if let Some(value) = value {
+                    self.core.registers[rt as usize] = value;
+                }
+            }
+            // STR, LDR, STRB, LDRB, STRH, LDRH (immediate) and STR, LDR (SP-relative)
+            0b01100..=0b10011 => {
+                let (size, base, rt, offset) = match instruction >> 12 {
+                    0b0110 => (4, r(low(3)), low(0), ((instruction >> 6) & 0x1f) * 4),
+                    0b0111 => (1, r(low(3)), low(0), (instruction >> 6) & 0x1f),
+                    0b1000 => (2, r(low(3)), low(0), ((instruction >> 6) & 0x1f) * 2),
+                    _ => (4, r(REG_SP as u32), low(8), (instruction & 0xff) * 4),
+                };
+                let address = base.wrapping_add(offset);
+                if (instruction & (1 << 11)) != 0 {
+                    self.core.registers[rt as usize] = self.load(address, size)?;
+                } else {
+                    self.store(address, size, r(rt))?;
+                }
+            }
+            // ADR, ADD (SP plus immediate)
+            0b10100 | 0b10101 => {
+                let base = if (instruction & (1 << 11)) == 0 { r(REG_PC as u32) & !3 } else { r(REG_SP as u32) };
+                self.core.registers[low(8) as usize] = base.wrapping_add((instruction & 0xff) * 4);
+            }
+            0b10110 | 0b10111 => {
+                match (instruction >> 8) & 0xf {
+                    // ADD, SUB (SP plus immediate)
+                    0b0000 => {
+                        let offset = (instruction & 0x7f) * 4;
+                        let sp = r(REG_SP as u32);
+                        self.core.registers[REG_SP] = if (instruction & (1 << 7)) != 0 {
+                            sp.wrapping_sub(offset)
+                        } else {
+                            sp.wrapping_add(offset)
+                        };
+                    }
+                    // SXTH, SXTB, UXTH, UXTB
+                    0b0010 => {
+                        let value = r(low(3));
+                        self.core.registers[low(0) as usize] = match (instruction >> 6) & 3 {
+                            0 => (value as u16) as i16 as u32,
+                            1 => (value as u8) as i8 as u32,
+                            2 => value & 0xffff,
+                            _ => value & 0xff,
+                        };
+                    }
+                    // PUSH
+                    0b0100 | 0b0101 => {
+                        let list = (instruction & 0xff) | ((instruction & (1 << 8)) << 6);
+                        if list == 0 {
+                            return Err(undefined());
+                        }
+                        let sp = r(REG_SP as u32).wrapping_sub(list.count_ones() * 4);
+                        let mut address = sp;
+                        for index in (0..16).filter(|index| (list & (1 << index)) != 0) {
+                            self.store(address, 4, r(index))?;
+                            address = address.wrapping_add(4);
+                        }
+                        self.core.registers[REG_SP] = sp;
+                    }
+                    // CPSIE, CPSID set PRIMASK, kept in bit 0 of the special register word
+                    0b0110 if (instruction & 0xffef) == 0xb662 => {
+                        let primask = (instruction >> 4) & 1;
+                        self.core.registers[0x14] = (self.core.registers[0x14] & !1) | primask;
+                    }
+                    // REV, REV16, REVSH
+                    0b1010 => {
+                        let value = r(low(3));
+                        self.core.registers[low(0) as usize] = match (instruction >> 6) & 3 {
+                            0 => value.swap_bytes(),
+                            1 => ((value & 0x00ff00ff) << 8) | ((value >> 8) & 0x00ff00ff),
+                            3 => (value as u16).swap_bytes() as i16 as u32,
+                            _ => {
+                                return Err(undefined());
+                            }
+                        };
+                    }
+                    // POP
+                    0b1100 | 0b1101 => {
+                        let list = (instruction & 0xff) | ((instruction & (1 << 8)) << 7);
+                        if list == 0 {
+                            return Err(undefined());
+                        }
+                        let mut address = r(REG_SP as u32);
+                        let mut values = Vec::new();
+                        for index in (0..16).filter(|index| (list & (1 << index)) != 0) {
+                            values.push((index, self.load(address, 4)?));
+                            address = address.wrapping_add(4);
+                        }
+                        for (index, value) in values {
+                            if index == (REG_PC as u32) {
+                                if (value & 1) == 0 {
+                                    return Err(format!("interworking return to ARM state at 0x{:08X}", value));
+                                }
+                                next = value & !1;
+                            } else {
+                                self.core.registers[index as usize] = value;
+                            }
+                        }
+                        self.core.registers[REG_SP] = address;
+                    }
+                    // BKPT halts the core when a debugger is attached, else it escalates to HardFault
+                    0b1110 => {
+                        if (self.core.dhcsr_control & C_DEBUGEN) == 0 {
+                            return Err("BKPT without C_DEBUGEN".to_string());
+                        }
+                        self.core.bkpt(pc);
+                        return Ok(());
+                    }
+                    // NOP, YIELD, WFE, WFI, SEV
+                    0b1111 if (instruction & 0xf) == 0 => {}
+                    _ => {
+                        return Err(undefined());
+                    }
+                }
+            }
+            // STM, LDM
+            0b11000 | 0b11001 => {
+                let (rn, list) = (low(8), instruction & 0xff);
+                if list == 0 {
+                    return Err(undefined());
+                }
+                let mut address = r(rn);
+                for index in (0..8).filter(|index| (list & (1 << index)) != 0) {
+                    if (instruction & (1 << 11)) != 0 {
+                        self.core.registers[index as usize] = self.load(address, 4)?;
+                    } else {
+                        self.store(address, 4, r(index))?;
+                    }
+                    address = address.wrapping_add(4);
+                }
+                // LDM leaves the base alone when it was loaded
+                if (instruction & (1 << 11)) == 0 || (list & (1 << rn)) == 0 {
+                    self.core.registers[rn as usize] = address;
+                }
+            }
+            // B (conditional), UDF, SVC
+            0b11010 | 0b11011 => {
+                let condition = (instruction >> 8) & 0xf;
+                match condition {
+                    0b1110 => {
+                        return Err(undefined());
+                    }
+                    0b1111 => {
+                        return Err("SVC".to_string());
+                    }
+                    _ => {
+                        if self.condition_passed(condition) {
+                            next = r(REG_PC as u32).wrapping_add((((instruction & 0xff) << 24) as i32 >> 23) as u32);
+                        }
+                    }
+                }
+            }
+            // B (unconditional)
+            0b11100 => {
+                next = r(REG_PC as u32).wrapping_add((((instruction & 0x7ff) << 21) as i32 >> 20) as u32);
+            }
+            // 32-bit instructions: BL and the barriers
+            _ => {
+                let second = self.fetch(pc.wrapping_add(2))? as u32;
+                next = pc.wrapping_add(4);
+                if (instruction >> 11) == 0b11110 && (second & 0xd000) == 0xd000 {
+                    let sign = (instruction >> 10) & 1;
+                    let i1 = !((second >> 13) ^ sign) & 1;
+                    let i2 = !((second >> 11) ^ sign) & 1;
+                    let offset =
+                        (sign << 24) | (i1 << 23) | (i2 << 22) | ((instruction & 0x3ff) << 12) | ((second & 0x7ff) << 1);
+                    self.core.registers[REG_LR] = next | 1;
+                    next = next.wrapping_add(((offset << 7) as i32 >> 7) as u32);
+                } else if instruction != 0xf3bf || (second & 0xff00) != 0x8f00 {
+                    return Err(format!("unsupported instruction {:04x} {:04x}", instruction, second));
+                }
+            }
+        }
+        self.core.registers[REG_PC] = next;
+        Ok(())
+    }
+
+    /// AND, EOR, LSL, LSR, ASR, ADC, SBC, ROR, TST, RSB, CMP, CMN, ORR, MUL, BIC, MVN
+    fn data_processing(&mut self, instruction: u32, registers: &[u32; 0x15]) {
+        let (rdn, opcode) = ((instruction & 7) as usize, (instruction >> 6) & 0xf);
+        let (x, y) = (registers[rdn], registers[((instruction >> 3) & 7) as usize]);
+        let carry = self.carry();
+        let result = match opcode {
+            0x0 | 0x8 => self.set_flags(x & y, None, None),
+            0x1 => self.set_flags(x ^ y, None, None),
+            0x2 | 0x3 | 0x4 | 0x7 => {
+                let kind = if opcode == 0x7 { 3 } else { opcode - 2 };
+                let (result, carry) = shift(kind, x, y & 0xff, carry);
+                self.set_flags(result, Some(carry), None)
+            }
+            0x5 => self.add_with_carry(x, y, carry),
+            0x6 => self.add_with_carry(x, !y, carry),
+            0x9 => self.add_with_carry(!y, 0, true),
+            0xa => self.add_with_carry(x, !y, true),
+            0xb => self.add_with_carry(x, y, false),
+            0xc => self.set_flags(x | y, None, None),
+            0xd => self.set_flags(x.wrapping_mul(y), None, None),
+            0xe => self.set_flags(x & !y, None, None),
+            _ => self.set_flags(!y, None, None),
+        };
+        // TST, CMP and CMN only set the flags
+        if !matches!(opcode, 0x8 | 0xa | 0xb) {
+            self.core.registers[rdn] = result;
+        }
+    }
+
+    fn carry(&self) -> bool {
+        (self.core.registers[REG_XPSR] & XPSR_C) != 0
+    }
+
+    /// Set N and Z from `result`, and C and V when given; returns `result`
+    fn set_flags(&mut self, result: u32, carry: Option<bool>, overflow: Option<bool>) -> u32 {
+        let mut xpsr = self.core.registers[REG_XPSR] & !(XPSR_N | XPSR_Z);
+        if (result as i32) < 0 {
+            xpsr |= XPSR_N;
+        }
+        if result == 0 {
+            xpsr |= XPSR_Z;
+        }
+        for (flag, value) in [(XPSR_C, carry), (XPSR_V, overflow)] {
+            match value {
+                Some(true) => xpsr |= flag,
+                Some(false) => xpsr &= !flag,
+                None => {}
+            }
+        }
+        self.core.registers[REG_XPSR] = xpsr;
+        result
+    }
+
+    /// `x + y + carry`, setting all four flags; subtraction is `x + !y + 1`
+    fn add_with_carry(&mut self, x: u32, y: u32, carry: bool) -> u32 {
+        let sum = (x as u64) + (y as u64) + (carry as u64);
+        let result = sum as u32;
+        let overflow = ((x ^ result) & (y ^ result)) >> 31 != 0;
+        self.set_flags(result, Some(sum > (u32::MAX as u64)), Some(overflow))
+    }
+
+    fn condition_passed(&self, condition: u32) -> bool {
+        let xpsr = self.core.registers[REG_XPSR];
+        let [n, z, c, v] = [XPSR_N, XPSR_Z, XPSR_C, XPSR_V].map(|flag| (xpsr & flag) != 0);
+        let passed = match condition >> 1 {
+            0 => z,
+            1 => c,
+            2 => n,
+            3 => v,
+            4 => c && !z,
+            5 => n == v,
+            6 => !z && n == v,
+            _ => true,
+        };
+        // Odd conditions are the inverse of the even one before them
+        if (condition & 1) != 0 { !passed } else { passed }
+    }
+
+    /// Data access by the core; ARMv6-M faults on unaligned accesses
+    fn load(&mut self, address: u32, size: u32) -> Result<u32, String> {
+        if !address.is_multiple_of(size) {
+            return Err(format!("unaligned load from 0x{:08X}", address));
+        }
+        let word = self.read_word(address & !3).ok_or_else(|| format!("bus fault reading 0x{:08X}", address))?;
+        let value = word >> ((address & 3) * 8);
+        Ok(match size {
+            1 => value & 0xff,
+            2 => value & 0xffff,
+            _ => value,
+        })
+    }
+
+    /// Data store by the core. Flash is only written through FLASHCTL, and byte and
+    /// halfword stores are merged into the word around them.
+    fn store(&mut self, address: u32, size: u32, value: u32) -> Result<(), String> {
+        let fault = || format!("bus fault writing 0x{:08X}", address);
+        if !address.is_multiple_of(size) {
+            return Err(format!("unaligned store to 0x{:08X}", address));
+        }
+        if mspm0::in_flash(address) {
+            return Err(fault());
+        }
+        let word = if size == 4 {
+            value
+        } else {
+            let shift = (address & 3) * 8;
+            let mask = (if size == 1 { 0xff } else { 0xffff }) << shift;
+            let current = self.read_word(address & !3).ok_or_else(fault)?;
+            (current & !mask) | ((value << shift) & mask)
+        };
+        if self.write_word(address & !3, word) { Ok(()) } else { Err(fault()) }
+    }
+}
+
+/// `value` shifted by `amount` with LSL, LSR, ASR or ROR (`kind` 0 to 3) and the
+/// carry out; a shift by 0 leaves both the value and `carry` alone
+fn shift(kind: u32, value: u32, amount: u32, carry: bool) -> (u32, bool) {
+    let bit = |index: u32| ((value >> index) & 1) != 0;
+    match (kind, amount) {
+        (_, 0) => (value, carry),
+        (0, 1..=31) => (value << amount, bit(32 - amount)),
+        (0, 32) => (0, bit(0)),
+        (1, 1..=31) => (value >> amount, bit(amount - 1)),
+        (1, 32) => (0, bit(31)),
+        (0 | 1, _) => (0, false),
+        (2, 1..=31) => (((value as i32) >> amount) as u32, bit(amount - 1)),
+        (2, _) => (((value as i32) >> 31) as u32, bit(31)),
+        _ => {
+            let result = value.rotate_right(amount % 32);
+            (result, (result >> 31) != 0)
+        }
+    }
+}
+
+impl Default for BridgeSimulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Transport that answers frames in-process from a `BridgeSimulator`
+pub struct SimulatorTransport {
+    simulator: BridgeSimulator,
+    reassembler: FrameReassembler,
+    responses: VecDeque<Vec<u8>>,
+    timeout: Duration,
+}
+
+impl SimulatorTransport {
+    pub fn new(simulator: BridgeSimulator) -> Self {
+        SimulatorTransport {
+            simulator,
+            reassembler: FrameReassembler::new(),
+            responses: VecDeque::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+impl Transport for SimulatorTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), DebugError> {
+        self.reassembler.push(frame);
+        while let Some(frame) = self.reassembler.next_frame() {
+            if let Some(response) = self.simulator.handle_frame(&frame) {
+                self.responses.push_back(response);
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>, DebugError> {
+        self.responses.pop_front().ok_or(DebugError::Timeout)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), DebugError> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn describe(&self) -> String {
+        "simulator".to_string()
+    }
+}
+
+/// Serve the simulator on a pseudo-terminal and return the slave device path.
+/// The path can be passed to the CLI (`--port`) or the Socket.IO `connect` event.
+#[cfg(all(unix, feature = "cli"))]
+pub fn spawn_pty(mut simulator: BridgeSimulator) -> Result<String, DebugError> {
+    use serialport::{ SerialPort, TTYPort };
+    use std::io::{ Read, Write };
+
+    let (mut master, mut slave) = TTYPort::pair()?;
+    slave.set_exclusive(false)?;
+    let path = slave
+        .name()
+        .ok_or_else(|| DebugError::Transport("Pseudo-terminal has no name".to_string()))?;
+    info!("Simulator listening on {}", path);
+
+    std::thread::spawn(move || {
+        // Keep the slave open so the master does not see EIO between clients
+        let _slave = slave;
+        let mut reassembler = FrameReassembler::new();
+        let mut chunk = [0; 256];
+        loop {
+            match master.read(&mut chunk) {
+                Ok(count) => reassembler.push(&chunk[..count]),
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    continue;
+                }
+                Err(e) => {
+                    info!("Simulator pty closed: {}", e);
+                    return;
+                }
+            }
+            while let Some(frame) = reassembler.next_frame() {
+                if
+                    let Some(response) = simulator.handle_frame(&frame) &&
+                    let Err(e) = master.write_all(&response)
+                {
+                    info!("Simulator pty write failed: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cortex_m::{ CaughtVector, HaltReason, WatchKind, Watchpoint, DFSR_DWTTRAP };
+    use crate::elf_reader::{ ElfFlashVerifier, FlashSection };
+    use crate::flash_algorithm::FlashAlgorithm;
+    use crate::image::{ FirmwareImage, Segment };
+    use crate::loader::{ RoutineFrame, SerialLoader };
+    use crate::mspm0::ResetKind;
+    use crate::routines;
+    use std::sync::{ Arc, Mutex };
+
+    fn simulated_loader() -> SerialLoader {
+        loader_for(BridgeSimulator::new())
+    }
+
+    fn loader_for(simulator: BridgeSimulator) -> SerialLoader {
+        SerialLoader::with_transport(Box::new(SimulatorTransport::new(simulator))).expect("handshake")
+    }
+
+    /// Run `code` from the start of SRAM with `registers` set until it stops on a BKPT
+    fn run_code(code: &[u16], registers: &[(usize, u32)]) -> BridgeSimulator {
+        let mut simulator = BridgeSimulator::new();
+        let bytes: Vec<u8> = code.iter().flat_map(|halfword| halfword.to_le_bytes()).collect();
+        simulator.memory.load(SRAM_BASE, &bytes);
+        simulator.core.halt();
+        for &(index, value) in registers {
+            simulator.core.registers[index] = value;
+        }
+        simulator.core.registers[REG_PC] = SRAM_BASE;
+        simulator.core.resume();
+        simulator.run();
+        assert!(simulator.core.halted, "stalled at 0x{:08X}", simulator.core.registers[REG_PC]);
+        simulator
+    }
+
+    /// A frame the link between host and bridge loses
+    #[derive(Debug, Clone, Copy)]
+    enum Loss {
+        /// The request never reaches the bridge
+        Request,
+        /// The bridge executes the request but its reply is lost
+        Reply,
+    }
+
+    type Losses = Arc<Mutex<VecDeque<(u8, Loss)>>>;
+
+    /// `SimulatorTransport` behind a link that loses the next frame of each queued opcode
+    struct LossyLink {
+        inner: SimulatorTransport,
+        losses: Losses,
+    }
+
+    impl Transport for LossyLink {
+        fn send_frame(&mut self, frame: &[u8]) -> Result<(), DebugError> {
+            let opcode = ProtocolHandler::validate_frame(frame).ok().and_then(|(_, body)| body.first().copied());
+            let mut losses = self.losses.lock().unwrap();
+            match losses.front() {
+                Some(&(lost, loss)) if Some(lost) == opcode => {
+                    losses.pop_front();
+                    if let Loss::Reply = loss {
+                        self.inner.send_frame(frame)?;
+                        self.inner.receive_frame()?;
+                    }
+                    Ok(())
+                }
+                _ => self.inner.send_frame(frame),
+            }
+        }
+
+        fn receive_frame(&mut self) -> Result<Vec<u8>, DebugError> {
+            self.inner.receive_frame()
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> Result<(), DebugError> {
+            self.inner.set_timeout(timeout)
+        }
+
+        fn timeout(&self) -> Duration {
+            self.inner.timeout()
+        }
+
+        fn describe(&self) -> String {
+            "lossy simulator".to_string()
+        }
+    }
+
+    fn lossy_loader(simulator: BridgeSimulator) -> (SerialLoader, Losses) {
+        let losses = Losses::default();
+        let link = LossyLink { inner: SimulatorTransport::new(simulator), losses: Arc::clone(&losses) };
+        (SerialLoader::with_transport(Box::new(link)).expect("handshake"), losses)
+    }
+
+    fn main_elf() -> FirmwareImage {
+        FirmwareImage::load("main.elf", None, FLASH_BASE).expect("main.elf fixture")
+    }
+
+    #[test]
+    fn halt_and_resume() {
+        let mut loader = simulated_loader();
+        assert!(loader.bridge_info().supports(ProtocolHandler::BATCH_COMMAND));
+        assert!(!loader.core_status().unwrap().halted);
+        let event = loader.halt().unwrap();
+        assert!(matches!(event.reason, HaltReason::Request));
+        assert!(loader.core_status().unwrap().halted);
+        assert!(!loader.resume().unwrap().halted);
+        assert!(loader.poll_halt(false).unwrap().is_none());
+    }
+
+    #[test]
+    fn memory_access_is_split_and_retried() {
+        let mut simulator = BridgeSimulator::new();
+        simulator.info.max_payload = 64;
+        let data: Vec<u8> = (0..100).collect();
+        simulator.memory.load(SRAM_BASE + 0x100, &data);
+        let (mut loader, losses) = lossy_loader(simulator);
+        loader.write_word(SRAM_BASE + 4, 0xcafef00d).unwrap();
+        assert_eq!(loader.read_word(SRAM_BASE + 4).unwrap(), 0xcafef00d);
+        // 100 bytes in chunks of the negotiated 64 byte payload
+        assert_eq!(loader.read_bytes(SRAM_BASE + 0x100, 100).unwrap(), data);
+
+        // A read whose reply is lost is sent again
+        losses.lock().unwrap().push_back((ProtocolHandler::READ_WORD, Loss::Reply));
+        assert_eq!(loader.read_word(SRAM_BASE + 4).unwrap(), 0xcafef00d);
+        assert_eq!((loader.last_retries(), loader.total_retries()), (1, 1));
+        // A write is not
+        losses.lock().unwrap().push_back((ProtocolHandler::WRITE_COMMAND, Loss::Reply));
+        assert!(matches!(loader.write_word(SRAM_BASE + 4, 0), Err(DebugError::Timeout)));
+        assert_eq!(loader.read_word(SRAM_BASE + 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn halt_is_resent_after_a_lost_request() {
+        let (mut loader, losses) = lossy_loader(BridgeSimulator::new());
+        // Setting C_HALT again is harmless, so the batch is retried like a read
+        losses.lock().unwrap().push_back((ProtocolHandler::BATCH_COMMAND, Loss::Request));
+        let event = loader.halt().unwrap();
+        assert!(matches!(event.reason, HaltReason::Request));
+        assert_eq!(loader.total_retries(), 1);
+        assert!(!loader.resume().unwrap().halted);
+    }
+
+    #[test]
+    fn a_halt_resent_after_a_lost_reply_keeps_the_reason() {
+        let mut simulator = BridgeSimulator::new();
+        // movs r0, #1; adds r0, #1; b .
+        simulator.memory.load(0x100, &[0x01, 0x20, 0x01, 0x30, 0xfe, 0xe7]);
+        let (mut loader, losses) = lossy_loader(simulator);
+        loader.halt().unwrap();
+        loader.set_breakpoint(0x102).unwrap();
+        loader.write_register(0x0f, 0x100).unwrap();
+        loader.write_word(DHCSR_ADDR, DBGKEY | C_DEBUGEN).unwrap();
+
+        // The first run of the batch reads DFSR; the rerun must find it unchanged
+        losses.lock().unwrap().push_back((ProtocolHandler::BATCH_COMMAND, Loss::Reply));
+        let event = loader.halt().unwrap();
+        assert_eq!(loader.total_retries(), 1);
+        assert!(matches!(event.reason, HaltReason::Breakpoint { .. }));
+        assert_eq!(event.pc, 0x102);
+        // Cleared once decoded
+        assert_eq!(loader.poll_halt(false).unwrap().unwrap().reason, HaltReason::Unknown);
+    }
+
+    #[test]
+    fn reset_survives_a_lost_reply() {
+        let (mut loader, losses) = lossy_loader(BridgeSimulator::new());
+        // SYSRESETREQ can tear down the transfer: the reset happens, its reply is lost
+        losses.lock().unwrap().push_back((ProtocolHandler::WRITE_COMMAND, Loss::Reply));
+        assert!(loader.reset(ResetKind::System, false).unwrap().is_none());
+
+        // Without S_RESET_ST the reset is reported as not having happened
+        losses.lock().unwrap().push_back((ProtocolHandler::WRITE_COMMAND, Loss::Request));
+        assert!(
+            matches!(
+                loader.reset(ResetKind::System, false),
+                Err(DebugError::PollTimeout { mask: S_RESET_ST, .. })
+            )
+        );
+    }
+
+    #[test]
+    fn core_registers_need_a_halted_core() {
+        let mut loader = simulated_loader();
+        assert!(matches!(loader.read_register(0), Err(DebugError::NotHalted)));
+        loader.halt().unwrap();
+        loader.write_registers(&[(0, 0x12345678), (1, 0x9abcdef0), (0x0f, 0x00000120)]).unwrap();
+        assert_eq!(loader.read_registers(&[0, 1, 0x0f]).unwrap(), vec![0x12345678, 0x9abcdef0, 0x00000120]);
+        loader.write_register(0x0d, 0x20207ff0).unwrap();
+        assert_eq!(loader.read_register(0x0d).unwrap(), 0x20207ff0);
+    }
+
+    #[test]
+    fn breakpoints_use_the_bpu_comparators() {
+        let mut loader = simulated_loader();
+        assert_eq!(loader.breakpoint_comparators().unwrap(), BP_COMPARATORS);
+        let first = loader.set_breakpoint(0x00000100).unwrap();
+        let second = loader.set_breakpoint(0x00000102).unwrap();
+        assert_eq!(loader.breakpoints().unwrap().len(), 2);
+        assert_eq!((first.address, second.address), (0x00000100, 0x00000102));
+        loader.clear_breakpoint(0x00000100).unwrap();
+        let remaining = loader.breakpoints().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].address, 0x00000102);
+        loader.clear_all_breakpoints().unwrap();
+        assert!(loader.breakpoints().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reset_halts_on_the_reset_vector() {
+        let mut loader = simulated_loader();
+        loader.halt().unwrap();
+        let image = main_elf();
+        loader.flash_image(&image, |_| {}).unwrap();
+        let vector = &image.segments[0].data[4..8];
+        let reset_handler = u32::from_le_bytes([vector[0], vector[1], vector[2], vector[3]]) & !1;
+        for kind in [ResetKind::System, ResetKind::Core, ResetKind::Boot] {
+            let event = loader.reset(kind, true).unwrap().expect("halt after reset");
+            assert!(matches!(event.reason, HaltReason::VectorCatch { vector: CaughtVector::Reset, .. }));
+            assert_eq!(event.pc, reset_handler);
+        }
+        assert!(loader.reset(ResetKind::System, false).unwrap().is_none());
+        assert!(!loader.core_status().unwrap().halted);
+    }
+
+    #[test]
+    fn peeking_keeps_the_triggered_watchpoint() {
+        let mut simulator = BridgeSimulator::new();
+        simulator.core.halt();
+        simulator.core.dfsr = DFSR_DWTTRAP;
+        simulator.dwt[1] = [0x20200100, 2, WatchKind::Write.function() | DWT_FUNCTION_MATCHED];
+        let mut loader = loader_for(simulator);
+        let watchpoint = Watchpoint { comparator: 1, address: 0x20200100, size: 4, kind: WatchKind::Write };
+        let reason = HaltReason::Watchpoint { watchpoint: Some(watchpoint) };
+
+        // The first peek reads (and so clears) MATCHED; the event survives until DFSR is cleared
+        assert_eq!(loader.poll_halt(false).unwrap().unwrap().reason, reason);
+        assert_eq!(loader.poll_halt(false).unwrap().unwrap().reason, reason);
+        assert_eq!(loader.watchpoints().unwrap(), vec![watchpoint]);
+        assert_eq!(loader.poll_halt(true).unwrap().unwrap().reason, reason);
+        assert_eq!(loader.poll_halt(false).unwrap().unwrap().reason, HaltReason::Unknown);
+    }
+
+    #[test]
+    fn flash_and_verify_an_image() {
+        let mut loader = simulated_loader();
+        loader.halt().unwrap();
+        let image = main_elf();
+        let summary = loader.flash_image(&image, |_| {}).unwrap();
+        assert_eq!((summary.sectors, summary.unchanged), (1, 0));
+        let again = loader.flash_image(&image, |_| {}).unwrap();
+        assert_eq!(again.unchanged, 1);
+
+        let result = loader.verify_image(&image, false).unwrap();
+        assert!(result.success);
+        assert_eq!(result.bytes_read_back, 0);
+        let result = loader.verify_image(&image, true).unwrap();
+        assert!(result.success);
+        assert_eq!(result.bytes_read_back, image.size());
+
+        // A byte that differs is found through the CRC and read back
+        let mut changed = image.clone();
+        changed.segments[0].data[0x10] ^= 0xff;
+        let result = loader.verify_image(&changed, false).unwrap();
+        assert!(!result.success);
+        assert_eq!(result.mismatch_count(), 1);
+    }
+
+    #[test]
+    fn flash_through_the_builtin_algorithm() {
+        let mut loader = simulated_loader();
+        loader.halt().unwrap();
+        let algorithm = FlashAlgorithm::builtin();
+        let layout = algorithm.layout().unwrap();
+        // Several pages over three sectors, with a flash word of 0xFF left erased
+        let mut data: Vec<u8> = (0..0x900u32).map(|i| (i ^ (i >> 8)) as u8).collect();
+        data[0x200..0x208].fill(0xff);
+        let image = FirmwareImage { segments: vec![Segment { address: 0x200, data }], entry_point: None };
+        let mut progress = Vec::new();
+        let summary = loader.flash_image_with_algorithm(&image, &algorithm, |update| progress.push(update)).unwrap();
+        assert_eq!((summary.sectors, summary.programmed, summary.erased), (3, 3, 0));
+        assert!(progress.len() >= 3);
+        assert!(progress.last().is_some_and(|update| update.done == update.total));
+        assert!(loader.verify_image(&image, true).unwrap().success);
+        assert_eq!(loader.read_word(0x400).unwrap(), 0xffffffff);
+
+        // Changed bytes that are already programmed need EraseSector
+        let mut changed = image.clone();
+        changed.segments[0].data[0x10] ^= 0xff;
+        let summary = loader.flash_image_with_algorithm(&changed, &algorithm, |_| {}).unwrap();
+        assert_eq!((summary.erased, summary.unchanged), (1, 2));
+        assert!(loader.verify_image(&changed, true).unwrap().success);
+
+        // The algorithm stays loaded: EraseChip, then a ProgramPage that fails
+        let erase_chip = layout.code_address + routines::MSPM0_FLASH_ERASE_CHIP;
+        assert_eq!(loader.run_routine(erase_chip, &[], &layout.frame, algorithm.erase_timeout).unwrap(), 0);
+        assert_eq!(loader.read_word(0x210).unwrap(), 0xffffffff);
+        assert!(!loader.verify_image(&changed, false).unwrap().success);
+        loader.write_word(layout.buffers[0], 0).unwrap();
+        loader.write_word(layout.buffers[0] + 4, 0).unwrap();
+        let program_page = layout.code_address + algorithm.program_page;
+        let status = loader
+            .run_routine(program_page, &[FLASH_SIZE, 8, layout.buffers[0]], &layout.frame, algorithm.program_timeout)
+            .unwrap();
+        assert_ne!(status & FLASHCTL_STATCMD_FAIL_ILLADDR, 0);
+    }
+
+    #[test]
+    fn shifts_set_the_carry_out() {
+        assert_eq!(shift(0, 0x80000001, 0, true), (0x80000001, true));
+        assert_eq!(shift(0, 0x80000001, 1, false), (0x00000002, true));
+        assert_eq!(shift(0, 0x00000001, 32, false), (0, true));
+        assert_eq!(shift(0, 0xffffffff, 33, true), (0, false));
+        assert_eq!(shift(1, 0x00000003, 1, false), (0x00000001, true));
+        assert_eq!(shift(1, 0x80000000, 32, false), (0, true));
+        assert_eq!(shift(2, 0x80000000, 4, false), (0xf8000000, false));
+        assert_eq!(shift(2, 0x80000000, 40, false), (0xffffffff, true));
+        assert_eq!(shift(3, 0x00000001, 1, false), (0x80000000, true));
+        assert_eq!(shift(3, 0x12345678, 32, true), (0x12345678, false));
+    }
+
+    #[test]
+    fn arithmetic_sets_the_flags() {
+        // movs r0, #0; subs r0, #1; bkpt
+        let simulator = run_code(&[0x2000, 0x3801, 0xbe00], &[]);
+        assert_eq!(simulator.core.registers[0], 0xffffffff);
+        assert_eq!(simulator.core.registers[REG_XPSR] & (XPSR_N | XPSR_Z | XPSR_C | XPSR_V), XPSR_N);
+        // adds r0, #1 wraps to zero with a carry
+        let simulator = run_code(&[0x3001, 0xbe00], &[(0, 0xffffffff)]);
+        assert_eq!(simulator.core.registers[0], 0);
+        assert_eq!(simulator.core.registers[REG_XPSR] & (XPSR_N | XPSR_Z | XPSR_C | XPSR_V), XPSR_Z | XPSR_C);
+        // cmp r0, r1 overflows: INT_MIN < 1, so bge falls through to movs r2, #1
+        let simulator = run_code(&[0x4288, 0xda00, 0x2201, 0xbe00], &[(0, 0x80000000), (1, 1), (2, 0)]);
+        assert_eq!(simulator.core.registers[REG_XPSR] & (XPSR_N | XPSR_V), XPSR_V);
+        assert_eq!(simulator.core.registers[2], 1);
+        assert_eq!(simulator.core.registers[REG_PC], SRAM_BASE + 6);
+    }
+
+    #[test]
+    fn calls_and_the_stack() {
+        let code = [
+            0xb503, // push  {r0, r1, lr}
+            0xf000, // bl    +4
+            0xf802,
+            0xbc06, // pop   {r1, r2}
+            0xbd00, // pop   {pc}
+            0x3005, // adds  r0, #5
+            0x4770, // bx    lr
+        ];
+        let stack = SRAM_BASE + 0x100;
+        let simulator = run_code(
+            &[0xf000, 0xf802, 0xbe00, 0xbf00].iter().chain(&code).copied().collect::<Vec<_>>(),
+            &[(0, 7), (1, 9), (REG_SP, stack)]
+        );
+        // bl into the routine, which calls the adds and returns to the BKPT
+        assert_eq!(simulator.core.registers[0], 12);
+        assert_eq!((simulator.core.registers[1], simulator.core.registers[2]), (7, 9));
+        assert_eq!(simulator.core.registers[REG_SP], stack);
+        assert_eq!(simulator.core.registers[REG_PC], SRAM_BASE + 4);
+    }
+
+    #[test]
+    fn breakpoints_stop_the_running_core() {
+        let mut simulator = BridgeSimulator::new();
+        // movs r0, #1; adds r0, #1; b .
+        simulator.memory.load(0x100, &[0x01, 0x20, 0x01, 0x30, 0xfe, 0xe7]);
+        let mut loader = loader_for(simulator);
+        loader.halt().unwrap();
+        loader.set_breakpoint(0x102).unwrap();
+        loader.write_register(0x0f, 0x100).unwrap();
+        loader.write_word(DHCSR_ADDR, DBGKEY | C_DEBUGEN).unwrap();
+        let event = loader.poll_halt(true).unwrap().expect("halted on the breakpoint");
+        assert!(matches!(event.reason, HaltReason::Breakpoint { .. }));
+        assert_eq!(event.pc, 0x102);
+        assert_eq!(loader.read_register(0).unwrap(), 1);
+
+        loader.clear_all_breakpoints().unwrap();
+        assert_eq!(loader.step(true).unwrap().pc, 0x104);
+        assert_eq!(loader.read_register(0).unwrap(), 2);
+        assert_eq!(loader.step(true).unwrap().pc, 0x104);
+    }
+
+    #[test]
+    fn crc_routine_matches_the_host_checksum() {
+        let data: Vec<u8> = (0..1001u32).map(|i| (i * 7 + (i >> 3)) as u8).collect();
+        let address = SRAM_BASE + 0x1000;
+        let mut simulator = BridgeSimulator::new();
+        simulator.memory.load(address, &data);
+        let mut loader = loader_for(simulator);
+        loader.halt().unwrap();
+
+        let image = FirmwareImage {
+            segments: vec![
+                Segment { address, data: data.clone() },
+                Segment { address: address + 0x800, data: vec![0; 3] }
+            ],
+            entry_point: None,
+        };
+        let ranges: Vec<(u32, u32)> = image.segments
+            .iter()
+            .map(|segment| (segment.address, segment.data.len() as u32))
+            .collect();
+        let expected = ElfFlashVerifier::from_image(&image).calculate_checksums();
+        assert_eq!(loader.crc32_on_target(&ranges).unwrap(), expected);
+        assert_eq!(expected[0], FlashSection { address, size: 1001, data: data.clone() }.checksum());
+
+        // A CRC passed in R2 is continued
+        for (index, word) in routines::words(&routines::CRC32).into_iter().enumerate() {
+            loader.write_word(SRAM_BASE + (index as u32) * 4, word).unwrap();
+        }
+        let frame = RoutineFrame {
+            breakpoint: SRAM_BASE + routines::CRC32_BKPT_OFFSET,
+            stack_pointer: SRAM_BASE + SRAM_SIZE,
+            static_base: None,
+        };
+        let timeout = Duration::from_secs(1);
+        let first = loader.run_routine(SRAM_BASE, &[address, 600, 0], &frame, timeout).unwrap();
+        let whole = loader.run_routine(SRAM_BASE, &[address + 600, 401, first], &frame, timeout).unwrap();
+        assert_eq!(whole, expected[0]);
+        assert_eq!(loader.run_routine(SRAM_BASE, &[address, 0, 0], &frame, timeout).unwrap(), 0);
+    }
+}
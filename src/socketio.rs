@@ -1,9 +1,33 @@
 use socketioxide::{ extract::{ AckSender, Data, SocketRef }, SocketIo };
 use serde_json::Value;
 use tracing::info;
-use std::sync::{ Arc, Mutex };
+use std::sync::{ atomic::{ AtomicBool, Ordering }, Arc, Mutex };
 
-use crate::{ loader, models::CommandResponse };
+use std::time::{ Duration, Instant };
+
+use crate::{
+    cortex_m::{ HaltReason, VectorCatch, WatchKind, DHCSR_ADDR, S_HALT },
+    flash_algorithm::FlashAlgorithm,
+    error::DebugError,
+    image::{ FirmwareImage, ImageFormat },
+    loader,
+    models::CommandResponse,
+    mspm0::{ self, ResetKind },
+    symbols::ElfSymbols,
+};
+
+/// Delay between DHCSR polls while a `wait` is pending
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Delay between DHCSR polls of the background target monitor
+const MONITOR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Coarse core state tracked by `monitor_target`; a change is pushed to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TargetState {
+    Running,
+    Halted,
+    Lockup,
+}
 
 pub fn on_connect(socket: SocketRef, Data(data): Data<Value>) {
     info!(ns = socket.ns(), ?socket.id, "Socket.IO connected");
@@ -19,48 +43,70 @@ pub fn on_connect(socket: SocketRef, Data(data): Data<Value>) {
         ack.send(&data).ok();
     });
     let loader = Arc::new(
-        Mutex::new(loader::SerialLoader::new(None, 115200).expect("Failed to create SerialLoader"))
+        Mutex::new(
+            loader::SerialLoader::new(None, 115200).unwrap_or_else(|e| {
+                info!("No bridge attached yet ({}), waiting for a connect", e);
+                loader::SerialLoader::disconnected()
+            })
+        )
     );
-    register_debugger_handlers(&socket, Arc::clone(&loader));
-    check_port_connection(socket.clone(), Arc::clone(&loader));
+    // Set while the session uses a link other than the auto-detected USB port, so hotplug is ignored
+    let network_link = Arc::new(AtomicBool::new(false));
+    register_debugger_handlers(&socket, Arc::clone(&loader), Arc::clone(&network_link));
+    check_port_connection(socket.clone(), Arc::clone(&loader), network_link);
+    monitor_target(socket.clone(), loader);
 }
-fn check_port_connection(socket: SocketRef, loader: Arc<Mutex<loader::SerialLoader>>) {
+fn check_port_connection(
+    socket: SocketRef,
+    loader: Arc<Mutex<loader::SerialLoader>>,
+    network_link: Arc<AtomicBool>
+) {
     tokio::spawn(async move {
         let mut last_status = None;
         loop {
+            if network_link.load(Ordering::SeqCst) {
+                last_status = None;
+                tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+                continue;
+            }
             // Check if the port is connected
             let connected = loader::is_device_connected(loader::TARGET_PID);
 
             if last_status != Some(connected) {
                 if connected {
-                    match loader.lock() {
-                        Ok(mut loader_guard) => {
-                            match loader_guard.reconnect() {
-                                Ok(_) => {
-                                    info!("Device connected successfully");
-                                    socket.emit("device-connected", &Value::Bool(true)).ok();
-                                }
-                                Err(e) => {
-                                    info!("Failed to reconnect device: {}", e);
-                                    socket.emit("device-connected", &Value::Bool(false)).ok();
-                                }
-                            }
+                    // Opening the port and the INFO handshake block on I/O
+                    let reconnect_loader = Arc::clone(&loader);
+                    let result = tokio::task::spawn_blocking(move || match reconnect_loader.lock() {
+                        Ok(mut loader) => loader.reconnect(),
+                        Err(_) => Err(DebugError::Transport("Failed to acquire loader lock".to_string())),
+                    }).await
+                        .unwrap_or_else(|e| Err(DebugError::Transport(e.to_string())));
+                    match result {
+                        Ok(_) => {
+                            info!("Device connected successfully");
+                            socket.emit("device-connected", &Value::Bool(true)).ok();
                         }
                         Err(e) => {
-                            info!("Failed to acquire loader lock for reconnect: {}", e);
+                            info!("Failed to reconnect device: {}", e);
                             socket.emit("device-connected", &Value::Bool(false)).ok();
                         }
                     }
                 } else {
-                    match loader.lock() {
-                        Ok(mut loader_guard) => {
-                            let _ = loader_guard.close();
-                            info!("Device disconnected");
-                            socket.emit("device-connected", &Value::Bool(false)).ok();
+                    // A command in progress holds the lock until it fails on the lost port
+                    let close_loader = Arc::clone(&loader);
+                    let closed = tokio::task::spawn_blocking(move || match close_loader.lock() {
+                        Ok(mut loader) => {
+                            let _ = loader.close();
+                            true
                         }
                         Err(e) => {
                             info!("Failed to acquire loader lock for close: {}", e);
+                            false
                         }
+                    }).await;
+                    if let Ok(true) = closed {
+                        info!("Device disconnected");
+                        socket.emit("device-connected", &Value::Bool(false)).ok();
                     }
                 }
 
@@ -71,100 +117,550 @@ fn check_port_connection(socket: SocketRef, loader: Arc<Mutex<loader::SerialLoad
         }
     });
 }
-fn register_debugger_handlers(socket: &SocketRef, loader: Arc<Mutex<loader::SerialLoader>>) {
-    socket.on(
-        "connect",
-        |Data::<Value>(data), ack: AckSender| {
-            //get the port path based on pid
-
-            // info!(?data, "Connect event received");
-            // ack.send(&data).ok();
+/// Poll DHCSR in the background and push `target-halted`, `target-running`,
+/// `target-lockup` and `target-reset` when the core changes state on its own.
+/// The polls block on the serial link, so the monitor has a thread of its own.
+fn monitor_target(socket: SocketRef, loader: Arc<Mutex<loader::SerialLoader>>) {
+    std::thread::spawn(move || {
+        let mut last_state = None;
+        while socket.connected() {
+            std::thread::sleep(MONITOR_POLL_INTERVAL);
+            // A command holds the loader: skip this poll rather than queue behind it
+            let Ok(mut loader) = loader.try_lock() else {
+                continue;
+            };
+            let status = match loader.core_status() {
+                Ok(status) => status,
+                Err(_) => {
+                    // No bridge or no target; report the state afresh once it is back
+                    last_state = None;
+                    continue;
+                }
+            };
+            if status.reset {
+                info!("Target reset");
+                socket.emit("target-reset", &status).ok();
+            }
+            let state = if status.lockup {
+                TargetState::Lockup
+            } else if status.halted {
+                TargetState::Halted
+            } else {
+                TargetState::Running
+            };
+            if last_state == Some(state) {
+                continue;
+            }
+            match state {
+                TargetState::Halted =>
+                    match loader.poll_halt(false) {
+                        // DFSR is left for `wait` and `status`; resume clears it. Empty
+                        // DFSR means a command (halt, step, reset) already took the event
+                        // and emitted `target-halted` itself.
+                        Ok(Some(event)) if
+                            event.reason != HaltReason::Unknown || last_state.is_none()
+                        => {
+                            info!("Target halted at 0x{:08X} ({})", event.pc, event.reason);
+                            emit_halted(&socket, &event);
+                        }
+                        Ok(_) => {}
+                        Err(_) => {
+                            last_state = None;
+                            continue;
+                        }
+                    }
+                TargetState::Running => {
+                    socket.emit("target-running", &status).ok();
+                }
+                TargetState::Lockup => {
+                    info!("Target locked up");
+                    socket.emit("target-lockup", &status).ok();
+                }
+            }
+            last_state = Some(state);
         }
-    );
-
+    });
+}
+fn register_debugger_handlers(
+    socket: &SocketRef,
+    loader: Arc<Mutex<loader::SerialLoader>>,
+    network_link: Arc<AtomicBool>
+) {
     let loader_clone = Arc::clone(&loader);
-    socket.on("halt", move |ack: AckSender| {
+    socket.on("connect", move |Data::<Value>(data), ack: AckSender| {
         let loader_clone = Arc::clone(&loader_clone);
+        let network_link = Arc::clone(&network_link);
         tokio::spawn(async move {
-            info!("Halt command received");
-            // Here you would call the halt function from your loader
-            match loader_clone.lock() {
-                Ok(mut loader) => {
-                    match loader.halt() {
-                        Ok(_) => {
-                            let response = CommandResponse {
-                                success: true,
-                                message: "Halted".to_string(),
-                                command: "halt".to_string(),
-                                args: vec![],
-                            };
-                            ack.send(&response).ok();
+            info!(?data, "Connect event received");
+            // {"host": "192.168.1.50", "port": 3333} or {"address": "192.168.1.50:3333"} selects TCP,
+            // {"simulator": true} the built-in simulator, {"path": "/dev/pts/3"} an explicit serial
+            // device (e.g. the simulator's pty); anything else falls back to the USB port found by PID
+            let address = match (data.get("host").and_then(Value::as_str), data.get("port")) {
+                (Some(host), Some(port)) => Some(format!("{}:{}", host, port)),
+                _ => data.get("address").and_then(Value::as_str).map(str::to_string),
+            };
+            let simulator = data.get("simulator").and_then(Value::as_bool).unwrap_or(false);
+            let path = data.get("path").and_then(Value::as_str).map(str::to_string);
+            // Opening the link and the INFO handshake block on I/O
+            let connect_loader = Arc::clone(&loader_clone);
+            let connect_address = address.clone();
+            let result = tokio::task::spawn_blocking(move || match connect_loader.lock() {
+                Ok(mut loader) =>
+                    match &connect_address {
+                        None if simulator => {
+                            let transport = crate::simulator::SimulatorTransport::new(
+                                crate::simulator::BridgeSimulator::new()
+                            );
+                            loader
+                                .set_transport(Box::new(transport))
+                                .map(|_| {
+                                    network_link.store(true, Ordering::SeqCst);
+                                    "Connected to simulator".to_string()
+                                })
                         }
-                        Err(e) => {
-                            let response = CommandResponse {
-                                success: false,
-                                message: format!("Error: {}", e),
-                                command: "halt".to_string(),
-                                args: vec![],
-                            };
-                            info!("Failed to halt the loader: {}", e);
-                            ack.send(&response).ok();
+                        None if path.is_some() => {
+                            let path = path.as_deref().unwrap_or_default();
+                            crate::transport::SerialTransport
+                                ::open(path, 115200)
+                                .and_then(|transport| loader.set_transport(Box::new(transport)))
+                                .map(|_| {
+                                    network_link.store(true, Ordering::SeqCst);
+                                    format!("Connected to {}", path)
+                                })
+                        }
+                        Some(address) =>
+                            crate::transport::TcpTransport
+                                ::connect(address)
+                                .and_then(|transport| loader.set_transport(Box::new(transport)))
+                                .map(|_| {
+                                    network_link.store(true, Ordering::SeqCst);
+                                    format!("Connected to {}", address)
+                                }),
+                        None => {
+                            network_link.store(false, Ordering::SeqCst);
+                            loader
+                                .reconnect()
+                                .map(|_| "Connected over USB".to_string())
                         }
                     }
-                }
+                Err(_) => Err(DebugError::Transport("Failed to acquire loader lock".to_string())),
+            }).await
+                .unwrap_or_else(|e| Err(DebugError::Transport(e.to_string())));
+            let response = match result {
+                Ok(message) => CommandResponse {
+                    success: true,
+                    message: match loader_clone.lock() {
+                        Ok(loader) => format!("{} ({})", message, loader.bridge_info()),
+                        Err(_) => message,
+                    },
+                    command: "connect".to_string(),
+                    args: address.into_iter().collect(),
+                    retries: 0,
+                    error_code: None,
+                    data: None,
+                },
                 Err(e) => {
-                    info!("Failed to acquire loader lock: {}", e);
-                    let response = CommandResponse {
+                    info!("Failed to connect: {}", e);
+                    CommandResponse {
                         success: false,
-                        message: "Error: Failed to acquire loader lock".to_string(),
-                        command: "halt".to_string(),
-                        args: vec![],
-                    };
-                    ack.send(&response).ok();
+                        message: format!("Error: {}", e),
+                        command: "connect".to_string(),
+                        args: address.into_iter().collect(),
+                        retries: 0,
+                        error_code: Some(e.code().to_string()),
+                        data: None,
+                    }
                 }
-            }
+            };
+            ack.send(&response).ok();
         });
     });
 
-    socket.on("resume", |ack: AckSender| {
-        info!("Resume command received");
+    let loader_clone = Arc::clone(&loader);
+    socket.on("halt", move |socket: SocketRef, ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
         tokio::spawn(async move {
-            // Here you would call the resume function from your loader
-            match loader.lock() {
-                Ok(mut loader) => {
-                    match loader.resume() {
-                        Ok(_) => {
-                            let response = CommandResponse {
-                                success: true,
-                                message: "Resumed".to_string(),
-                                command: "resume".to_string(),
-                                args: vec![],
-                            };
-                            ack.send(&response).ok();
-                        }
-                        Err(e) => {
-                            let response = CommandResponse {
-                                success: false,
-                                message: format!("Error: {}", e),
-                                command: "resume".to_string(),
-                                args: vec![],
-                            };
-                            info!("Failed to resume the loader: {}", e);
-                            ack.send(&response).ok();
-                        }
+            info!("Halt command received");
+            respond(&loader_clone, "halt", vec![], ack, move |loader| {
+                let event = loader.halt()?;
+                emit_halted(&socket, &event);
+                Ok((format!("Halted at 0x{:08X} ({})", event.pc, event.reason), serde_json::to_value(event).ok()))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("status", move |ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!("Status command received");
+            respond(&loader_clone, "status", vec![], ack, move |loader| {
+                let status = loader.core_status()?;
+                let mut data = serde_json::to_value(status).ok();
+                // DFSR is only peeked at here; halt, step and wait clear it
+                if let Some(event) = loader.poll_halt(false)? {
+                    if let Some(Value::Object(fields)) = data.as_mut() {
+                        fields.insert("halt".to_string(), serde_json::json!(event));
                     }
+                    return Ok((
+                        format!("Target {}, stopped at 0x{:08X} ({})", status, event.pc, event.reason),
+                        data,
+                    ));
                 }
-                Err(e) => {
-                    info!("Failed to acquire loader lock for resume: {}", e);
-                    let response = CommandResponse {
-                        success: false,
-                        message: "Error: Failed to acquire loader lock".to_string(),
-                        command: "resume".to_string(),
-                        args: vec![],
-                    };
-                    ack.send(&response).ok();
+                Ok((format!("Target {}", status), data))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("reset", move |socket: SocketRef, Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Reset command received");
+            // {"kind": "system" | "core" | "boot", "halt": true}
+            respond(&loader_clone, "reset", vec![data.to_string()], ack, move |loader| {
+                let kind = data["kind"]
+                    .as_str()
+                    .unwrap_or("system")
+                    .parse::<ResetKind>()
+                    .map_err(DebugError::InvalidArgument)?;
+                let halt = data["halt"].as_bool().unwrap_or(false);
+                match loader.reset(kind, halt)? {
+                    Some(event) => {
+                        emit_halted(&socket, &event);
+                        Ok((
+                            format!("{}, halted at 0x{:08X}", kind, event.pc),
+                            serde_json::to_value(event).ok(),
+                        ))
+                    }
+                    None => Ok((format!("{}, target running", kind), None)),
+                }
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("vector-catch", move |Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Vector catch command received");
+            // {"hard_fault": true, "core_reset": false}; omitted fields keep their setting
+            respond(&loader_clone, "vector-catch", vec![data.to_string()], ack, move |loader| {
+                let current = loader.vector_catch()?;
+                let requested = VectorCatch {
+                    hard_fault: data["hard_fault"].as_bool().unwrap_or(current.hard_fault),
+                    core_reset: data["core_reset"].as_bool().unwrap_or(current.core_reset),
+                };
+                let catch = if requested == current {
+                    current
+                } else {
+                    loader.set_vector_catch(requested)?
+                };
+                Ok((format!("Vector catch: {}", catch), serde_json::to_value(catch).ok()))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("fault-report", move |Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Fault report command received");
+            // {"elf": "/path/to/firmware.elf"}; without an ELF addresses stay unresolved
+            respond(&loader_clone, "fault-report", vec![data["elf"].to_string()], ack, move |loader| {
+                let symbols = match data["elf"].as_str() {
+                    Some(path) => Some(ElfSymbols::from_elf_file(path)?),
+                    None => None,
+                };
+                let mut report = loader.fault_report()?;
+                if let Some(symbols) = &symbols {
+                    report.symbolize(symbols);
+                }
+                Ok((report.summary(), serde_json::to_value(&report).ok()))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("flash", move |socket: SocketRef, Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Flash command received");
+            // {"path": "firmware.hex", "base": "0x0", "format": "elf" | "hex" | "bin",
+            // "algorithm": "builtin" | "device.FLM"}; progress is pushed as
+            // `flash-progress` events after every sector (every page with an algorithm)
+            respond(&loader_clone, "flash", vec![data["path"].to_string()], ack, move |loader| {
+                let path = data["path"]
+                    .as_str()
+                    .ok_or_else(|| DebugError::InvalidArgument("Missing path".to_string()))?;
+                let format = match data["format"].as_str() {
+                    Some(format) => Some(format.parse::<ImageFormat>().map_err(DebugError::InvalidArgument)?),
+                    None => None,
+                };
+                let base = u32_arg(&data["base"]).unwrap_or(mspm0::FLASH_BASE);
+                let image = FirmwareImage::load(path, format, base)?;
+                let algorithm = data["algorithm"].as_str().map(FlashAlgorithm::load).transpose()?;
+                if !loader.core_status()?.halted {
+                    loader.halt()?;
+                }
+                let report = |progress: loader::FlashProgress| {
+                    socket.emit("flash-progress", &progress).ok();
+                };
+                let summary = match &algorithm {
+                    Some(algorithm) => loader.flash_image_with_algorithm(&image, algorithm, report)?,
+                    None => loader.flash_image(&image, report)?,
+                };
+                Ok((format!("Flashed {}: {}", path, summary), serde_json::to_value(summary).ok()))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("verify", move |Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Verify command received");
+            // {"path": "firmware.elf", "base": "0x0", "format": "elf" | "hex" | "bin",
+            // "read_back": false}; a mismatch still succeeds, with `success: false`
+            // in the result data
+            respond(&loader_clone, "verify", vec![data["path"].to_string()], ack, move |loader| {
+                let path = data["path"]
+                    .as_str()
+                    .ok_or_else(|| DebugError::InvalidArgument("Missing path".to_string()))?;
+                let format = match data["format"].as_str() {
+                    Some(format) => Some(format.parse::<ImageFormat>().map_err(DebugError::InvalidArgument)?),
+                    None => None,
+                };
+                let base = u32_arg(&data["base"]).unwrap_or(mspm0::FLASH_BASE);
+                let image = FirmwareImage::load(path, format, base)?;
+                let result = loader.verify_image(&image, data["read_back"].as_bool().unwrap_or(false))?;
+                let message = if result.success {
+                    format!("Verified {}: {} section(s) match", path, result.total_sections)
+                } else {
+                    format!("Verification of {} failed: {} byte(s) differ", path, result.mismatch_count())
+                };
+                Ok((message, serde_json::to_value(&result).ok()))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("wait", move |socket: SocketRef, Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Wait command received");
+            // {"timeout_ms": 10000}; the loader is only locked for each DHCSR poll so
+            // other commands (e.g. halt) can still get through while waiting
+            let timeout = data["timeout_ms"].as_u64().unwrap_or(10000);
+            let deadline = Instant::now() + Duration::from_millis(timeout);
+            let mut result = Err(DebugError::PollTimeout { address: DHCSR_ADDR, mask: S_HALT });
+            while Instant::now() < deadline {
+                let loader = Arc::clone(&loader_clone);
+                let polled = tokio::task::spawn_blocking(move || match loader.lock() {
+                    Ok(mut loader) => loader.poll_halt(true),
+                    Err(_) => Err(DebugError::Transport("Loader lock poisoned".to_string())),
+                }).await
+                    .unwrap_or_else(|e| Err(DebugError::Transport(e.to_string())));
+                match polled {
+                    Ok(None) => tokio::time::sleep(WAIT_POLL_INTERVAL).await,
+                    Ok(Some(event)) => {
+                        result = Ok(event);
+                        break;
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
                 }
             }
+            respond(&loader_clone, "wait", vec![timeout.to_string()], ack, move |_| {
+                let event = result?;
+                emit_halted(&socket, &event);
+                Ok((
+                    format!("Halted at 0x{:08X} ({})", event.pc, event.reason),
+                    serde_json::to_value(event).ok(),
+                ))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("write-reg", move |Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Write register command received");
+            // {"register": "pc" | 15, "value": 536871168 | "0x20000100"}
+            let args = vec![data["register"].to_string(), data["value"].to_string()];
+            respond(&loader_clone, "write-reg", args, ack, move |loader| {
+                let register = register_arg(&data["register"]).map_err(DebugError::InvalidArgument)?;
+                let value = u32_arg(&data["value"]).ok_or_else(|| {
+                    DebugError::InvalidArgument("Missing or invalid value".to_string())
+                })?;
+                loader.write_register(register, value)?;
+                Ok((format!("Wrote 0x{:08X} to {}", value, crate::get_register_name(register)), None))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("step", move |socket: SocketRef, Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Step command received");
+            // {"count": 5} or {"start": "0x100", "end": "0x140", "max_steps": 1000};
+            // "mask_interrupts" defaults to true
+            let mask_interrupts = data["mask_interrupts"].as_bool().unwrap_or(true);
+            respond(&loader_clone, "step", vec![data.to_string()], ack, move |loader| {
+                let outcome = match (u32_arg(&data["start"]), u32_arg(&data["end"])) {
+                    (Some(start), Some(end)) => {
+                        let max_steps = u32_arg(&data["max_steps"]).unwrap_or(10000);
+                        loader.step_until_outside(start..end, max_steps, mask_interrupts)?
+                    }
+                    _ => loader.step_many(u32_arg(&data["count"]).unwrap_or(1), mask_interrupts)?,
+                };
+                emit_halted(&socket, &(loader::HaltEvent { pc: outcome.pc, reason: outcome.reason }));
+                Ok((
+                    format!("PC: 0x{:08X} after {} step(s)", outcome.pc, outcome.steps),
+                    serde_json::to_value(outcome).ok(),
+                ))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("break", move |Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Break command received");
+            // {"address": "0x000001c4"}
+            respond(&loader_clone, "break", vec![data["address"].to_string()], ack, move |loader| {
+                let address = u32_arg(&data["address"]).ok_or_else(|| {
+                    DebugError::InvalidArgument("Missing or invalid address".to_string())
+                })?;
+                let breakpoint = loader.set_breakpoint(address)?;
+                Ok((
+                    format!("Breakpoint at 0x{:08X}", breakpoint.address),
+                    serde_json::to_value(breakpoint).ok(),
+                ))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("delete", move |Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Delete command received");
+            // {"address": "0x000001c4"}; without an address every breakpoint is removed
+            respond(&loader_clone, "delete", vec![data["address"].to_string()], ack, move |loader| {
+                match u32_arg(&data["address"]) {
+                    Some(address) => {
+                        loader.clear_breakpoint(address)?;
+                        Ok((format!("Deleted breakpoint at 0x{:08X}", address), None))
+                    }
+                    None => {
+                        loader.clear_all_breakpoints()?;
+                        Ok(("Deleted all breakpoints".to_string(), None))
+                    }
+                }
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("breakpoints", move |ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!("Breakpoints command received");
+            respond(&loader_clone, "breakpoints", vec![], ack, move |loader| {
+                let comparators = loader.breakpoint_comparators()?;
+                let breakpoints = loader.breakpoints()?;
+                let mut used: Vec<usize> = breakpoints.iter().map(|b| b.comparator).collect();
+                used.dedup();
+                Ok((
+                    format!(
+                        "{} breakpoint(s), {} of {} comparators in use",
+                        breakpoints.len(),
+                        used.len(),
+                        comparators
+                    ),
+                    Some(serde_json::json!({ "comparators": comparators, "breakpoints": breakpoints })),
+                ))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("watch", move |Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Watch command received");
+            // {"address": "0x20200010", "size": 4, "kind": "write" | "read" | "access"}
+            respond(&loader_clone, "watch", vec![data.to_string()], ack, move |loader| {
+                let address = u32_arg(&data["address"]).ok_or_else(|| {
+                    DebugError::InvalidArgument("Missing or invalid address".to_string())
+                })?;
+                let size = u32_arg(&data["size"]).unwrap_or(4);
+                let kind = data["kind"]
+                    .as_str()
+                    .unwrap_or("write")
+                    .parse::<WatchKind>()
+                    .map_err(DebugError::InvalidArgument)?;
+                let watchpoint = loader.set_watchpoint(address, size, kind)?;
+                Ok((
+                    format!("Watchpoint ({}) at 0x{:08X}+{}", kind, address, size),
+                    serde_json::to_value(watchpoint).ok(),
+                ))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("unwatch", move |Data::<Value>(data), ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!(?data, "Unwatch command received");
+            // {"address": "0x20200010"}; without an address every watchpoint is removed
+            respond(&loader_clone, "unwatch", vec![data["address"].to_string()], ack, move |loader| {
+                match u32_arg(&data["address"]) {
+                    Some(address) => {
+                        loader.clear_watchpoint(address)?;
+                        Ok((format!("Deleted watchpoint at 0x{:08X}", address), None))
+                    }
+                    None => {
+                        loader.clear_all_watchpoints()?;
+                        Ok(("Deleted all watchpoints".to_string(), None))
+                    }
+                }
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("watchpoints", move |ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!("Watchpoints command received");
+            respond(&loader_clone, "watchpoints", vec![], ack, move |loader| {
+                let comparators = loader.watchpoint_comparators()?;
+                let watchpoints = loader.watchpoints()?;
+                Ok((
+                    format!("{} of {} comparators in use", watchpoints.len(), comparators),
+                    Some(serde_json::json!({ "comparators": comparators, "watchpoints": watchpoints })),
+                ))
+            }).await;
+        });
+    });
+
+    let loader_clone = Arc::clone(&loader);
+    socket.on("resume", move |ack: AckSender| {
+        let loader_clone = Arc::clone(&loader_clone);
+        tokio::spawn(async move {
+            info!("Resume command received");
+            respond(&loader_clone, "resume", vec![], ack, |loader| {
+                let status = loader.resume()?;
+                Ok((format!("Resumed ({})", status), serde_json::to_value(status).ok()))
+            }).await;
         });
     });
 
@@ -177,3 +673,100 @@ fn register_debugger_handlers(socket: &SocketRef, loader: Arc<Mutex<loader::Seri
         }
     });
 }
+
+/// Register given by name ("pc", "r0") or index
+fn register_arg(value: &Value) -> Result<u32, String> {
+    match value {
+        Value::String(name) => crate::parse_register_name(name),
+        Value::Number(_) => crate::parse_register_name(&value.to_string()),
+        _ => Err("Missing register".to_string()),
+    }
+}
+
+/// Tell the client the core stopped: `target-halted` with `{pc, reason}`
+fn emit_halted(socket: &SocketRef, event: &loader::HaltEvent) {
+    socket.emit("target-halted", event).ok();
+}
+
+/// 32-bit argument given as a JSON number or a hex/decimal string
+fn u32_arg(value: &Value) -> Option<u32> {
+    match value {
+        Value::Number(number) => number.as_u64().and_then(|n| u32::try_from(n).ok()),
+        Value::String(text) => crate::parse_hex(text).ok(),
+        _ => None,
+    }
+}
+
+/// Run `action` against the session's loader and acknowledge with a `CommandResponse`
+/// carrying its message and optional structured data, or the error code on failure.
+/// The action does blocking serial I/O, so it runs on the blocking thread pool.
+async fn respond<F>(
+    loader: &Arc<Mutex<loader::SerialLoader>>,
+    command: &'static str,
+    args: Vec<String>,
+    ack: AckSender,
+    action: F
+)
+    where
+        F: FnOnce(&mut loader::SerialLoader) -> Result<(String, Option<Value>), DebugError> +
+            Send +
+            'static
+{
+    let loader = Arc::clone(loader);
+    let response = tokio::task::spawn_blocking(move || match loader.lock() {
+        Ok(mut loader) =>
+            match action(&mut loader) {
+                Ok((message, data)) => CommandResponse {
+                    success: true,
+                    message,
+                    command: command.to_string(),
+                    args,
+                    retries: loader.last_retries(),
+                    error_code: None,
+                    data,
+                },
+                Err(e) => {
+                    info!("Command {} failed: {}", command, e);
+                    CommandResponse {
+                        success: false,
+                        message: format!("Error: {}", e),
+                        command: command.to_string(),
+                        args,
+                        retries: loader.last_retries(),
+                        error_code: Some(e.code().to_string()),
+                        data: None,
+                    }
+                }
+            }
+        Err(e) => {
+            info!("Failed to acquire loader lock: {}", e);
+            CommandResponse {
+                success: false,
+                message: "Error: Failed to acquire loader lock".to_string(),
+                command: command.to_string(),
+                args,
+                retries: 0,
+                error_code: Some("busy".to_string()),
+                data: None,
+            }
+        }
+    }).await;
+    match response {
+        Ok(response) => {
+            ack.send(&response).ok();
+        }
+        Err(e) => {
+            info!("Command {} panicked: {}", command, e);
+            let response = CommandResponse {
+                success: false,
+                message: format!("Error: {}", e),
+                command: command.to_string(),
+                args: vec![],
+                retries: 0,
+                error_code: None,
+                data: None,
+            };
+            ack.send(&response).ok();
+        }
+    }
+}
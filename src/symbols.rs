@@ -0,0 +1,552 @@
+use goblin::elf::{ sym, Elf };
+use serde::Serialize;
+use std::fmt;
+use crate::error::DebugError;
+
+/// A function symbol from the ELF symbol table
+#[derive(Debug, Clone)]
+struct Symbol {
+    name: String,
+    /// Start address with the Thumb bit cleared
+    address: u32,
+    size: u32,
+    global: bool,
+}
+
+/// Addresses `start..end` generated from one source line
+#[derive(Debug, Clone)]
+struct LineRange {
+    start: u32,
+    end: u32,
+    file: usize,
+    line: u32,
+}
+
+/// Where an address falls in the firmware sources
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub address: u32,
+    pub symbol: Option<String>,
+    /// Bytes from the start of `symbol`
+    pub offset: u32,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:08X}", self.address)?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " in {}+0x{:x}", symbol, self.offset)?;
+        }
+        if let (Some(file), Some(line)) = (&self.file, self.line) {
+            write!(f, " at {}:{}", file, line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Function symbols and DWARF line information of a firmware ELF, used to
+/// turn target addresses into `function+offset (file:line)`
+pub struct ElfSymbols {
+    symbols: Vec<Symbol>,
+    files: Vec<String>,
+    lines: Vec<LineRange>,
+}
+
+impl ElfSymbols {
+    pub fn from_elf_file(elf_path: &str) -> Result<Self, DebugError> {
+        let buffer = std::fs::read(elf_path).map_err(|e| {
+            DebugError::InvalidArgument(format!("Cannot read {}: {}", elf_path, e))
+        })?;
+        Self::from_elf_bytes(&buffer).map_err(|e| {
+            DebugError::InvalidArgument(format!("{}: {}", elf_path, e))
+        })
+    }
+
+    pub fn from_elf_bytes(buffer: &[u8]) -> Result<Self, String> {
+        let elf = Elf::parse(buffer).map_err(|e| format!("Invalid ELF file: {}", e))?;
+
+        let symbols: Vec<Symbol> = elf.syms
+            .iter()
+            .filter(|symbol| symbol.st_type() == sym::STT_FUNC && symbol.st_value != 0)
+            .filter_map(|symbol| {
+                let name = elf.strtab.get_at(symbol.st_name)?;
+                Some(Symbol {
+                    name: name.to_string(),
+                    address: (symbol.st_value as u32) & !1,
+                    size: symbol.st_size as u32,
+                    global: symbol.st_bind() == sym::STB_GLOBAL,
+                })
+            })
+            .collect();
+
+        let debug_line = elf.section_headers.iter().find_map(|header| {
+            if elf.shdr_strtab.get_at(header.sh_name) != Some(".debug_line") {
+                return None;
+            }
+            let start = header.sh_offset as usize;
+            buffer.get(start..start + (header.sh_size as usize))
+        });
+        let (files, lines) = match debug_line {
+            Some(data) => parse_debug_line(data),
+            None => (Vec::new(), Vec::new()),
+        };
+
+        Ok(Self::new(symbols, files, lines))
+    }
+
+    fn new(mut symbols: Vec<Symbol>, files: Vec<String>, lines: Vec<LineRange>) -> Self {
+        // Aliases share an address (weak handlers); sort the global name last
+        // so that the backward search in `locate_preferring` finds it first
+        symbols.sort_by_key(|symbol| (symbol.address, symbol.global));
+        ElfSymbols { symbols, files, lines }
+    }
+
+    /// Whether the ELF carried a `.debug_line` section
+    #[cfg(feature = "cli")]
+    pub fn has_line_info(&self) -> bool {
+        !self.lines.is_empty()
+    }
+
+    /// Resolve `address` (Thumb bit ignored) to its function and source line
+    pub fn locate(&self, address: u32) -> Location {
+        self.locate_preferring(address, None)
+    }
+
+    /// Like `locate`, but name the function `preferred` when it is one of several
+    /// aliases at the address (e.g. `HardFault_Handler` among the default handlers)
+    pub fn locate_preferring(&self, address: u32, preferred: Option<&str>) -> Location {
+        let address = address & !1;
+        // Last symbol starting at or before the address; it must contain the
+        // address unless its size is unknown
+        let symbol = self.symbols
+            .iter()
+            .rev()
+            .find(|symbol| symbol.address <= address)
+            .filter(|symbol| symbol.size == 0 || address - symbol.address < symbol.size)
+            .map(|symbol| {
+                self.symbols
+                    .iter()
+                    .find(|alias| alias.address == symbol.address && Some(alias.name.as_str()) == preferred)
+                    .unwrap_or(symbol)
+            });
+        // Line 0 marks compiler-generated code with no source line
+        let line = self.lines
+            .iter()
+            .find(|range| range.line != 0 && range.start <= address && address < range.end);
+        Location {
+            address,
+            symbol: symbol.map(|symbol| symbol.name.clone()),
+            offset: symbol.map_or(0, |symbol| address - symbol.address),
+            file: line.and_then(|range| self.files.get(range.file).cloned()),
+            line: line.map(|range| range.line),
+        }
+    }
+}
+
+/// Cursor over little-endian DWARF data
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let value = *self.data.get(self.position)?;
+        self.position += 1;
+        Some(value)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.data.get(self.position..self.position + 2)?;
+        self.position += 2;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.data.get(self.position..self.position + 4)?;
+        self.position += 4;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if (byte & 0x80) == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Option<i64> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if (byte & 0x80) == 0 {
+                if shift < 64 && (byte & 0x40) != 0 {
+                    value |= -1i64 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<&'a str> {
+        let rest = self.data.get(self.position..)?;
+        let length = rest.iter().position(|&byte| byte == 0)?;
+        self.position += length + 1;
+        std::str::from_utf8(&rest[..length]).ok()
+    }
+}
+
+// Standard and extended line number opcodes (DWARF v3 section 6.2.5)
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+/// Run the line number programs of every unit in `.debug_line`. Handles the
+/// 32-bit DWARF format of versions 2 to 4; other units are skipped.
+fn parse_debug_line(data: &[u8]) -> (Vec<String>, Vec<LineRange>) {
+    let mut files = Vec::new();
+    let mut lines = Vec::new();
+    let mut reader = Reader { data, position: 0 };
+    while reader.position < data.len() {
+        let Some(unit_length) = reader.u32() else {
+            break;
+        };
+        // 0xffffffff introduces 64-bit DWARF, which 32-bit targets do not use
+        if unit_length >= 0xfffffff0 {
+            break;
+        }
+        let unit_end = reader.position.saturating_add(unit_length as usize).min(data.len());
+        let mut unit = Reader { data: &data[..unit_end], position: reader.position };
+        parse_line_unit(&mut unit, &mut files, &mut lines);
+        reader.position = unit_end;
+    }
+    (files, lines)
+}
+
+fn parse_line_unit(
+    reader: &mut Reader,
+    files: &mut Vec<String>,
+    lines: &mut Vec<LineRange>
+) -> Option<()> {
+    let version = reader.u16()?;
+    if !(2..=4).contains(&version) {
+        return None;
+    }
+    let header_length = reader.u32()? as usize;
+    let program_start = reader.position.checked_add(header_length)?;
+    let min_instruction_length = reader.u8()? as u32;
+    if version >= 4 {
+        reader.u8()?; // maximum_operations_per_instruction, 1 for non-VLIW targets
+    }
+    reader.u8()?; // default_is_stmt
+    let line_base = reader.u8()? as i8 as i64;
+    let line_range = reader.u8()?;
+    let opcode_base = reader.u8()?;
+    if line_range == 0 || opcode_base == 0 {
+        return None;
+    }
+    let mut standard_opcode_lengths = Vec::with_capacity(opcode_base as usize);
+    for _ in 1..opcode_base {
+        standard_opcode_lengths.push(reader.u8()?);
+    }
+
+    let mut directories = Vec::new();
+    loop {
+        let directory = reader.cstr()?;
+        if directory.is_empty() {
+            break;
+        }
+        directories.push(directory);
+    }
+    // File indices in the program are 1-based into this unit's table
+    let first_file = files.len();
+    let add_file = |reader: &mut Reader, name: &str, files: &mut Vec<String>| -> Option<()> {
+        let directory = reader.uleb128()? as usize;
+        reader.uleb128()?; // modification time
+        reader.uleb128()?; // length
+        let path = match directories.get(directory.wrapping_sub(1)) {
+            // Absolute file names are used as they are
+            Some(directory) if !name.starts_with('/') => format!("{}/{}", directory, name),
+            _ => name.to_string(),
+        };
+        files.push(path);
+        Some(())
+    };
+    loop {
+        let name = reader.cstr()?;
+        if name.is_empty() {
+            break;
+        }
+        add_file(reader, name, files)?;
+    }
+
+    reader.position = program_start;
+    let mut address = 0u32;
+    let mut file = 1u64;
+    let mut line = 1i64;
+    let mut previous: Option<(u32, u64, i64)> = None;
+    // Every row closes the address range opened by the row before it
+    let mut emit = |address: u32, file: u64, line: i64, end_sequence: bool, lines: &mut Vec<LineRange>| {
+        if let Some((start, previous_file, previous_line)) = previous
+            && address > start
+            && let Some(file) = (previous_file as usize).checked_sub(1).and_then(|index| first_file.checked_add(index))
+        {
+            lines.push(LineRange {
+                start,
+                end: address,
+                file,
+                line: previous_line.max(0) as u32,
+            });
+        }
+        previous = if end_sequence { None } else { Some((address, file, line)) };
+    };
+
+    while reader.position < reader.data.len() {
+        let opcode = reader.u8()?;
+        if opcode >= opcode_base {
+            let adjusted = opcode - opcode_base;
+            address = address.wrapping_add(((adjusted / line_range) as u32) * min_instruction_length);
+            line = line.wrapping_add(line_base + ((adjusted % line_range) as i64));
+            emit(address, file, line, false, lines);
+            continue;
+        }
+        match opcode {
+            0 => {
+                // Operands are untrusted: arithmetic wraps, and a length past the
+                // end of the address space stops the unit
+                let length = usize::try_from(reader.uleb128()?).ok()?;
+                let end = reader.position.checked_add(length)?;
+                match reader.u8()? {
+                    DW_LNE_END_SEQUENCE => {
+                        emit(address, file, line, true, lines);
+                        address = 0;
+                        file = 1;
+                        line = 1;
+                    }
+                    DW_LNE_SET_ADDRESS => {
+                        address = reader.u32()?;
+                    }
+                    DW_LNE_DEFINE_FILE => {
+                        // Files defined mid-program extend this unit's table
+                        let name = reader.cstr()?;
+                        add_file(reader, name, files)?;
+                    }
+                    _ => {}
+                }
+                reader.position = end;
+            }
+            DW_LNS_COPY => emit(address, file, line, false, lines),
+            DW_LNS_ADVANCE_PC => {
+                address = address.wrapping_add((reader.uleb128()? as u32).wrapping_mul(min_instruction_length));
+            }
+            DW_LNS_ADVANCE_LINE => {
+                line = line.wrapping_add(reader.sleb128()?);
+            }
+            DW_LNS_SET_FILE => {
+                file = reader.uleb128()?;
+            }
+            DW_LNS_CONST_ADD_PC => {
+                let adjusted = 255 - opcode_base;
+                address = address.wrapping_add(((adjusted / line_range) as u32) * min_instruction_length);
+            }
+            DW_LNS_FIXED_ADVANCE_PC => {
+                address = address.wrapping_add(reader.u16()? as u32);
+            }
+            _ => {
+                // set_column, negate_stmt, set_isa... and opcodes newer than
+                // this parser: skip their ULEB128 operands
+                for _ in 0..standard_opcode_lengths[(opcode - 1) as usize] {
+                    reader.uleb128()?;
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(data: &[u8]) -> Reader<'_> {
+        Reader { data, position: 0 }
+    }
+
+    fn function(name: &str, address: u32, size: u32, global: bool) -> Symbol {
+        Symbol { name: name.to_string(), address, size, global }
+    }
+
+    /// A DWARF v3 line number unit with `src/main.c` and `/abs/start.s`
+    fn line_unit(program: &[u8]) -> Vec<u8> {
+        let mut header = vec![
+            2,    // minimum_instruction_length
+            1,    // default_is_stmt
+            0xfb, // line_base -5
+            14,   // line_range
+            13,   // opcode_base
+            0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1,
+        ];
+        header.extend_from_slice(b"src\0\0");
+        header.extend_from_slice(b"main.c\0\x01\x00\x00");
+        header.extend_from_slice(b"/abs/start.s\0\x01\x00\x00");
+        header.push(0);
+
+        let mut unit = 3u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(program);
+        let mut data = (unit.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&unit);
+        data
+    }
+
+    #[test]
+    fn reads_leb128() {
+        assert_eq!(reader(&[0x02]).uleb128(), Some(2));
+        assert_eq!(reader(&[0x7f]).uleb128(), Some(127));
+        assert_eq!(reader(&[0x80, 0x01]).uleb128(), Some(128));
+        assert_eq!(reader(&[0xe5, 0x8e, 0x26]).uleb128(), Some(624485));
+        assert_eq!(reader(&[0x80]).uleb128(), None);
+
+        assert_eq!(reader(&[0x02]).sleb128(), Some(2));
+        assert_eq!(reader(&[0x7e]).sleb128(), Some(-2));
+        assert_eq!(reader(&[0xff, 0x00]).sleb128(), Some(127));
+        assert_eq!(reader(&[0x80, 0x7f]).sleb128(), Some(-128));
+        assert_eq!(reader(&[0xc0, 0xbb, 0x78]).sleb128(), Some(-123456));
+        assert_eq!(reader(&[0xff]).sleb128(), None);
+
+        let mut cursor = reader(&[0x80, 0x01, 0x7e, 0xaa]);
+        assert_eq!(cursor.uleb128(), Some(128));
+        assert_eq!(cursor.sleb128(), Some(-2));
+        assert_eq!(cursor.position, 3);
+    }
+
+    #[test]
+    fn runs_a_line_number_program() {
+        let mut data = line_unit(&[
+            0x00, 5, DW_LNE_SET_ADDRESS, 0x00, 0x01, 0x00, 0x00,
+            DW_LNS_ADVANCE_LINE, 9,
+            DW_LNS_COPY,
+            // Special opcode: address += 2 * 2, line += 2
+            13 + (2 + 5) + 14 * 2,
+            DW_LNS_SET_FILE, 2,
+            DW_LNS_ADVANCE_LINE, 0x7d,
+            DW_LNS_ADVANCE_PC, 3,
+            DW_LNS_COPY,
+            DW_LNS_FIXED_ADVANCE_PC, 0x10, 0x00,
+            0x00, 1, DW_LNE_END_SEQUENCE,
+        ]);
+        // DWARF 5 units are skipped, and so is anything after a 64-bit one
+        data.extend_from_slice(&[4, 0, 0, 0, 5, 0, 0xff, 0xff]);
+        data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0]);
+
+        let (files, lines) = parse_debug_line(&data);
+        assert_eq!(files, ["src/main.c", "/abs/start.s"]);
+        let rows: Vec<_> = lines.iter().map(|r| (r.start, r.end, r.file, r.line)).collect();
+        assert_eq!(rows, [(0x100, 0x104, 0, 10), (0x104, 0x10a, 0, 12), (0x10a, 0x11a, 1, 9)]);
+    }
+
+    #[test]
+    fn truncated_line_programs_keep_the_rows_so_far() {
+        let data = line_unit(&[
+            0x00, 5, DW_LNE_SET_ADDRESS, 0x00, 0x01, 0x00, 0x00,
+            DW_LNS_COPY,
+            DW_LNS_ADVANCE_PC, 2,
+            DW_LNS_COPY,
+            DW_LNS_ADVANCE_PC,
+        ]);
+        let (files, lines) = parse_debug_line(&data);
+        assert_eq!(files.len(), 2);
+        assert_eq!(lines.len(), 1);
+        assert_eq!((lines[0].start, lines[0].end, lines[0].line), (0x100, 0x104, 1));
+
+        assert_eq!(parse_debug_line(&data[..10]).1.len(), 0);
+    }
+
+    #[test]
+    fn overflowing_operands_do_not_panic() {
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        let mut program = vec![0x00, 5, DW_LNE_SET_ADDRESS, 0x00, 0x01, 0x00, 0x00, DW_LNS_COPY];
+        // Huge ADVANCE_PC and ADVANCE_LINE operands wrap
+        program.push(DW_LNS_ADVANCE_PC);
+        program.extend_from_slice(&max);
+        program.push(DW_LNS_ADVANCE_LINE);
+        program.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f]);
+        program.extend_from_slice(&[DW_LNS_ADVANCE_LINE, 0x7f, DW_LNS_COPY]);
+        // A file index past the table, then an extended opcode longer than memory
+        program.push(DW_LNS_SET_FILE);
+        program.extend_from_slice(&max);
+        program.extend_from_slice(&[DW_LNS_ADVANCE_PC, 1, DW_LNS_COPY, DW_LNS_ADVANCE_PC, 1, DW_LNS_COPY, 0x00]);
+        program.extend_from_slice(&max);
+        program.extend_from_slice(&[DW_LNE_END_SEQUENCE, DW_LNS_COPY]);
+
+        let (files, lines) = parse_debug_line(&line_unit(&program));
+        assert_eq!(files.len(), 2);
+        // 0x100 + 2 * (2^64 - 1) wraps to 0xfe: only the rows that move forward remain,
+        // and the bad file index is left for the lookup to reject
+        let rows: Vec<_> = lines.iter().map(|r| (r.start, r.end, r.file)).collect();
+        assert_eq!(rows, [(0xfe, 0x100, 0), (0x100, 0x102, usize::MAX - 1)]);
+    }
+
+    #[test]
+    fn prefers_the_global_alias() {
+        let symbols = ElfSymbols::new(
+            vec![
+                function("main", 0x100, 0x40, true),
+                function("HardFault_Handler", 0x1e8, 2, false),
+                function("Default_Handler", 0x1e8, 2, true),
+                function("NMI_Handler", 0x1e8, 2, false),
+            ],
+            vec!["main.c".to_string()],
+            // A file index past the table must not panic
+            vec![
+                LineRange { start: 0x100, end: 0x110, file: 0, line: 7 },
+                LineRange { start: 0x1e8, end: 0x1ea, file: 3, line: 9 },
+            ],
+        );
+
+        let location = symbols.locate(0x1e9);
+        assert_eq!(location.symbol.as_deref(), Some("Default_Handler"));
+        assert_eq!((location.offset, location.file, location.line), (0, None, Some(9)));
+
+        let location = symbols.locate_preferring(0x1e8, Some("HardFault_Handler"));
+        assert_eq!(location.symbol.as_deref(), Some("HardFault_Handler"));
+        let location = symbols.locate_preferring(0x1e8, Some("SVC_Handler"));
+        assert_eq!(location.symbol.as_deref(), Some("Default_Handler"));
+
+        let location = symbols.locate(0x104);
+        assert_eq!(location.to_string(), "0x00000104 in main+0x4 at main.c:7");
+        assert_eq!(symbols.locate(0x1ea).symbol, None);
+        assert_eq!(symbols.locate(0x80).symbol, None);
+    }
+
+    #[test]
+    fn locates_main_in_the_example_firmware() {
+        let symbols = ElfSymbols::from_elf_file("main.elf").unwrap();
+        assert!(!symbols.lines.is_empty());
+        let location = symbols.locate(0x109);
+        assert_eq!((location.symbol.as_deref(), location.offset), (Some("main"), 8));
+        assert!(location.file.is_some_and(|file| file.ends_with(".c")));
+    }
+}
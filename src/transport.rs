@@ -0,0 +1,262 @@
+use serialport::{ DataBits, FlowControl, Parity, SerialPort, StopBits };
+use std::io::{ Read, Write };
+use std::net::{ TcpStream, ToSocketAddrs };
+#[cfg(test)]
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, Sender };
+use std::time::{ Duration, Instant };
+use tracing::info;
+use crate::error::DebugError;
+use crate::protocol::FrameReassembler;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// A link to the ESP32 bridge that moves whole protocol frames
+pub trait Transport: Send {
+    /// Send one encoded frame to the bridge
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), DebugError>;
+    /// Wait for the next complete frame from the bridge
+    fn receive_frame(&mut self) -> Result<Vec<u8>, DebugError>;
+    /// Change how long `receive_frame` waits before giving up
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), DebugError>;
+    /// Current receive timeout
+    fn timeout(&self) -> Duration;
+    /// Short description of the link for logs
+    fn describe(&self) -> String;
+}
+
+/// Read from a byte stream until the reassembler yields a frame or the deadline passes
+fn receive_from_stream<R: Read + ?Sized>(
+    reader: &mut R,
+    reassembler: &mut FrameReassembler,
+    timeout: Duration
+) -> Result<Vec<u8>, DebugError> {
+    let deadline = Instant::now() + timeout;
+    let mut chunk = [0; 256];
+    loop {
+        if let Some(frame) = reassembler.next_frame() {
+            return Ok(frame);
+        }
+        if Instant::now() >= deadline {
+            return Err(DebugError::Timeout);
+        }
+        match reader.read(&mut chunk) {
+            Ok(0) => {
+                return Err(DebugError::Transport("Connection closed by bridge".to_string()));
+            }
+            Ok(count) => reassembler.push(&chunk[..count]),
+            Err(e) if
+                e.kind() == std::io::ErrorKind::TimedOut ||
+                e.kind() == std::io::ErrorKind::WouldBlock
+            => {}
+            Err(e) => {
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+/// USB-CDC serial port to the bridge
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+    reassembler: FrameReassembler,
+}
+
+impl SerialTransport {
+    pub fn open(port_name: &str, baud_rate: u32) -> Result<Self, DebugError> {
+        let port = serialport
+            ::new(port_name, baud_rate)
+            .timeout(DEFAULT_TIMEOUT)
+            .data_bits(DataBits::Eight)
+            .flow_control(FlowControl::None)
+            .parity(Parity::None)
+            .stop_bits(StopBits::One)
+            .open()?;
+        info!("Opened serial port {} at {} baud", port_name, baud_rate);
+        Ok(Self::from_port(port))
+    }
+
+    pub fn from_port(port: Box<dyn SerialPort>) -> Self {
+        SerialTransport { port, reassembler: FrameReassembler::new() }
+    }
+}
+
+impl Transport for SerialTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), DebugError> {
+        self.port.write_all(frame)?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>, DebugError> {
+        let timeout = self.port.timeout();
+        receive_from_stream(&mut self.port, &mut self.reassembler, timeout)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), DebugError> {
+        self.port.set_timeout(timeout)?;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.port.timeout()
+    }
+
+    fn describe(&self) -> String {
+        format!("serial {}", self.port.name().unwrap_or_else(|| "<unnamed>".to_string()))
+    }
+}
+
+/// TCP connection to a bridge reachable over WiFi (or a local stand-in)
+pub struct TcpTransport {
+    stream: TcpStream,
+    address: String,
+    reassembler: FrameReassembler,
+    timeout: Duration,
+}
+
+impl TcpTransport {
+    /// Connect to `host:port`
+    pub fn connect(address: &str) -> Result<Self, DebugError> {
+        let socket_address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                DebugError::InvalidArgument(format!("Could not resolve bridge address {}", address))
+            })?;
+        let stream = TcpStream::connect_timeout(&socket_address, DEFAULT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
+        info!("Connected to bridge at {}", socket_address);
+        Ok(TcpTransport {
+            stream,
+            address: address.to_string(),
+            reassembler: FrameReassembler::new(),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), DebugError> {
+        self.stream.write_all(frame)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>, DebugError> {
+        receive_from_stream(&mut self.stream, &mut self.reassembler, self.timeout)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), DebugError> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn describe(&self) -> String {
+        format!("tcp {}", self.address)
+    }
+}
+
+/// In-memory pipe end; bytes sent on one end are received on the other.
+/// Used to drive `SerialLoader` without hardware.
+#[cfg(test)]
+pub struct LoopbackTransport {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    reassembler: FrameReassembler,
+    timeout: Duration,
+}
+
+#[cfg(test)]
+impl LoopbackTransport {
+    /// Create two connected ends
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        (
+            LoopbackTransport {
+                tx: a_tx,
+                rx: a_rx,
+                reassembler: FrameReassembler::new(),
+                timeout: DEFAULT_TIMEOUT,
+            },
+            LoopbackTransport {
+                tx: b_tx,
+                rx: b_rx,
+                reassembler: FrameReassembler::new(),
+                timeout: DEFAULT_TIMEOUT,
+            },
+        )
+    }
+
+    /// Push raw bytes (not necessarily a whole frame) to the other end
+    pub fn send_raw(&mut self, bytes: &[u8]) -> Result<(), DebugError> {
+        self.tx
+            .send(bytes.to_vec())
+            .map_err(|_| DebugError::Transport("Loopback peer disconnected".to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Transport for LoopbackTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), DebugError> {
+        self.send_raw(frame)
+    }
+
+    fn receive_frame(&mut self) -> Result<Vec<u8>, DebugError> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(frame) = self.reassembler.next_frame() {
+                return Ok(frame);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.rx.recv_timeout(remaining) {
+                Ok(bytes) => self.reassembler.push(&bytes),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(DebugError::Timeout);
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(DebugError::Transport("Loopback peer disconnected".to_string()));
+                }
+            }
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), DebugError> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn describe(&self) -> String {
+        "loopback".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ProtocolHandler;
+
+    #[test]
+    fn loopback_carries_split_frames() {
+        let (mut a, mut b) = LoopbackTransport::pair();
+        let frame = ProtocolHandler::build_frame(&[ProtocolHandler::INFO_COMMAND]);
+        a.send_raw(&frame[..3]).unwrap();
+        a.send_raw(&frame[3..]).unwrap();
+        assert_eq!(b.receive_frame().unwrap(), frame);
+        b.set_timeout(Duration::from_millis(10)).unwrap();
+        assert!(matches!(b.receive_frame(), Err(DebugError::Timeout)));
+        drop(a);
+        assert!(matches!(b.receive_frame(), Err(DebugError::Transport(_))));
+    }
+}